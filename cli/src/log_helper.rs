@@ -10,7 +10,7 @@ pub(crate) fn setup_logger() -> Result<(), fern::InitError> {
         // depending on the terminals color scheme, this is the same as the background color
         .trace(Color::BrightBlack);
 
-    let colors_level = colors_line.clone().info(Color::Green);
+    let colors_level = colors_line.info(Color::Green);
 
     fern::Dispatch::new()
         .format(move |out, message, record| {
@@ -42,13 +42,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .get_matches();
 
     setup();
-    Config::load().await;
+    let config = Config::load().await.expect("unable to load config");
 
     if matches.is_present("add") {
-        if let Some(ref matches) = matches.subcommand_matches("add") {
+        if let Some(matches) = matches.subcommand_matches("add") {
             let result = Mailbox::new(
                 matches.value_of("username").unwrap().parse()?,
                 matches.value_of("password").unwrap().parse()?,
+                &config,
             )
             .await;
             match result {
@@ -0,0 +1,2 @@
+DROP TABLE message_uids;
+DROP TABLE folders
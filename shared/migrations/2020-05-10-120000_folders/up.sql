@@ -0,0 +1,17 @@
+CREATE TABLE folders (
+  id INTEGER NOT NULL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id),
+  name TEXT NOT NULL,
+  uid_validity BIGINT NOT NULL,
+  uid_next BIGINT NOT NULL,
+  UNIQUE (user_id, name)
+);
+
+CREATE TABLE message_uids (
+  id INTEGER NOT NULL PRIMARY KEY,
+  folder_id INTEGER NOT NULL REFERENCES folders(id),
+  uid BIGINT NOT NULL,
+  file_id TEXT NOT NULL,
+  UNIQUE (folder_id, uid),
+  UNIQUE (folder_id, file_id)
+)
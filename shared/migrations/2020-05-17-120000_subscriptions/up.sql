@@ -0,0 +1,6 @@
+CREATE TABLE subscriptions (
+  id INTEGER NOT NULL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id),
+  name TEXT NOT NULL,
+  UNIQUE (user_id, name)
+)
@@ -0,0 +1,3 @@
+ALTER TABLE message_uids DROP COLUMN modseq;
+ALTER TABLE message_uids DROP COLUMN flags;
+ALTER TABLE folders DROP COLUMN highest_modseq
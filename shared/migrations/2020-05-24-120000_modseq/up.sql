@@ -0,0 +1,5 @@
+ALTER TABLE folders ADD COLUMN highest_modseq BIGINT NOT NULL DEFAULT 1;
+
+-- The flag letters of the last change, to notice changes made by other programs
+ALTER TABLE message_uids ADD COLUMN flags TEXT NOT NULL DEFAULT '';
+ALTER TABLE message_uids ADD COLUMN modseq BIGINT NOT NULL DEFAULT 1
@@ -10,6 +10,29 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 pub struct Config {
     pub shared_secret: String,
     pub mailbox_root: String,
+    /// The biggest literal in bytes a client may send (for example using APPEND)
+    #[serde(default = "default_max_literal_size")]
+    pub max_literal_size: usize,
+    /// Advertise LITERAL- instead of LITERAL+ which limits non-synchronizing literals to 4096 bytes
+    #[serde(default)]
+    pub literal_minus: bool,
+    /// PEM file with the certificate chain for IMAPS, leaf certificate first
+    #[serde(default)]
+    pub tls_certificate: Option<String>,
+    /// PEM file with the PKCS#8 or RSA private key of the certificate
+    #[serde(default)]
+    pub tls_key: Option<String>,
+    /// Refuse authentication until the connection is encrypted
+    #[serde(default)]
+    pub tls_required: bool,
+    /// Allow plain text passwords (LOGIN, AUTHENTICATE PLAIN) on connections that
+    /// are not encrypted, unless `tls_required` is set
+    #[serde(default)]
+    pub plaintext_login: bool,
+}
+
+fn default_max_literal_size() -> usize {
+    50 * 1024 * 1024
 }
 
 impl Config {
@@ -28,6 +51,12 @@ impl Config {
         let config = Self {
             shared_secret: random_string,
             mailbox_root: "./mailbox_root".to_string(),
+            max_literal_size: default_max_literal_size(),
+            literal_minus: false,
+            tls_certificate: None,
+            tls_key: None,
+            tls_required: false,
+            plaintext_login: false,
         };
 
         // TODO consider using /etc/ImapServer/Config.yml instead
@@ -38,16 +67,24 @@ impl Config {
         let wrote = file.write_all(c.as_bytes()).await;
 
         match wrote {
-            Ok(_) => {
-                return Some(config);
-            }
+            Ok(_) => Some(config),
             Err(e) => {
                 error!("{}", e);
-                return None;
+                None
             }
         }
     }
 
+    /// If a certificate and key for TLS are configured.
+    pub fn tls_configured(&self) -> bool {
+        self.tls_certificate.is_some() && self.tls_key.is_some()
+    }
+
+    /// If plain text passwords may be sent, `tls` tells if the connection is encrypted.
+    pub fn login_allowed(&self, tls: bool) -> bool {
+        tls || (self.plaintext_login && !self.tls_required)
+    }
+
     pub async fn load() -> Option<Self> {
         let metadata = metadata("./Config.yml").await;
         match metadata {
@@ -64,15 +101,15 @@ impl Config {
                     let deserialized_config: Self = serde_yaml::from_str(&data)
                         .expect("unable to make struct from config content");
 
-                    return Some(deserialized_config);
+                    Some(deserialized_config)
                 } else {
-                    return Config::new().await;
+                    Config::new().await
                 }
             }
 
             Err(e) => {
                 error!("{}", e);
-                return Config::new().await;
+                Config::new().await
             }
         }
     }
@@ -7,6 +7,11 @@ pub fn establish_connection() -> SqliteConnection {
     dotenv().ok();
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    SqliteConnection::establish(&database_url)
-        .expect(&format!("Error connecting to {}", database_url))
+    let connection = SqliteConnection::establish(&database_url)
+        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+    // Every connection of the server shares the same file
+    connection
+        .execute("PRAGMA busy_timeout = 5000")
+        .expect("unable to set busy timeout");
+    connection
 }
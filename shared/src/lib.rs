@@ -1,3 +1,6 @@
+// Only for the crate name `IMAPServer_shared`, which can't take an allow of its own
+#![allow(non_snake_case)]
+
 #[macro_use]
 extern crate diesel;
 #[macro_use]
@@ -9,7 +12,10 @@ pub mod config;
 pub mod mailbox;
 
 mod database;
+// The diesel 1.4 derives and `table!` wrap their impls in named constants
+#[allow(non_local_definitions)]
 mod models;
+#[allow(non_local_definitions)]
 mod schema;
 
 #[cfg(test)]
@@ -0,0 +1,579 @@
+//! Message storage using the Maildir++ layout.
+//!
+//! The INBOX lives directly in the mailbox root, every other folder is a
+//! dotted directory next to it (`.Sent`, `.Archive.2019`). Each folder has the
+//! usual `cur`, `new` and `tmp` directories. Flags are encoded in the file name
+//! (`<unique>,S=<size>:2,<flags>`) and keywords use the letters `a`-`z` which are
+//! mapped to names in the `dovecot-keywords` file of the folder. UIDs are kept
+//! in the database.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::warn;
+use tokio::fs::{
+    create_dir_all, metadata, read_dir, read_to_string, remove_dir_all, remove_file, rename, File,
+};
+use tokio::io::AsyncWriteExt;
+
+use super::store::{
+    already_exists, invalid_name, not_found, renamed, safe_folder_name, FolderMetadata, MailStore,
+    MessageInfo,
+};
+use super::uid_list::UidList;
+
+/// The hierarchy delimiter used for folder names.
+pub const DELIMITER: char = '.';
+
+const KEYWORDS_FILE: &str = "dovecot-keywords";
+
+/// Maps system flags to the letters used in Maildir file names.
+const SYSTEM_FLAGS: [(&str, char); 5] = [
+    ("\\Draft", 'D'),
+    ("\\Flagged", 'F'),
+    ("\\Answered", 'R'),
+    ("\\Seen", 'S'),
+    ("\\Deleted", 'T'),
+];
+
+static DELIVERIES: AtomicUsize = AtomicUsize::new(0);
+
+/// A message file found in `new` or `cur`.
+struct Entry {
+    id: String,
+    letters: String,
+    size: u64,
+    internal_date: SystemTime,
+    recent: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Maildir {
+    root: PathBuf,
+    /// The owner of the folders in the `users` table
+    user_id: i32,
+}
+
+/// Splits a file name into the unique id and the flag letters.
+fn split_file_name(file_name: &str) -> (&str, &str) {
+    match file_name.find(':') {
+        Some(colon) => {
+            let info = &file_name[colon + 1..];
+            let letters = info.strip_prefix("2,").unwrap_or("");
+            (&file_name[..colon], letters)
+        }
+        None => (file_name, ""),
+    }
+}
+
+/// Reads the `S=<size>` field Maildir++ adds to the unique part.
+fn size_from_id(id: &str) -> Option<u64> {
+    id.split(',')
+        .skip(1)
+        .find_map(|field| field.strip_prefix("S="))
+        .and_then(|size| size.parse().ok())
+}
+
+fn unique_name(size: usize) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let host = std::env::var("HOSTNAME")
+        .unwrap_or_else(|_| "localhost".to_string())
+        .replace('/', "\\057")
+        .replace(':', "\\072");
+
+    format!(
+        "{}.M{}P{}Q{}.{},S={}",
+        now.as_secs(),
+        now.subsec_micros(),
+        process::id(),
+        DELIVERIES.fetch_add(1, Ordering::SeqCst),
+        host,
+        size
+    )
+}
+
+/// The folder a path below the mailbox `root` belongs to.
+///
+/// Only the `new` and `cur` directories and the messages in them count, so
+/// files like `tmp` deliveries or the keyword list are ignored.
+pub fn folder_of(root: &Path, path: &Path) -> Option<String> {
+    let mut components = path.strip_prefix(root).ok()?.iter().map(|c| c.to_str());
+    match components.next()?? {
+        "new" | "cur" => Some("INBOX".to_string()),
+        name if name.len() > 1 && name.starts_with(DELIMITER) => match components.next()?? {
+            "new" | "cur" => Some(name[1..].to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Maildir {
+    pub fn new<P>(root: P, user_id: i32) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Maildir {
+            root: root.as_ref().to_owned(),
+            user_id,
+        }
+    }
+
+    /// The directory of `folder`. The INBOX is the mailbox root itself.
+    ///
+    /// Names that could leave the mailbox root fail with `InvalidInput`.
+    pub fn folder_path(&self, folder: &str) -> Result<PathBuf, io::Error> {
+        if folder.eq_ignore_ascii_case("INBOX") {
+            Ok(self.root.clone())
+        } else if safe_folder_name(folder) {
+            Ok(self.root.join(format!("{}{}", DELIMITER, folder)))
+        } else {
+            Err(invalid_name())
+        }
+    }
+
+    async fn keywords(&self, folder: &str) -> Result<Vec<String>, io::Error> {
+        let path = self.folder_path(folder)?.join(KEYWORDS_FILE);
+        let content = read_to_string(path).await.unwrap_or_default();
+
+        let mut keywords: Vec<String> = Vec::new();
+        for line in content.lines() {
+            let mut parts = line.splitn(2, ' ');
+            let index = parts.next().and_then(|index| index.parse::<usize>().ok());
+            if let (Some(index), Some(keyword)) = (index, parts.next()) {
+                if keywords.len() <= index {
+                    keywords.resize(index + 1, String::new());
+                }
+                keywords[index] = keyword.to_string();
+            }
+        }
+        Ok(keywords)
+    }
+
+    async fn store_keywords(&self, folder: &str, keywords: &[String]) -> Result<(), io::Error> {
+        let content: String = keywords
+            .iter()
+            .enumerate()
+            .filter(|(_, keyword)| !keyword.is_empty())
+            .map(|(index, keyword)| format!("{} {}\n", index, keyword))
+            .collect();
+
+        let path = self.folder_path(folder)?.join(KEYWORDS_FILE);
+        let mut file = File::create(path).await?;
+        file.write_all(content.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Turns IMAP flags into the sorted letters used in file names.
+    ///
+    /// Unknown keywords get registered in the keywords file of the folder.
+    async fn flags_to_letters(&self, folder: &str, flags: &[String]) -> Result<String, io::Error> {
+        let mut keywords = self.keywords(folder).await?;
+        let mut changed = false;
+        let mut letters: Vec<char> = Vec::new();
+
+        for flag in flags {
+            if let Some((_, letter)) = SYSTEM_FLAGS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(flag))
+            {
+                letters.push(*letter);
+                continue;
+            }
+            if flag.starts_with('\\') {
+                // \Recent and unknown system flags can't be stored
+                continue;
+            }
+
+            let index = match keywords.iter().position(|keyword| keyword == flag) {
+                Some(index) => index,
+                None => match keywords.iter().position(String::is_empty) {
+                    Some(index) => {
+                        keywords[index] = flag.clone();
+                        changed = true;
+                        index
+                    }
+                    None if keywords.len() < 26 => {
+                        keywords.push(flag.clone());
+                        changed = true;
+                        keywords.len() - 1
+                    }
+                    None => return Err(io::Error::other("too many keywords in folder")),
+                },
+            };
+            letters.push((b'a' + index as u8) as char);
+        }
+
+        if changed {
+            self.store_keywords(folder, &keywords).await?;
+        }
+
+        letters.sort_unstable();
+        letters.dedup();
+        Ok(letters.into_iter().collect())
+    }
+
+    fn letters_to_flags(letters: &str, keywords: &[String]) -> Vec<String> {
+        letters
+            .chars()
+            .filter_map(|letter| {
+                if let Some((name, _)) = SYSTEM_FLAGS.iter().find(|(_, l)| *l == letter) {
+                    return Some(name.to_string());
+                }
+                if letter.is_ascii_lowercase() {
+                    let index = (letter as u8 - b'a') as usize;
+                    return keywords
+                        .get(index)
+                        .filter(|keyword| !keyword.is_empty())
+                        .cloned();
+                }
+                None
+            })
+            .collect()
+    }
+
+    fn uid_list(&self, folder: &str) -> Result<UidList, io::Error> {
+        UidList::load(self.user_id, folder, false)
+    }
+
+    /// Updates the UIDs and mod-sequences to the files of `folder`.
+    ///
+    /// New files get a UID, removed files lose theirs and files whose flags
+    /// were changed get a new mod-sequence.
+    async fn synchronize(
+        &self,
+        folder: &str,
+    ) -> Result<(FolderMetadata, Vec<MessageInfo>), io::Error> {
+        let entries = self.entries(folder).await?;
+        let keywords = self.keywords(folder).await?;
+        let mut uid_list = self.uid_list(folder)?;
+
+        uid_list.retain(|id| entries.iter().any(|entry| entry.id == id));
+
+        let mut messages = Vec::new();
+        for entry in entries {
+            let uid = match uid_list.uid(&entry.id) {
+                Some(uid) => uid,
+                None => uid_list.assign(entry.id.clone(), &entry.letters),
+            };
+            messages.push(MessageInfo {
+                uid,
+                flags: Maildir::letters_to_flags(&entry.letters, &keywords),
+                size: entry.size,
+                internal_date: entry.internal_date,
+                recent: entry.recent,
+                modseq: uid_list.update(uid, &entry.letters),
+            });
+        }
+
+        uid_list.save()?;
+
+        messages.sort_by_key(|message| message.uid);
+        Ok((uid_list.metadata, messages))
+    }
+
+    async fn entries(&self, folder: &str) -> Result<Vec<Entry>, io::Error> {
+        let path = self.folder_path(folder)?;
+        let mut found = Vec::new();
+
+        for (sub, recent) in &[("new", true), ("cur", false)] {
+            let mut entries = read_dir(path.join(sub)).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                if file_name.starts_with('.') {
+                    continue;
+                }
+                let metadata = entry.metadata().await?;
+                if !metadata.is_file() {
+                    continue;
+                }
+
+                let (id, letters) = split_file_name(&file_name);
+                found.push(Entry {
+                    id: id.to_string(),
+                    letters: letters.to_string(),
+                    size: size_from_id(id).unwrap_or(metadata.len()),
+                    internal_date: metadata.modified().unwrap_or(UNIX_EPOCH),
+                    recent: *recent,
+                });
+            }
+        }
+
+        found.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(found)
+    }
+
+    /// Maps the unique ids of all messages in `cur` and `new` to their files.
+    async fn message_files(&self, folder: &str) -> Result<HashMap<String, PathBuf>, io::Error> {
+        let path = self.folder_path(folder)?;
+        let mut files = HashMap::new();
+        for sub in &["new", "cur"] {
+            let mut entries = read_dir(path.join(sub)).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                let id = split_file_name(&file_name).0.to_string();
+                files.insert(id, entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    async fn find_message(&self, folder: &str, id: &str) -> Result<PathBuf, io::Error> {
+        self.message_files(folder)
+            .await?
+            .remove(id)
+            .ok_or_else(|| not_found("message does not exist"))
+    }
+
+    /// Moves one folder directory together with its UIDs. The database goes
+    /// first, a conflict there leaves the directory where it was, and a failed
+    /// directory rename takes the UIDs back.
+    async fn move_folder(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        UidList::rename(self.user_id, from, to)?;
+        if let Err(e) = rename(self.folder_path(from)?, self.folder_path(to)?).await {
+            if let Err(e) = UidList::rename(self.user_id, to, from) {
+                warn!("Unable to roll back UIDs of {}: {}", from, e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn message_id(&self, folder: &str, uid: u32) -> Result<String, io::Error> {
+        self.uid_list(folder)?
+            .id(uid)
+            .map(str::to_string)
+            .ok_or_else(|| not_found("message does not exist"))
+    }
+}
+
+#[async_trait]
+impl MailStore for Maildir {
+    async fn list_folders(&self) -> Result<Vec<String>, io::Error> {
+        let mut folders = vec!["INBOX".to_string()];
+
+        let mut dirs = read_dir(&self.root).await?;
+        while let Some(dir) = dirs.next().await {
+            let dir = dir?;
+            if !dir.file_type().await?.is_dir() {
+                continue;
+            }
+            let name = dir.file_name().to_string_lossy().into_owned();
+            if let Some(folder) = name.strip_prefix(DELIMITER) {
+                if !folder.is_empty() && folder != "." {
+                    folders.push(folder.to_string());
+                }
+            }
+        }
+
+        folders.sort();
+        Ok(folders)
+    }
+
+    async fn folder_exists(&self, folder: &str) -> bool {
+        let path = match self.folder_path(folder) {
+            Ok(path) => path.join("cur"),
+            Err(_) => return false,
+        };
+        match metadata(path).await {
+            Ok(metadata) => metadata.is_dir(),
+            Err(_) => false,
+        }
+    }
+
+    /// Creates the `cur`, `new` and `tmp` directories of `folder` if they are missing.
+    ///
+    /// A folder that did not exist before starts with a new UIDVALIDITY.
+    async fn create_folder(&self, folder: &str) -> Result<(), io::Error> {
+        let recreate = !self.folder_exists(folder).await;
+        let path = self.folder_path(folder)?;
+        for sub in &["cur", "new", "tmp"] {
+            create_dir_all(path.join(sub)).await?;
+        }
+        if path != self.root {
+            let marker = path.join("maildirfolder");
+            if metadata(&marker).await.is_err() {
+                File::create(marker).await?;
+            }
+        }
+        UidList::load(self.user_id, folder, recreate)?;
+        Ok(())
+    }
+
+    /// The INBOX is the mailbox root and can't be deleted this way.
+    async fn delete_folder(&self, folder: &str) -> Result<(), io::Error> {
+        let path = self.folder_path(folder)?;
+        if path == self.root {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the INBOX can't be deleted",
+            ));
+        }
+        if !self.folder_exists(folder).await {
+            return Err(not_found("folder does not exist"));
+        }
+
+        remove_dir_all(path).await?;
+        UidList::remove(self.user_id, folder)
+    }
+
+    async fn rename_folder(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        if self.folder_path(from)? == self.root || self.folder_path(to)? == self.root {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the INBOX can't be renamed this way",
+            ));
+        }
+
+        let renames: Vec<(String, String)> = self
+            .list_folders()
+            .await?
+            .into_iter()
+            .filter_map(|folder| renamed(&folder, from, to).map(|new| (folder, new)))
+            .collect();
+
+        if renames.is_empty() {
+            return Err(not_found("folder does not exist"));
+        }
+        for (_, new) in &renames {
+            if metadata(self.folder_path(new)?).await.is_ok() {
+                return Err(already_exists("folder already exists"));
+            }
+        }
+
+        // Folders moved before a failure are moved back, so the hierarchy is
+        // never left half renamed.
+        for (index, (old, new)) in renames.iter().enumerate() {
+            if let Err(e) = self.move_folder(old, new).await {
+                for (old, new) in renames[..index].iter().rev() {
+                    if let Err(e) = self.move_folder(new, old).await {
+                        warn!("Unable to roll back rename of {} to {}: {}", old, new, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks at the messages first, so changes by other programs count.
+    async fn folder_metadata(&self, folder: &str) -> Result<FolderMetadata, io::Error> {
+        if !self.folder_exists(folder).await {
+            return Err(not_found("folder does not exist"));
+        }
+        Ok(self.synchronize(folder).await?.0)
+    }
+
+    /// Messages without flags end up in `new` and count as recent.
+    async fn append(
+        &self,
+        folder: &str,
+        data: &[u8],
+        flags: &[String],
+        internal_date: Option<SystemTime>,
+    ) -> Result<u32, io::Error> {
+        if !self.folder_exists(folder).await {
+            return Err(not_found("folder does not exist"));
+        }
+
+        let path = self.folder_path(folder)?;
+        let id = unique_name(data.len());
+        let tmp_path = path.join("tmp").join(&id);
+
+        let mut file = File::create(&tmp_path).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        if let Some(internal_date) = internal_date {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&tmp_path)?
+                .set_modified(internal_date)?;
+        }
+
+        let letters = self.flags_to_letters(folder, flags).await?;
+        let target = if flags.is_empty() {
+            path.join("new").join(&id)
+        } else {
+            path.join("cur").join(format!("{}:2,{}", id, letters))
+        };
+        rename(tmp_path, target).await?;
+
+        let mut uid_list = self.uid_list(folder)?;
+        let uid = uid_list.assign(id, &letters);
+        uid_list.save()?;
+
+        Ok(uid)
+    }
+
+    /// Messages delivered by other programs get their UIDs assigned here.
+    async fn list_messages(&self, folder: &str) -> Result<Vec<MessageInfo>, io::Error> {
+        Ok(self.synchronize(folder).await?.1)
+    }
+
+    async fn read_message(&self, folder: &str, uid: u32) -> Result<Vec<u8>, io::Error> {
+        let id = self.message_id(folder, uid).await?;
+        let path = self.find_message(folder, &id).await?;
+        tokio::fs::read(path).await
+    }
+
+    /// Loads the UIDs and lists the folder once instead of for every message.
+    async fn read_messages(
+        &self,
+        folder: &str,
+        uids: &[u32],
+    ) -> Result<Vec<Result<Vec<u8>, io::Error>>, io::Error> {
+        let uid_list = self.uid_list(folder)?;
+        let files = self.message_files(folder).await?;
+
+        let mut messages = Vec::with_capacity(uids.len());
+        for uid in uids {
+            let path = uid_list.id(*uid).and_then(|id| files.get(id));
+            messages.push(match path {
+                Some(path) => tokio::fs::read(path).await,
+                None => Err(not_found("message does not exist")),
+            });
+        }
+        Ok(messages)
+    }
+
+    /// This also moves the message from `new` to `cur`.
+    async fn set_flags(&self, folder: &str, uid: u32, flags: &[String]) -> Result<(), io::Error> {
+        let id = self.message_id(folder, uid).await?;
+        let current = self.find_message(folder, &id).await?;
+        let letters = self.flags_to_letters(folder, flags).await?;
+        let target = self
+            .folder_path(folder)?
+            .join("cur")
+            .join(format!("{}:2,{}", id, letters));
+
+        if current != target {
+            rename(current, target).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove_message(&self, folder: &str, uid: u32) -> Result<(), io::Error> {
+        let mut uid_list = self.uid_list(folder)?;
+        let id = uid_list
+            .id(uid)
+            .map(str::to_string)
+            .ok_or_else(|| not_found("message does not exist"))?;
+        let path = self.find_message(folder, &id).await?;
+        remove_file(path).await?;
+
+        uid_list.retain(|known| known != id);
+        uid_list.save()
+    }
+}
@@ -0,0 +1,218 @@
+//! A `MailStore` that keeps everything in memory. Mostly useful for tests.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use super::store::{
+    already_exists, normalize, not_found, renamed, FolderMetadata, MailStore, MessageInfo,
+};
+
+struct MemoryFolder {
+    metadata: FolderMetadata,
+    messages: Vec<(MessageInfo, Vec<u8>)>,
+}
+
+#[derive(Default)]
+struct Folders {
+    folders: BTreeMap<String, MemoryFolder>,
+    uid_validity: u32,
+}
+
+impl Folders {
+    fn create(&mut self, folder: String) {
+        if self.folders.contains_key(&folder) {
+            return;
+        }
+        self.uid_validity += 1;
+        self.folders.insert(
+            folder,
+            MemoryFolder {
+                metadata: FolderMetadata {
+                    uid_validity: self.uid_validity,
+                    uid_next: 1,
+                    highest_modseq: 1,
+                },
+                messages: Vec::new(),
+            },
+        );
+    }
+
+    fn get_mut(&mut self, folder: &str) -> Result<&mut MemoryFolder, io::Error> {
+        self.folders
+            .get_mut(&normalize(folder))
+            .ok_or_else(|| not_found("folder does not exist"))
+    }
+}
+
+fn inbox_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "the INBOX can't be deleted or renamed",
+    )
+}
+
+pub struct MemoryStore {
+    inner: Mutex<Folders>,
+}
+
+impl MemoryStore {
+    /// Creates a store that only contains an empty INBOX.
+    pub fn new() -> Self {
+        let mut folders = Folders::default();
+        folders.create("INBOX".to_string());
+        MemoryStore {
+            inner: Mutex::new(folders),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
+#[async_trait]
+impl MailStore for MemoryStore {
+    async fn list_folders(&self) -> Result<Vec<String>, io::Error> {
+        let inner = self.inner.lock().expect("memory store poisoned");
+        Ok(inner.folders.keys().cloned().collect())
+    }
+
+    async fn folder_exists(&self, folder: &str) -> bool {
+        let inner = self.inner.lock().expect("memory store poisoned");
+        inner.folders.contains_key(&normalize(folder))
+    }
+
+    async fn create_folder(&self, folder: &str) -> Result<(), io::Error> {
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        inner.create(normalize(folder));
+        Ok(())
+    }
+
+    async fn delete_folder(&self, folder: &str) -> Result<(), io::Error> {
+        if normalize(folder) == "INBOX" {
+            return Err(inbox_error());
+        }
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        inner
+            .folders
+            .remove(&normalize(folder))
+            .map(|_| ())
+            .ok_or_else(|| not_found("folder does not exist"))
+    }
+
+    async fn rename_folder(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        if normalize(from) == "INBOX" || normalize(to) == "INBOX" {
+            return Err(inbox_error());
+        }
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        let renames: Vec<(String, String)> = inner
+            .folders
+            .keys()
+            .filter_map(|folder| renamed(folder, from, to).map(|new| (folder.clone(), new)))
+            .collect();
+
+        if renames.is_empty() {
+            return Err(not_found("folder does not exist"));
+        }
+        if renames
+            .iter()
+            .any(|(_, new)| inner.folders.contains_key(&normalize(new)))
+        {
+            return Err(already_exists("folder already exists"));
+        }
+
+        for (old, new) in renames {
+            if let Some(folder) = inner.folders.remove(&old) {
+                inner.folders.insert(new, folder);
+            }
+        }
+        Ok(())
+    }
+
+    async fn folder_metadata(&self, folder: &str) -> Result<FolderMetadata, io::Error> {
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        Ok(inner.get_mut(folder)?.metadata)
+    }
+
+    async fn append(
+        &self,
+        folder: &str,
+        data: &[u8],
+        flags: &[String],
+        internal_date: Option<SystemTime>,
+    ) -> Result<u32, io::Error> {
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        let folder = inner.get_mut(folder)?;
+
+        let uid = folder.metadata.uid_next;
+        folder.metadata.uid_next += 1;
+        folder.metadata.highest_modseq += 1;
+
+        let info = MessageInfo {
+            uid,
+            flags: flags.to_vec(),
+            size: data.len() as u64,
+            internal_date: internal_date.unwrap_or_else(SystemTime::now),
+            recent: flags.is_empty(),
+            modseq: folder.metadata.highest_modseq,
+        };
+        folder.messages.push((info, data.to_vec()));
+        Ok(uid)
+    }
+
+    async fn list_messages(&self, folder: &str) -> Result<Vec<MessageInfo>, io::Error> {
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        let folder = inner.get_mut(folder)?;
+        Ok(folder
+            .messages
+            .iter()
+            .map(|(info, _)| info.clone())
+            .collect())
+    }
+
+    async fn read_message(&self, folder: &str, uid: u32) -> Result<Vec<u8>, io::Error> {
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        let folder = inner.get_mut(folder)?;
+        folder
+            .messages
+            .iter()
+            .find(|(info, _)| info.uid == uid)
+            .map(|(_, data)| data.clone())
+            .ok_or_else(|| not_found("message does not exist"))
+    }
+
+    async fn set_flags(&self, folder: &str, uid: u32, flags: &[String]) -> Result<(), io::Error> {
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        let folder = inner.get_mut(folder)?;
+        let (info, _) = folder
+            .messages
+            .iter_mut()
+            .find(|(info, _)| info.uid == uid)
+            .ok_or_else(|| not_found("message does not exist"))?;
+        if info.flags != flags {
+            folder.metadata.highest_modseq += 1;
+            info.flags = flags.to_vec();
+            info.modseq = folder.metadata.highest_modseq;
+        }
+        info.recent = false;
+        Ok(())
+    }
+
+    async fn remove_message(&self, folder: &str, uid: u32) -> Result<(), io::Error> {
+        let mut inner = self.inner.lock().expect("memory store poisoned");
+        let folder = inner.get_mut(folder)?;
+        let count = folder.messages.len();
+        folder.messages.retain(|(info, _)| info.uid != uid);
+        if folder.messages.len() == count {
+            return Err(not_found("message does not exist"));
+        }
+        folder.metadata.highest_modseq += 1;
+        Ok(())
+    }
+}
@@ -1,13 +1,12 @@
-use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use argonautica::{Hasher, Verifier};
 use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
 use futures::compat::Future01CompatExt;
-use futures::StreamExt;
 use log::debug;
 use log::warn;
 use rand::prelude::*;
-use tokio::fs::{create_dir_all, metadata, read_dir};
 
 use crate::config::Config;
 use crate::database::establish_connection;
@@ -15,44 +14,53 @@ use crate::models::{NewUser, User};
 use crate::schema::users;
 use crate::schema::users::dsl::*;
 
+use self::pattern::{canonical_pattern, matches, parents};
+
+pub use self::maildir::{folder_of, Maildir, DELIMITER};
+pub use self::memory::MemoryStore;
+pub use self::store::{safe_folder_name, FolderMetadata, MailStore, MessageInfo};
+
+mod maildir;
+mod memory;
+pub(crate) mod pattern;
+mod store;
+pub(crate) mod subscriptions;
+mod uid_list;
+
+/// Formats a folder name as quoted string.
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Checks if there are folders below `name`.
+fn has_children(folders: &[String], name: &str) -> bool {
+    let prefix = format!("{}{}", name, DELIMITER);
+    folders.iter().any(|folder| folder.starts_with(&prefix))
+}
+
 #[derive(Clone)]
 pub struct Mailbox {
     pub user: String,
     pub mailbox_root: String,
     password_hash: String,
-}
-
-fn string_to_static_str(s: String) -> &'static str {
-    Box::leak(s.into_boxed_str())
+    /// The id of the user in the `users` table
+    user_id: i32,
+    store: Arc<dyn MailStore>,
 }
 
 impl Mailbox {
-    pub async fn new(user: String, password: String) -> Option<Self> {
-        let config = Config::load().await.expect("unable to load config");
-
+    pub async fn new(user: String, password: String, config: &Config) -> Option<Self> {
         let mut hasher = Hasher::default();
         let password_hash_new = hasher
             .with_password(password)
-            .with_secret_key(config.shared_secret)
+            .with_secret_key(&config.shared_secret)
             .hash_non_blocking()
             .compat()
             .await
             .expect("unable to hash password");
 
-        let mut rng = StdRng::from_entropy();
-
-        // This does need to stay mutable even when the compiler says otherwise. If it is not mut it fails to generate random numbers
-        #[allow(unused_mut)]
-            let mut random_number: String;
-
-        if rng.gen() {
-            let random_number_int: i32 = rng.gen_range(0, 2 ^ 32 - 1);
-
-            random_number = format!("{:?}", random_number_int);
-        } else {
-            warn!("Unable to generate random number");
-            return None;
-        }
+        // UIDVALIDITY is kept per folder, the column only needs some value
+        let identifier = StdRng::from_entropy().gen::<u32>().to_string();
 
         let connection = establish_connection();
         let user_local = user.clone();
@@ -64,38 +72,46 @@ impl Mailbox {
         match results {
             Ok(m) => {
                 let mailbox_root = format!("{}/{}", config.mailbox_root, m.email);
-                return Some(Mailbox {
+                Some(Mailbox {
+                    store: Arc::new(Maildir::new(&mailbox_root, m.id)),
                     mailbox_root,
                     user,
                     password_hash: m.password_hash,
-                });
+                    user_id: m.id,
+                })
             }
             Err(_) => {
                 let new_user = NewUser {
                     email: &user,
                     password_hash: &password_hash_new,
-                    uid_validity_identifier: &random_number,
+                    uid_validity_identifier: &identifier,
                 };
 
                 diesel::insert_into(users::table)
                     .values(&new_user)
                     .execute(&connection)
                     .expect("Failed to add new User");
+                let user_id: i32 = users
+                    .filter(email.eq(&user))
+                    .select(id)
+                    .first(&connection)
+                    .expect("Failed to load new User");
 
                 let mailbox_root = format!("{}/{}", config.mailbox_root, user);
 
-                return Some(Mailbox {
+                Some(Mailbox {
+                    store: Arc::new(Maildir::new(&mailbox_root, user_id)),
                     mailbox_root,
                     user: user_local,
                     password_hash: password_hash_new,
-                });
+                    user_id,
+                })
             }
         }
     }
 
-    pub async fn load(user: String) -> Option<Self> {
+    pub async fn load(user: String, config: &Config) -> Option<Self> {
         let connection = establish_connection();
-        let config = Config::load().await.expect("unable to load config");
         let user_local = user.clone();
 
         let results: Result<User, diesel::result::Error> = users
@@ -107,18 +123,19 @@ impl Mailbox {
             Ok(results) => {
                 let mailbox_root = format!("{}/{}", config.mailbox_root, results.email);
                 Some(Mailbox {
+                    store: Arc::new(Maildir::new(&mailbox_root, results.id)),
                     mailbox_root,
                     user: user_local,
                     password_hash: results.password_hash,
+                    user_id: results.id,
                 })
             }
             _ => None,
         }
     }
 
-    pub async fn load_all() -> Option<Vec<Self>> {
+    pub async fn load_all(config: &Config) -> Option<Vec<Self>> {
         let connection = establish_connection();
-        let config = Config::load().await.expect("unable to load config");
 
         let results: Vec<User> = users
             .load::<User>(&connection)
@@ -129,175 +146,363 @@ impl Mailbox {
         for entry in &results {
             let mailbox_root = format!("{}/{}", config.mailbox_root, entry.email);
             returns.push(Mailbox {
+                store: Arc::new(Maildir::new(&mailbox_root, entry.id)),
                 mailbox_root,
                 user: entry.email.clone(),
                 password_hash: entry.password_hash.clone(),
+                user_id: entry.id,
             })
         }
 
         Some(returns)
     }
 
-    pub async fn check_password_plain(&self, password: String) -> Result<(), ()> {
-        let config = Config::load().await.expect("unable to load config");
+    pub async fn check_password_plain(&self, password: String, config: &Config) -> Result<(), ()> {
         let local_hash = self.password_hash.clone();
 
         let mut verifier = Verifier::default();
         let verified = verifier
             .with_hash(local_hash)
             .with_password(password)
-            .with_secret_key(config.shared_secret)
+            .with_secret_key(&config.shared_secret)
             .verify_non_blocking()
             .compat()
             .await;
         match verified {
             Ok(v) => {
                 if v {
-                    return Ok(());
+                    Ok(())
                 } else {
-                    return Err(());
+                    Err(())
                 }
             }
-            Err(_) => {
-                return Err(());
-            }
+            Err(_) => Err(()),
         }
     }
 
-    pub async fn get_lsub(&self, args: Vec<&str>) -> Option<Vec<String>> {
-        if args.len() == 4 {
-            debug!("get_lsub 4");
-        } else if args.len() == 5 {
-            debug!("get_lsub 5");
-        }
+    /// Lists the subscribed names matching `pattern` relative to `reference`.
+    ///
+    /// Unsubscribed parents of subscribed names are listed as `\Noselect`
+    /// if a `%` wildcard stops at them.
+    pub async fn get_lsub(&self, reference: &str, pattern: &str) -> Option<Vec<String>> {
+        debug!("get_lsub {} {}", reference, pattern);
 
-        let searched = args.get(args.len() - 1).expect("unable to get searched path").replace("\"", "");
+        let pattern = canonical_pattern(reference, pattern);
+        let folders = self.store.list_folders().await.expect("unable to read dir");
+        let subscribed = self.subscriptions().await.unwrap_or_default();
 
-        let mut dirs = read_dir(self.mailbox_root.to_owned())
-            .await
-            .expect("unable to read dir");
-
-        let mut dirs_lsub: Vec<&str> = Vec::new();
-
-        while let Some(dir) = dirs.next().await {
-            let dir = dir.expect("unable to get dir");
-            // FIXME this will break with subdirs
-            if dir.file_name().into_string().unwrap() != searched && searched != "*" { continue; }
-            let subscribed_str: &str = "(subscribed)";
-            if args.contains(&subscribed_str) {
-                let path_string = format!(
-                    "* LSUB (\\Subscribed) \".\" {}\r\n",
-                    dir.file_name()
-                        .into_string()
-                        .expect("unable to get filename")
-                );
-                let path_string = string_to_static_str(path_string);
-                dirs_lsub.push(path_string);
-                continue;
+        let mut names: Vec<String> = subscribed.clone();
+        for name in &subscribed {
+            names.extend(parents(name));
+        }
+        names.sort();
+        names.dedup();
+
+        let mut dirs_lsub: Vec<String> = Vec::new();
+
+        for name in names.iter().filter(|name| matches(&pattern, name)) {
+            let attributes = if !subscribed.contains(name) {
+                "\\Noselect"
+            } else if !folders.contains(name) {
+                // Subscriptions may outlive their folder
+                ""
+            } else if has_children(&folders, name) {
+                "\\HasChildren"
             } else {
-                let path_string = format!(
-                    "* LSUB (\\HasNoChildren) \".\" {}\r\n",
-                    dir.file_name()
-                        .into_string()
-                        .expect("unable to get filename")
-                );
-                let path_string = string_to_static_str(path_string);
-                dirs_lsub.push(path_string);
-                continue;
-            }
+                "\\HasNoChildren"
+            };
+            dirs_lsub.push(format!(
+                "* LSUB ({}) \"{}\" {}\r\n",
+                attributes,
+                DELIMITER,
+                quoted(name)
+            ));
         }
 
-        if dirs_lsub.len() > 0 {
-            let dirs_lsub: Vec<String> = dirs_lsub.iter().map(|s| (*s).to_string()).collect();
+        if !dirs_lsub.is_empty() {
             Some(dirs_lsub)
         } else {
             None
         }
     }
 
-    pub async fn get_list(&self, args: Vec<&str>) -> Option<Vec<String>> {
-        if args.len() == 4 {
-            debug!("get_list 4");
-        } else if args.len() == 5 {
-            debug!("get_list 5");
-        }
-
-        let searched = args.get(args.len() - 1).expect("unable to get searched path").replace("\"", "");
+    /// Lists the folders matching `pattern` relative to `reference`.
+    ///
+    /// Names that only exist because of folders below them are `\Noselect`.
+    /// With `subscribed` only subscribed names are listed, including the ones
+    /// that don't exist (RFC 5258). `return_subscribed` marks subscribed folders.
+    pub async fn get_list(
+        &self,
+        reference: &str,
+        pattern: &str,
+        subscribed: bool,
+        return_subscribed: bool,
+    ) -> Option<Vec<String>> {
+        debug!(
+            "get_list {} {} (subscribed: {})",
+            reference, pattern, subscribed
+        );
+
+        let pattern = canonical_pattern(reference, pattern);
+        let folders = self.store.list_folders().await.expect("unable to read dir");
+        let subscriptions = if subscribed || return_subscribed {
+            self.subscriptions().await.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
 
-        let mut dirs = read_dir(self.mailbox_root.to_owned())
-            .await
-            .expect("unable to read dir");
-
-        let mut dirs_list: Vec<&str> = Vec::new();
-
-        while let Some(dir) = dirs.next().await {
-            let dir = dir.expect("unable to get dir");
-            // FIXME this will break with subdirs
-            if dir.file_name().into_string().unwrap() != searched && searched != "*" { continue; }
-            let subscribed_str: &str = "(subscribed)";
-            if args.contains(&subscribed_str) {
-                // TODO actually check if subscribed or not.
-                let path_string = format!(
-                    "* LIST (\\Subscribed) \".\" {}\r\n",
-                    dir.file_name()
-                        .into_string()
-                        .expect("unable to get filename")
-                );
-                let path_string = string_to_static_str(path_string);
-                dirs_list.push(path_string);
-                continue;
+        let mut names: Vec<String> = if subscribed {
+            subscriptions.clone()
+        } else {
+            let mut names = folders.clone();
+            for folder in &folders {
+                names.extend(parents(folder));
+            }
+            names
+        };
+        names.sort();
+        names.dedup();
+
+        let mut dirs_list: Vec<String> = Vec::new();
+
+        for name in names.iter().filter(|name| matches(&pattern, name)) {
+            let mut attributes = Vec::new();
+            if !folders.contains(name) {
+                if subscribed {
+                    attributes.push("\\NonExistent");
+                } else {
+                    attributes.push("\\Noselect");
+                }
+            }
+            if has_children(&folders, name) {
+                attributes.push("\\HasChildren");
             } else {
-                // TODO actually check if subscribed or not.
-                let path_string = format!(
-                    "* LIST (\\HasNoChildren) \".\" {}\r\n",
-                    dir.file_name()
-                        .into_string()
-                        .expect("unable to get filename")
-                );
-                let path_string = string_to_static_str(path_string);
-                dirs_list.push(path_string);
-                continue;
+                attributes.push("\\HasNoChildren");
+            }
+            if subscriptions.contains(name) {
+                attributes.push("\\Subscribed");
             }
+            dirs_list.push(format!(
+                "* LIST ({}) \"{}\" {}\r\n",
+                attributes.join(" "),
+                DELIMITER,
+                quoted(name)
+            ));
         }
 
-        if dirs_list.len() > 0 {
-            let dirs_list: Vec<String> = dirs_list.iter().map(|s| (*s).to_string()).collect();
+        if !dirs_list.is_empty() {
             Some(dirs_list)
         } else {
             None
         }
     }
 
+    /// Subscribes to `folder`, which does not need to exist.
+    pub async fn subscribe(&self, folder: &str) -> Result<(), std::io::Error> {
+        subscriptions::subscribe(self.user_id, folder)
+    }
+
+    /// Returns false if `folder` was not subscribed.
+    pub async fn unsubscribe(&self, folder: &str) -> Result<bool, std::io::Error> {
+        subscriptions::unsubscribe(self.user_id, folder)
+    }
+
+    /// The names of all subscribed folders, sorted.
+    pub async fn subscriptions(&self) -> Result<Vec<String>, std::io::Error> {
+        subscriptions::list(self.user_id)
+    }
+
+    /// Replaces the storage backend, e.g. with a `MemoryStore` in tests.
+    pub fn with_store(mut self, store: Arc<dyn MailStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn store(&self) -> &Arc<dyn MailStore> {
+        &self.store
+    }
+
     pub async fn check_mailbox_root(&self) -> Result<(), std::io::Error> {
-        self.check_mailbox_folder("").await?;
+        self.check_mailbox_folder("INBOX").await?;
         Ok(())
     }
 
-    pub async fn check_mailbox_folder<P>(&self, path_part: P) -> Result<(), std::io::Error>
-        where
-            P: AsRef<Path>,
-    {
-        let path = Path::new(&self.mailbox_root);
-        let path = path.join(&path_part.as_ref().to_owned());
-        let metadata = metadata(&path).await;
-        match metadata {
-            Err(_) => {
-                warn!("Mailbox folder {:?} was missing. Recreating", &path);
+    pub async fn check_mailbox_folder(&self, folder: &str) -> Result<(), std::io::Error> {
+        if !self.store.folder_exists(folder).await {
+            warn!(
+                "Mailbox folder {} of {} was missing. Recreating",
+                folder, self.user
+            );
 
-                create_dir_all(path).await?
-            }
-            Ok(_) => {}
+            self.store.create_folder(folder).await?
         }
         Ok(())
     }
 
-    pub async fn create_folder<P>(&self, path: P) -> Result<(), std::io::Error>
-        where
-            P: AsRef<Path>,
-    {
-        let path = &path.as_ref().to_owned();
-        self.check_mailbox_folder(path).await?;
+    pub async fn folder_exists(&self, folder: &str) -> bool {
+        self.store.folder_exists(folder).await
+    }
+
+    /// Creates `folder` together with the missing folders above it.
+    pub async fn create_folder(&self, folder: &str) -> Result<(), std::io::Error> {
+        if self.store.folder_exists(folder).await {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "Mailbox already exists",
+            ));
+        }
+        for parent in parents(folder) {
+            self.check_mailbox_folder(&parent).await?;
+        }
+        self.store.create_folder(folder).await
+    }
+
+    /// Deletes `folder` and its messages. The INBOX can't be deleted.
+    ///
+    /// A name that only exists because of folders below it has the `\Noselect`
+    /// attribute and deleting it is an error as well.
+    pub async fn delete_folder(&self, folder: &str) -> Result<(), std::io::Error> {
+        if folder.eq_ignore_ascii_case("INBOX") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "INBOX can't be deleted",
+            ));
+        }
+        if !self.store.folder_exists(folder).await {
+            let prefix = format!("{}{}", folder, DELIMITER);
+            let has_children = self
+                .store
+                .list_folders()
+                .await?
+                .iter()
+                .any(|known| known.starts_with(&prefix));
+            if has_children {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "Mailbox has inferior hierarchical names",
+                ));
+            }
+        }
+        self.store.delete_folder(folder).await
+    }
+
+    /// Renames `from` together with the folders below it.
+    ///
+    /// Renaming the INBOX moves its messages into the new folder instead and
+    /// leaves the INBOX empty, as required by RFC 3501.
+    pub async fn rename_folder(&self, from: &str, to: &str) -> Result<(), std::io::Error> {
+        if to.eq_ignore_ascii_case("INBOX") || self.store.folder_exists(to).await {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "Mailbox already exists",
+            ));
+        }
+        if !from.eq_ignore_ascii_case("INBOX") {
+            return self.store.rename_folder(from, to).await;
+        }
 
+        self.store.create_folder(to).await?;
+        let uids: Vec<u32> = self
+            .store
+            .list_messages("INBOX")
+            .await?
+            .iter()
+            .map(|message| message.uid)
+            .collect();
+        self.copy_messages("INBOX", &uids, to).await?;
+        for uid in uids {
+            self.store.remove_message("INBOX", uid).await?;
+        }
         Ok(())
     }
+
+    pub async fn folder_metadata(&self, folder: &str) -> Result<FolderMetadata, std::io::Error> {
+        self.store.folder_metadata(folder).await
+    }
+
+    /// Stores a message in `folder` and returns the UID of it.
+    pub async fn append_message(
+        &self,
+        folder: &str,
+        data: &[u8],
+        flags: &[String],
+        internal_date: Option<SystemTime>,
+    ) -> Result<u32, std::io::Error> {
+        self.store.append(folder, data, flags, internal_date).await
+    }
+
+    pub async fn list_messages(&self, folder: &str) -> Result<Vec<MessageInfo>, std::io::Error> {
+        self.store.list_messages(folder).await
+    }
+
+    pub async fn read_message(&self, folder: &str, uid: u32) -> Result<Vec<u8>, std::io::Error> {
+        self.store.read_message(folder, uid).await
+    }
+
+    /// Reads several messages at once, see `MailStore::read_messages`.
+    pub async fn read_messages(
+        &self,
+        folder: &str,
+        uids: &[u32],
+    ) -> Result<Vec<Result<Vec<u8>, std::io::Error>>, std::io::Error> {
+        self.store.read_messages(folder, uids).await
+    }
+
+    pub async fn set_flags(
+        &self,
+        folder: &str,
+        uid: u32,
+        flags: &[String],
+    ) -> Result<(), std::io::Error> {
+        self.store.set_flags(folder, uid, flags).await
+    }
+
+    pub async fn remove_message(&self, folder: &str, uid: u32) -> Result<(), std::io::Error> {
+        self.store.remove_message(folder, uid).await
+    }
+
+    /// Copies messages of `folder` into `target` keeping their flags and internal date.
+    ///
+    /// Returns the new UIDs in the order of `uids`. If one message fails the
+    /// copies that were already made get removed again so nothing is copied.
+    pub async fn copy_messages(
+        &self,
+        folder: &str,
+        uids: &[u32],
+        target: &str,
+    ) -> Result<Vec<u32>, std::io::Error> {
+        let messages = self.store.list_messages(folder).await?;
+        let contents = self.store.read_messages(folder, uids).await?;
+
+        let mut copied = Vec::new();
+        for (uid, content) in uids.iter().zip(contents) {
+            let result = match messages.iter().find(|message| message.uid == *uid) {
+                Some(message) => match content {
+                    Ok(data) => {
+                        self.store
+                            .append(target, &data, &message.flags, Some(message.internal_date))
+                            .await
+                    }
+                    Err(e) => Err(e),
+                },
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "message does not exist",
+                )),
+            };
+
+            match result {
+                Ok(new_uid) => copied.push(new_uid),
+                Err(e) => {
+                    for new_uid in copied {
+                        if let Err(e) = self.store.remove_message(target, new_uid).await {
+                            warn!("Unable to roll back copy {} in {}: {}", new_uid, target, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(copied)
+    }
 }
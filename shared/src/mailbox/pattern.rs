@@ -0,0 +1,88 @@
+//! Matching of folder names against the patterns of LIST and LSUB (RFC 3501 section 6.3.8).
+
+use super::maildir::DELIMITER;
+
+/// Joins the reference name and the pattern of a LIST command.
+///
+/// A pattern starting with the delimiter is taken as it is, otherwise it is
+/// interpreted relative to the reference.
+pub fn canonical_pattern(reference: &str, pattern: &str) -> String {
+    if reference.is_empty() || pattern.starts_with(DELIMITER) {
+        return pattern.to_string();
+    }
+    format!("{}{}", reference, pattern)
+}
+
+/// Checks if `name` matches `pattern`.
+///
+/// `*` matches any number of characters, `%` does the same but stops at the
+/// hierarchy delimiter. A leading `INBOX` is compared case-insensitively.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = inbox_prefix(pattern).chars().collect();
+    let name: Vec<char> = inbox_prefix(name).chars().collect();
+    matches_from(&pattern, &name)
+}
+
+/// Uppercases a leading `INBOX` so that it matches in any case.
+fn inbox_prefix(value: &str) -> String {
+    let inbox = value.len() >= 5
+        && value.is_char_boundary(5)
+        && value[..5].eq_ignore_ascii_case("INBOX")
+        && value[5..]
+            .chars()
+            .next()
+            .is_none_or(|c| c == DELIMITER || c == '*' || c == '%');
+    if inbox {
+        format!("INBOX{}", &value[5..])
+    } else {
+        value.to_string()
+    }
+}
+
+/// Runs the pattern over `name` one character at a time.
+///
+/// `states[i]` tells if the first `i` pattern characters can match what was
+/// read so far. This takes `pattern.len() * name.len()` steps where trying
+/// every split of the wildcards would be exponential.
+fn matches_from(pattern: &[char], name: &[char]) -> bool {
+    // Wildcards may match nothing, so the states after them are reached as well
+    let close = |states: &mut Vec<bool>| {
+        for i in 0..pattern.len() {
+            if states[i] && (pattern[i] == '*' || pattern[i] == '%') {
+                states[i + 1] = true;
+            }
+        }
+    };
+
+    let mut states = vec![false; pattern.len() + 1];
+    states[0] = true;
+    close(&mut states);
+
+    for c in name {
+        let mut next = vec![false; pattern.len() + 1];
+        for (i, wanted) in pattern.iter().enumerate() {
+            if !states[i] {
+                continue;
+            }
+            match wanted {
+                '*' => next[i] = true,
+                '%' if *c != DELIMITER => next[i] = true,
+                '%' => {}
+                wanted if wanted == c => next[i + 1] = true,
+                _ => {}
+            }
+        }
+        close(&mut next);
+        states = next;
+    }
+
+    states[pattern.len()]
+}
+
+/// All names above `name` in the hierarchy, e.g. `a` and `a.b` for `a.b.c`.
+pub fn parents(name: &str) -> Vec<String> {
+    name.match_indices(DELIMITER)
+        .map(|(index, _)| name[..index].to_string())
+        .filter(|parent| !parent.is_empty())
+        .collect()
+}
@@ -0,0 +1,136 @@
+//! The interface between a `Mailbox` and the place its messages are kept.
+
+use std::io;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use super::maildir::DELIMITER;
+
+/// Metadata about a single stored message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageInfo {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub size: u64,
+    pub internal_date: SystemTime,
+    /// Set until the flags of the message were changed for the first time
+    pub recent: bool,
+    /// The mod-sequence of the last change (RFC 7162)
+    pub modseq: u64,
+}
+
+/// The UID related state of a folder.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FolderMetadata {
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    /// The highest mod-sequence of all changes, including removed messages
+    pub highest_modseq: u64,
+}
+
+/// A storage backend for the folders and messages of a single user.
+///
+/// Folder names use `DELIMITER` for the hierarchy. `INBOX` is case-insensitive
+/// and always exists once the store was set up.
+///
+/// Adding a message, changing its flags and removing it raise the highest
+/// mod-sequence of the folder. A message keeps the value of its last change.
+#[async_trait]
+pub trait MailStore: Send + Sync {
+    /// Lists the names of all folders including the INBOX.
+    async fn list_folders(&self) -> Result<Vec<String>, io::Error>;
+
+    async fn folder_exists(&self, folder: &str) -> bool;
+
+    /// Creates `folder`. Creating a folder that already exists is not an error.
+    async fn create_folder(&self, folder: &str) -> Result<(), io::Error>;
+
+    /// Deletes `folder` with all its messages. Folders below it are kept.
+    async fn delete_folder(&self, folder: &str) -> Result<(), io::Error>;
+
+    /// Renames `from` and every folder below it, keeping their UIDs.
+    ///
+    /// Fails with `AlreadyExists` if one of the new names is taken.
+    async fn rename_folder(&self, from: &str, to: &str) -> Result<(), io::Error>;
+
+    async fn folder_metadata(&self, folder: &str) -> Result<FolderMetadata, io::Error>;
+
+    /// Stores a new message in `folder` and returns its UID.
+    async fn append(
+        &self,
+        folder: &str,
+        data: &[u8],
+        flags: &[String],
+        internal_date: Option<SystemTime>,
+    ) -> Result<u32, io::Error>;
+
+    /// Lists all messages of `folder` sorted by their UID.
+    async fn list_messages(&self, folder: &str) -> Result<Vec<MessageInfo>, io::Error>;
+
+    /// Reads the raw RFC 5322 content of a message.
+    async fn read_message(&self, folder: &str, uid: u32) -> Result<Vec<u8>, io::Error>;
+
+    /// Reads several messages of `folder` in the order of `uids`.
+    ///
+    /// Stores that have to look messages up should do it once for all of
+    /// them. A message that can't be read only fails its own entry.
+    async fn read_messages(
+        &self,
+        folder: &str,
+        uids: &[u32],
+    ) -> Result<Vec<Result<Vec<u8>, io::Error>>, io::Error> {
+        let mut messages = Vec::with_capacity(uids.len());
+        for uid in uids {
+            messages.push(self.read_message(folder, *uid).await);
+        }
+        Ok(messages)
+    }
+
+    /// Replaces the flags of a message.
+    async fn set_flags(&self, folder: &str, uid: u32, flags: &[String]) -> Result<(), io::Error>;
+
+    /// Permanently removes a message. Its UID is never used again.
+    async fn remove_message(&self, folder: &str, uid: u32) -> Result<(), io::Error>;
+}
+
+pub(crate) fn not_found(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, what.to_string())
+}
+
+pub(crate) fn already_exists(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, what.to_string())
+}
+
+/// Checks that `folder` can't address anything outside of the mailbox root.
+///
+/// A `/` would add path components, and a name of `.` or `..` turns into the
+/// parent directory once the Maildir++ prefix is added. An empty name would
+/// be the INBOX directory under a second name.
+pub fn safe_folder_name(folder: &str) -> bool {
+    !folder.is_empty() && !folder.contains('/') && folder != "." && folder != ".."
+}
+
+pub(crate) fn invalid_name() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "invalid folder name")
+}
+
+/// Maps `folder` to its new name if it is `from` or below it.
+pub(crate) fn renamed(folder: &str, from: &str, to: &str) -> Option<String> {
+    if folder == from {
+        return Some(to.to_string());
+    }
+    folder
+        .strip_prefix(from)
+        .filter(|rest| rest.starts_with(DELIMITER))
+        .map(|rest| format!("{}{}", to, rest))
+}
+
+/// `INBOX` is case-insensitive, all other names are kept as they are.
+pub(crate) fn normalize(folder: &str) -> String {
+    if folder.eq_ignore_ascii_case("INBOX") {
+        "INBOX".to_string()
+    } else {
+        folder.to_string()
+    }
+}
@@ -0,0 +1,57 @@
+//! The folders a user subscribed to, kept in the database.
+//!
+//! Subscriptions are independent of the folders themselves, so a name can
+//! stay subscribed after its folder was deleted or before it was created.
+
+use std::io;
+
+use diesel::prelude::*;
+
+use crate::database::establish_connection;
+use crate::models::NewSubscription;
+use crate::schema::subscriptions;
+
+use super::store::normalize;
+
+fn database_error(e: diesel::result::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Subscribes to `folder`. Subscribing twice is not an error.
+pub(crate) fn subscribe(user_id: i32, folder: &str) -> Result<(), io::Error> {
+    let connection = establish_connection();
+    let name = normalize(folder);
+
+    diesel::replace_into(subscriptions::table)
+        .values(&NewSubscription {
+            user_id,
+            name: &name,
+        })
+        .execute(&connection)
+        .map(|_| ())
+        .map_err(database_error)
+}
+
+/// Removes the subscription of `folder` and reports if there was one.
+pub(crate) fn unsubscribe(user_id: i32, folder: &str) -> Result<bool, io::Error> {
+    let connection = establish_connection();
+
+    diesel::delete(subscriptions::table)
+        .filter(subscriptions::user_id.eq(user_id))
+        .filter(subscriptions::name.eq(normalize(folder)))
+        .execute(&connection)
+        .map(|removed| removed > 0)
+        .map_err(database_error)
+}
+
+/// The names of all subscribed folders, sorted.
+pub(crate) fn list(user_id: i32) -> Result<Vec<String>, io::Error> {
+    let connection = establish_connection();
+
+    subscriptions::table
+        .filter(subscriptions::user_id.eq(user_id))
+        .order(subscriptions::name)
+        .select(subscriptions::name)
+        .load(&connection)
+        .map_err(database_error)
+}
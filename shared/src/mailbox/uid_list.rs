@@ -0,0 +1,317 @@
+//! The UIDs of Maildir messages and the UIDVALIDITY of folders, kept in the database.
+
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use diesel::expression::dsl::max;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::database::establish_connection;
+use crate::models::{Folder, NewFolder, NewMessageUid};
+use crate::schema::{folders, message_uids};
+
+use super::store::{normalize, FolderMetadata};
+
+fn database_error(e: diesel::result::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// A UIDVALIDITY that is bigger than every one the user had so far.
+///
+/// The current time is used so that a folder which got deleted together with
+/// its row still ends up with a new value.
+fn new_uid_validity(connection: &SqliteConnection, user_id: i32) -> QueryResult<i64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or(1);
+    let highest: Option<i64> = folders::table
+        .filter(folders::user_id.eq(user_id))
+        .select(max(folders::uid_validity))
+        .first(connection)?;
+
+    Ok(now.max(highest.unwrap_or(0) + 1).min(i64::from(u32::MAX)))
+}
+
+/// A message of the folder as stored in the database.
+struct Known {
+    uid: u32,
+    /// The unique part of the file name
+    file_id: String,
+    /// The flag letters at the last change
+    letters: String,
+    modseq: u64,
+}
+
+/// The UIDs and mod-sequences of the messages of a folder.
+pub(crate) struct UidList {
+    folder_id: i32,
+    pub metadata: FolderMetadata,
+    known: Vec<Known>,
+    added: Vec<u32>,
+    changed: Vec<u32>,
+    removed: Vec<u32>,
+}
+
+/// Flag letters in a canonical order, as other programs may not sort them.
+fn sorted(letters: &str) -> String {
+    let mut letters: Vec<char> = letters.chars().collect();
+    letters.sort_unstable();
+    letters.dedup();
+    letters.into_iter().collect()
+}
+
+impl UidList {
+    /// Loads the list of `folder` and creates it if the folder is new.
+    ///
+    /// With `recreate` a known folder forgets all its UIDs and gets a new UIDVALIDITY.
+    pub fn load(user_id: i32, folder: &str, recreate: bool) -> Result<Self, io::Error> {
+        let connection = establish_connection();
+        let name = normalize(folder);
+
+        connection
+            .transaction(|| {
+                let existing: Option<Folder> = folders::table
+                    .filter(folders::user_id.eq(user_id))
+                    .filter(folders::name.eq(&name))
+                    .first(&connection)
+                    .optional()?;
+
+                let row = match existing {
+                    Some(row) if !recreate => row,
+                    Some(row) => {
+                        diesel::delete(message_uids::table)
+                            .filter(message_uids::folder_id.eq(row.id))
+                            .execute(&connection)?;
+                        let uid_validity = new_uid_validity(&connection, user_id)?;
+                        diesel::update(folders::table.find(row.id))
+                            .set((
+                                folders::uid_validity.eq(uid_validity),
+                                folders::uid_next.eq(1),
+                            ))
+                            .execute(&connection)?;
+                        folders::table.find(row.id).first(&connection)?
+                    }
+                    None => {
+                        let uid_validity = new_uid_validity(&connection, user_id)?;
+                        diesel::insert_into(folders::table)
+                            .values(&NewFolder {
+                                user_id,
+                                name: &name,
+                                uid_validity,
+                                uid_next: 1,
+                            })
+                            .execute(&connection)?;
+                        folders::table
+                            .filter(folders::user_id.eq(user_id))
+                            .filter(folders::name.eq(&name))
+                            .first(&connection)?
+                    }
+                };
+
+                let known: Vec<(i64, String, String, i64)> = message_uids::table
+                    .filter(message_uids::folder_id.eq(row.id))
+                    .order(message_uids::uid)
+                    .select((
+                        message_uids::uid,
+                        message_uids::file_id,
+                        message_uids::flags,
+                        message_uids::modseq,
+                    ))
+                    .load(&connection)?;
+
+                Ok(UidList {
+                    folder_id: row.id,
+                    metadata: FolderMetadata {
+                        uid_validity: row.uid_validity as u32,
+                        uid_next: row.uid_next as u32,
+                        highest_modseq: row.highest_modseq as u64,
+                    },
+                    known: known
+                        .into_iter()
+                        .map(|(uid, file_id, letters, modseq)| Known {
+                            uid: uid as u32,
+                            file_id,
+                            letters,
+                            modseq: modseq as u64,
+                        })
+                        .collect(),
+                    added: Vec::new(),
+                    changed: Vec::new(),
+                    removed: Vec::new(),
+                })
+            })
+            .map_err(database_error)
+    }
+
+    /// Forgets `folder` and the UIDs of its messages.
+    pub fn remove(user_id: i32, folder: &str) -> Result<(), io::Error> {
+        let connection = establish_connection();
+        let name = normalize(folder);
+
+        connection
+            .transaction::<_, diesel::result::Error, _>(|| {
+                let ids = folders::table
+                    .filter(folders::user_id.eq(user_id))
+                    .filter(folders::name.eq(&name))
+                    .select(folders::id);
+                diesel::delete(message_uids::table.filter(message_uids::folder_id.eq_any(ids)))
+                    .execute(&connection)?;
+                diesel::delete(folders::table)
+                    .filter(folders::user_id.eq(user_id))
+                    .filter(folders::name.eq(&name))
+                    .execute(&connection)?;
+                Ok(())
+            })
+            .map_err(database_error)
+    }
+
+    /// Moves the UIDs of `from` over to `to`. Does nothing if `from` is unknown.
+    pub fn rename(user_id: i32, from: &str, to: &str) -> Result<(), io::Error> {
+        let connection = establish_connection();
+
+        diesel::update(folders::table)
+            .filter(folders::user_id.eq(user_id))
+            .filter(folders::name.eq(normalize(from)))
+            .set(folders::name.eq(normalize(to)))
+            .execute(&connection)
+            .map(|_| ())
+            .map_err(database_error)
+    }
+
+    /// Writes the UIDs assigned, changed and removed since loading.
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        if self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty() {
+            return Ok(());
+        }
+
+        let connection = establish_connection();
+        connection
+            .transaction::<_, diesel::result::Error, _>(|| {
+                for uid in &self.removed {
+                    diesel::delete(message_uids::table)
+                        .filter(message_uids::folder_id.eq(self.folder_id))
+                        .filter(message_uids::uid.eq(i64::from(*uid)))
+                        .execute(&connection)?;
+                }
+
+                for known in self
+                    .known
+                    .iter()
+                    .filter(|known| self.changed.contains(&known.uid))
+                {
+                    diesel::update(message_uids::table)
+                        .filter(message_uids::folder_id.eq(self.folder_id))
+                        .filter(message_uids::uid.eq(i64::from(known.uid)))
+                        .set((
+                            message_uids::flags.eq(&known.letters),
+                            message_uids::modseq.eq(known.modseq as i64),
+                        ))
+                        .execute(&connection)?;
+                }
+
+                let rows: Vec<NewMessageUid> = self
+                    .known
+                    .iter()
+                    .filter(|known| self.added.contains(&known.uid))
+                    .map(|known| NewMessageUid {
+                        folder_id: self.folder_id,
+                        uid: i64::from(known.uid),
+                        file_id: &known.file_id,
+                        flags: &known.letters,
+                        modseq: known.modseq as i64,
+                    })
+                    .collect();
+                diesel::insert_into(message_uids::table)
+                    .values(&rows)
+                    .execute(&connection)?;
+
+                diesel::update(folders::table.find(self.folder_id))
+                    .set((
+                        folders::uid_next.eq(i64::from(self.metadata.uid_next)),
+                        folders::highest_modseq.eq(self.metadata.highest_modseq as i64),
+                    ))
+                    .execute(&connection)?;
+                Ok(())
+            })
+            .map_err(database_error)?;
+
+        self.added.clear();
+        self.changed.clear();
+        self.removed.clear();
+        Ok(())
+    }
+
+    pub fn uid(&self, id: &str) -> Option<u32> {
+        self.known
+            .iter()
+            .find(|known| known.file_id == id)
+            .map(|known| known.uid)
+    }
+
+    pub fn id(&self, uid: u32) -> Option<&str> {
+        self.known
+            .iter()
+            .find(|known| known.uid == uid)
+            .map(|known| known.file_id.as_str())
+    }
+
+    fn next_modseq(&mut self) -> u64 {
+        self.metadata.highest_modseq += 1;
+        self.metadata.highest_modseq
+    }
+
+    /// Gives a new message the next UID.
+    pub fn assign(&mut self, id: String, letters: &str) -> u32 {
+        let uid = self.metadata.uid_next;
+        self.metadata.uid_next += 1;
+        let modseq = self.next_modseq();
+        self.known.push(Known {
+            uid,
+            file_id: id,
+            letters: sorted(letters),
+            modseq,
+        });
+        self.added.push(uid);
+        uid
+    }
+
+    /// Records the current flag letters of a message and returns its mod-sequence.
+    pub fn update(&mut self, uid: u32, letters: &str) -> u64 {
+        let letters = sorted(letters);
+        let position = match self.known.iter().position(|known| known.uid == uid) {
+            Some(position) => position,
+            None => return 0,
+        };
+        if self.known[position].letters != letters {
+            let modseq = self.next_modseq();
+            let known = &mut self.known[position];
+            known.letters = letters;
+            known.modseq = modseq;
+            if !self.added.contains(&uid) && !self.changed.contains(&uid) {
+                self.changed.push(uid);
+            }
+        }
+        self.known[position].modseq
+    }
+
+    /// Forgets the UIDs of all messages `keep` returns false for.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let removed = &mut self.removed;
+        let count = removed.len();
+        self.known.retain(|known| {
+            if keep(&known.file_id) {
+                return true;
+            }
+            removed.push(known.uid);
+            false
+        });
+        if self.removed.len() > count {
+            self.next_modseq();
+        }
+    }
+}
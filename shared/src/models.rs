@@ -1,10 +1,12 @@
-use super::schema::users;
+use super::schema::{folders, message_uids, subscriptions, users};
 
 #[derive(Debug, Queryable)]
 pub struct User {
     pub id: i32,
     pub email: String,
     pub password_hash: String,
+    // Queryable needs a field for every column
+    #[allow(dead_code)]
     pub uid_validity_identifier: String,
 }
 
@@ -15,3 +17,41 @@ pub struct NewUser<'a> {
     pub password_hash: &'a str,
     pub uid_validity_identifier: &'a str,
 }
+
+#[derive(Debug, Queryable)]
+pub struct Folder {
+    pub id: i32,
+    #[allow(dead_code)]
+    pub user_id: i32,
+    #[allow(dead_code)]
+    pub name: String,
+    pub uid_validity: i64,
+    pub uid_next: i64,
+    pub highest_modseq: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "folders"]
+pub struct NewFolder<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub uid_validity: i64,
+    pub uid_next: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "message_uids"]
+pub struct NewMessageUid<'a> {
+    pub folder_id: i32,
+    pub uid: i64,
+    pub file_id: &'a str,
+    pub flags: &'a str,
+    pub modseq: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "subscriptions"]
+pub struct NewSubscription<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+}
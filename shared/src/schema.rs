@@ -6,3 +6,39 @@ table! {
         uid_validity_identifier -> Text,
     }
 }
+
+table! {
+    folders (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        uid_validity -> BigInt,
+        uid_next -> BigInt,
+        highest_modseq -> BigInt,
+    }
+}
+
+table! {
+    message_uids (id) {
+        id -> Integer,
+        folder_id -> Integer,
+        uid -> BigInt,
+        file_id -> Text,
+        flags -> Text,
+        modseq -> BigInt,
+    }
+}
+
+table! {
+    subscriptions (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+    }
+}
+
+joinable!(folders -> users (user_id));
+joinable!(message_uids -> folders (folder_id));
+joinable!(subscriptions -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(folders, message_uids, subscriptions, users);
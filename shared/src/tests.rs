@@ -1,15 +1,21 @@
-use diesel;
+use std::io;
+use std::path::Path;
+
 use diesel::result::Error;
-use diesel::{Connection, RunQueryDsl, SqliteConnection};
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
 
+use crate::mailbox::pattern::{canonical_pattern, matches, parents};
+use crate::mailbox::subscriptions::{list, subscribe, unsubscribe};
+use crate::mailbox::{folder_of, FolderMetadata, MailStore, Maildir, MemoryStore};
 use crate::models::NewUser;
-use crate::schema::users;
+use crate::schema::{folders, message_uids, subscriptions, users};
 
-pub fn with_db<F>(f: F) -> ()
+pub fn with_db<F>(f: F)
 where
-    F: Fn(&SqliteConnection) -> (),
+    F: Fn(&SqliteConnection),
 {
     let conn = crate::database::establish_connection();
+    crate::embedded_migrations::run(&conn).expect("failed to run shared migrations");
 
     conn.test_transaction::<_, Error, _>(|| {
         f(&conn);
@@ -32,3 +38,288 @@ fn create_mailbox() {
             .expect("Failed to add new User");
     })
 }
+
+fn temp_root(name: &str) -> std::path::PathBuf {
+    let root = std::env::temp_dir().join(format!("imapserver-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    root
+}
+
+/// Runs the checks every `MailStore` has to pass against `store`.
+async fn check_store(store: &dyn MailStore) {
+    store.create_folder("INBOX").await.unwrap();
+    store.create_folder("Sent").await.unwrap();
+    assert_eq!(store.list_folders().await.unwrap(), vec!["INBOX", "Sent"]);
+    assert!(store.folder_exists("inbox").await);
+    assert!(!store.folder_exists("Drafts").await);
+
+    let metadata = store.folder_metadata("Sent").await.unwrap();
+    assert_eq!(metadata.uid_next, 1);
+
+    let message = b"Subject: Hello\r\n\r\nWorld\r\n";
+    let first = store.append("Sent", message, &[], None).await.unwrap();
+    let second = store
+        .append("Sent", message, &["\\Draft".to_string()], None)
+        .await
+        .unwrap();
+    assert!(second > first);
+    assert_eq!(
+        store.folder_metadata("Sent").await.unwrap(),
+        FolderMetadata {
+            uid_validity: metadata.uid_validity,
+            uid_next: second + 1,
+            highest_modseq: metadata.highest_modseq + 2,
+        }
+    );
+    assert!(store.append("Drafts", message, &[], None).await.is_err());
+
+    let messages = store.list_messages("Sent").await.unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].uid, first);
+    assert_eq!(messages[0].size, message.len() as u64);
+    assert!(messages[0].recent);
+    assert_eq!(messages[1].flags, vec!["\\Draft"]);
+    assert_eq!(messages[1].modseq, metadata.highest_modseq + 2);
+
+    let flags = vec!["\\Seen".to_string(), "Junk".to_string()];
+    store.set_flags("Sent", first, &flags).await.unwrap();
+    let messages = store.list_messages("Sent").await.unwrap();
+    assert_eq!(messages[0].flags, vec!["\\Seen", "Junk"]);
+    assert!(!messages[0].recent);
+    assert_eq!(messages[0].modseq, metadata.highest_modseq + 3);
+
+    // Storing the same flags again is not a change
+    store.set_flags("Sent", first, &flags).await.unwrap();
+    let highest_modseq = store.folder_metadata("Sent").await.unwrap().highest_modseq;
+    assert_eq!(highest_modseq, metadata.highest_modseq + 3);
+
+    assert_eq!(store.read_message("Sent", first).await.unwrap(), message);
+    assert!(store.read_message("Sent", second + 1).await.is_err());
+
+    let contents = store
+        .read_messages("Sent", &[second + 1, first])
+        .await
+        .unwrap();
+    assert_eq!(contents.len(), 2);
+    assert!(contents[0].is_err());
+    assert_eq!(contents[1].as_ref().unwrap(), &message);
+
+    store.remove_message("Sent", first).await.unwrap();
+    let messages = store.list_messages("Sent").await.unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].uid, second);
+    assert!(store.read_message("Sent", first).await.is_err());
+    assert!(store.remove_message("Sent", first).await.is_err());
+    let after_removal = store.folder_metadata("Sent").await.unwrap();
+    assert_eq!(after_removal.uid_next, second + 1);
+    assert!(after_removal.highest_modseq > highest_modseq);
+
+    // Renaming moves the whole hierarchy and keeps the UIDs
+    store.create_folder("Work").await.unwrap();
+    store.create_folder("Work.2020").await.unwrap();
+    let uid = store.append("Work", message, &[], None).await.unwrap();
+    let metadata = store.folder_metadata("Work").await.unwrap();
+    store.rename_folder("Work", "Projects").await.unwrap();
+    assert_eq!(
+        store.list_folders().await.unwrap(),
+        vec!["INBOX", "Projects", "Projects.2020", "Sent"]
+    );
+    assert_eq!(store.folder_metadata("Projects").await.unwrap(), metadata);
+    assert_eq!(store.read_message("Projects", uid).await.unwrap(), message);
+
+    let error = store.rename_folder("Projects", "Sent").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    let error = store.rename_folder("Work", "Other").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+    // Deleting keeps the folders below
+    store.delete_folder("Projects").await.unwrap();
+    assert_eq!(
+        store.list_folders().await.unwrap(),
+        vec!["INBOX", "Projects.2020", "Sent"]
+    );
+    assert!(store.delete_folder("Projects").await.is_err());
+    assert!(store.delete_folder("INBOX").await.is_err());
+    store.delete_folder("Projects.2020").await.unwrap();
+}
+
+/// Adds a user outside of a transaction for stores that open their own connections.
+fn add_test_user(name: &str) -> i32 {
+    crate::setup();
+    let conn = crate::database::establish_connection();
+    let test_email = format!("{}-{}@localhost", name, std::process::id());
+    remove_test_user(&test_email);
+
+    diesel::insert_into(users::table)
+        .values(&NewUser {
+            email: &test_email,
+            password_hash: "test",
+            uid_validity_identifier: "0000",
+        })
+        .execute(&conn)
+        .expect("Failed to add new User");
+    users::table
+        .filter(users::email.eq(&test_email))
+        .select(users::id)
+        .first(&conn)
+        .expect("Failed to load new User")
+}
+
+fn remove_test_user(test_email: &str) {
+    let conn = crate::database::establish_connection();
+    let user_ids = users::table
+        .filter(users::email.eq(test_email))
+        .select(users::id);
+    let folder_ids = folders::table
+        .filter(folders::user_id.eq_any(user_ids))
+        .select(folders::id);
+    diesel::delete(message_uids::table.filter(message_uids::folder_id.eq_any(folder_ids)))
+        .execute(&conn)
+        .unwrap();
+    diesel::delete(folders::table.filter(folders::user_id.eq_any(user_ids)))
+        .execute(&conn)
+        .unwrap();
+    diesel::delete(subscriptions::table.filter(subscriptions::user_id.eq_any(user_ids)))
+        .execute(&conn)
+        .unwrap();
+    diesel::delete(users::table.filter(users::email.eq(test_email)))
+        .execute(&conn)
+        .unwrap();
+}
+
+#[tokio::test]
+async fn maildir_store() {
+    let root = temp_root("maildir");
+    let user_id = add_test_user("maildir");
+    check_store(&Maildir::new(&root, user_id)).await;
+
+    // The UIDs have to survive a new store instance
+    let maildir = Maildir::new(&root, user_id);
+    let uids: Vec<u32> = maildir
+        .list_messages("Sent")
+        .await
+        .unwrap()
+        .iter()
+        .map(|message| message.uid)
+        .collect();
+    assert_eq!(uids, vec![2]);
+
+    // Names must not leave the mailbox root, not even to a sibling mailbox
+    let victim = root.with_file_name(format!("victim-{}", std::process::id()));
+    std::fs::create_dir_all(victim.join("cur")).unwrap();
+    let escape = format!("/../{}", victim.file_name().unwrap().to_string_lossy());
+    for name in &[escape.as_str(), "", ".", "..", "Sent/../.."] {
+        assert!(maildir.folder_path(name).is_err(), "{}", name);
+        let e = maildir.delete_folder(name).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", name);
+        assert!(!maildir.folder_exists(name).await);
+        assert!(maildir.list_messages(name).await.is_err());
+    }
+    assert!(victim.join("cur").is_dir());
+    std::fs::remove_dir_all(victim).unwrap();
+    assert_eq!(maildir.folder_path("inbox").unwrap(), root);
+    assert_eq!(
+        maildir.folder_path("Archive.2019").unwrap(),
+        root.join(".Archive.2019")
+    );
+
+    // A recreated folder must not reuse the old UIDs
+    let old = maildir.folder_metadata("Sent").await.unwrap();
+    std::fs::remove_dir_all(root.join(".Sent")).unwrap();
+    maildir.create_folder("Sent").await.unwrap();
+    let new = maildir.folder_metadata("Sent").await.unwrap();
+    assert!(new.uid_validity > old.uid_validity);
+    assert_eq!(new.uid_next, 1);
+
+    // A stale row for the new name stops the rename before the directory moves
+    diesel::insert_into(folders::table)
+        .values((
+            folders::user_id.eq(user_id),
+            folders::name.eq("Stale"),
+            folders::uid_validity.eq(1),
+            folders::uid_next.eq(1),
+        ))
+        .execute(&crate::database::establish_connection())
+        .unwrap();
+    assert!(maildir.rename_folder("Sent", "Stale").await.is_err());
+    assert!(maildir.folder_exists("Sent").await);
+    assert!(!maildir.folder_exists("Stale").await);
+    assert_eq!(maildir.folder_metadata("Sent").await.unwrap(), new);
+
+    remove_test_user(&format!("maildir-{}@localhost", std::process::id()));
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[tokio::test]
+async fn memory_store() {
+    check_store(&MemoryStore::new()).await;
+}
+
+#[test]
+fn subscribe_and_unsubscribe() {
+    let user_id = add_test_user("subscriptions");
+
+    subscribe(user_id, "Sent").unwrap();
+    subscribe(user_id, "inbox").unwrap();
+    // Subscribing twice and to folders that don't exist is fine
+    subscribe(user_id, "Sent").unwrap();
+    subscribe(user_id, "Missing").unwrap();
+    assert_eq!(list(user_id).unwrap(), vec!["INBOX", "Missing", "Sent"]);
+
+    assert!(unsubscribe(user_id, "Missing").unwrap());
+    assert!(!unsubscribe(user_id, "Missing").unwrap());
+    assert_eq!(list(user_id).unwrap(), vec!["INBOX", "Sent"]);
+
+    remove_test_user(&format!("subscriptions-{}@localhost", std::process::id()));
+    assert!(list(user_id).unwrap().is_empty());
+}
+
+#[test]
+fn list_patterns() {
+    assert!(matches("*", "Archive.2019.Q1"));
+    assert!(matches("%", "Archive"));
+    assert!(!matches("%", "Archive.2019"));
+    assert!(matches("Archive.%", "Archive.2019"));
+    assert!(!matches("Archive.%", "Archive.2019.Q1"));
+    assert!(matches("Archive.*", "Archive.2019.Q1"));
+    assert!(matches("A%.2019", "Archive.2019"));
+    assert!(!matches("Archive", "archive"));
+    assert!(matches("inbox", "INBOX"));
+    assert!(matches("Inbox.%", "INBOX.Sub"));
+    assert!(matches("inbox*", "INBOX"));
+    assert!(!matches("inboxes", "INBOXES"));
+    assert!(matches("%.%", "Archive.2019"));
+    assert!(!matches("%a%", "Ar.chive"));
+
+    // Many wildcards against a long name must not backtrack through every split
+    let name = "a".repeat(200);
+    assert!(!matches("*a*a*a*a*a*a*a*a*a*a*b", &name));
+    assert!(!matches("%a%a%a%a%a%a%a%a%a%a%b", &name));
+    assert!(matches("*a*a*a*a*a*a*a*a*a*a*", &name));
+
+    assert_eq!(canonical_pattern("Archive.", "%"), "Archive.%");
+    assert_eq!(canonical_pattern("", "*"), "*");
+    assert_eq!(canonical_pattern("Archive.", ".Other"), ".Other");
+
+    assert_eq!(parents("a.b.c"), vec!["a", "a.b"]);
+    assert!(parents("INBOX").is_empty());
+}
+
+#[test]
+fn maildir_paths() {
+    let root = Path::new("/var/mail/user");
+    let folder = |path: &str| folder_of(root, Path::new(path));
+
+    assert_eq!(
+        folder("/var/mail/user/new/1.M2P3.host"),
+        Some("INBOX".to_string())
+    );
+    assert_eq!(
+        folder("/var/mail/user/.Archive.2019/cur"),
+        Some("Archive.2019".to_string())
+    );
+    assert_eq!(folder("/var/mail/user/.Archive/tmp/1.M2P3.host"), None);
+    assert_eq!(folder("/var/mail/user/.Archive/dovecot-keywords"), None);
+    assert_eq!(folder("/var/mail/user/dovecot-keywords"), None);
+    assert_eq!(folder("/var/mail/other/new/1.M2P3.host"), None);
+}
@@ -0,0 +1,265 @@
+//! A codec that frames complete IMAP commands including their literals.
+//!
+//! Every line that ends with a literal announcement (`{N}`, `{N+}`) gets
+//! joined with the literal data and the following line until a line without
+//! a literal ends the command. Synchronizing literals produce an
+//! [`Input::ContinuationRequest`] so that the connection can ask the client
+//! for the data (RFC 3501 section 7.5), non-synchronizing literals (RFC 7888)
+//! are read right away.
+//!
+//! A whole command, all literals included, may be at most `MAX_LINE_LENGTH`
+//! bigger than the configured maximum literal size.
+
+use std::fmt;
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Longest line (without literal data) we accept before giving up on a client.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Biggest non-synchronizing literal allowed when LITERAL- is advertised.
+const LITERAL_MINUS_LIMIT: usize = 4096;
+
+/// What the decoder produces for the connection.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Input {
+    /// A complete command without the final CRLF. Literals are inlined as `{N}\r\n<data>`.
+    Command(Vec<u8>),
+    /// The client announced a synchronizing literal and waits for a `+` response.
+    ContinuationRequest,
+    /// A literal was bigger than allowed. The command was dropped.
+    ///
+    /// A synchronizing literal is refused before the client sends it, the
+    /// data of a non-synchronizing one was read and thrown away.
+    LiteralTooLarge {
+        tag: Option<String>,
+        synchronizing: bool,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) enum ImapCodecError {
+    /// A line was longer than `MAX_LINE_LENGTH`.
+    MaxLineLengthExceeded,
+    Io(io::Error),
+}
+
+impl fmt::Display for ImapCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImapCodecError::MaxLineLengthExceeded => write!(f, "max line length exceeded"),
+            ImapCodecError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImapCodecError {}
+
+impl From<io::Error> for ImapCodecError {
+    fn from(e: io::Error) -> ImapCodecError {
+        ImapCodecError::Io(e)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ImapCodec {
+    /// The command assembled so far.
+    frame: Vec<u8>,
+    /// Bytes of literal data still to be read.
+    literal_remaining: usize,
+    /// Set when a literal was too large. The rest of the command gets thrown away.
+    discarding: bool,
+    /// Set when a line was too long. Everything up to the next newline gets thrown away.
+    skipping_line: bool,
+    max_literal_size: usize,
+    literal_minus: bool,
+}
+
+/// Checks if `line` (without CRLF) ends with a literal announcement.
+///
+/// Returns the length and whether the literal is synchronizing.
+fn literal_announcement(line: &[u8]) -> Option<(usize, bool)> {
+    let line = line.strip_suffix(b"}")?;
+    let (line, synchronizing) = match line.strip_suffix(b"+") {
+        Some(line) => (line, false),
+        None => (line, true),
+    };
+    let open = line.iter().rposition(|c| *c == b'{')?;
+    let digits = &line[open + 1..];
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let length = std::str::from_utf8(digits).ok()?.parse().ok()?;
+    Some((length, synchronizing))
+}
+
+impl ImapCodec {
+    pub fn new(max_literal_size: usize, literal_minus: bool) -> Self {
+        ImapCodec {
+            frame: Vec::new(),
+            literal_remaining: 0,
+            discarding: false,
+            skipping_line: false,
+            max_literal_size,
+            literal_minus,
+        }
+    }
+
+    fn tag(&self) -> Option<String> {
+        let end = self.frame.iter().position(|c| *c == b' ')?;
+        Some(String::from_utf8_lossy(&self.frame[..end]).into_owned())
+    }
+
+    /// Checks a literal of `length` bytes against the limits.
+    ///
+    /// The command assembled so far counts as well, as the number of literals
+    /// in a command is not limited.
+    fn too_large(&self, length: usize, synchronizing: bool) -> bool {
+        length > self.max_literal_size
+            || self.frame.len().saturating_add(length)
+                > self.max_literal_size.saturating_add(MAX_LINE_LENGTH)
+            || (self.literal_minus && !synchronizing && length > LITERAL_MINUS_LIMIT)
+    }
+
+    fn reset(&mut self) {
+        self.frame.clear();
+        self.literal_remaining = 0;
+        self.discarding = false;
+    }
+}
+
+impl Decoder for ImapCodec {
+    type Item = Input;
+    type Error = ImapCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Input>, ImapCodecError> {
+        loop {
+            if self.literal_remaining > 0 {
+                let available = self.literal_remaining.min(src.len());
+                if !self.discarding {
+                    self.frame.extend_from_slice(&src[..available]);
+                }
+                src.advance(available);
+                self.literal_remaining -= available;
+
+                if self.literal_remaining > 0 {
+                    return Ok(None);
+                }
+            }
+
+            let newline = src.iter().position(|c| *c == b'\n');
+
+            if self.skipping_line {
+                match newline {
+                    Some(newline) => {
+                        src.advance(newline + 1);
+                        self.skipping_line = false;
+                        continue;
+                    }
+                    None => {
+                        src.clear();
+                        return Ok(None);
+                    }
+                }
+            }
+
+            // The rest of a line that is too long is skipped, like `LinesCodec` does.
+            // Otherwise the same bytes would fail again on the next call.
+            let newline = match newline {
+                Some(newline) if newline <= MAX_LINE_LENGTH => newline,
+                Some(newline) => {
+                    src.advance(newline + 1);
+                    self.reset();
+                    return Err(ImapCodecError::MaxLineLengthExceeded);
+                }
+                None if src.len() > MAX_LINE_LENGTH => {
+                    src.clear();
+                    self.reset();
+                    self.skipping_line = true;
+                    return Err(ImapCodecError::MaxLineLengthExceeded);
+                }
+                None => return Ok(None),
+            };
+
+            let line = src.split_to(newline + 1);
+            let content = line
+                .strip_suffix(b"\r\n")
+                .or_else(|| line.strip_suffix(b"\n"))
+                .expect("line ends with a newline");
+
+            match literal_announcement(content) {
+                Some((length, synchronizing)) if !self.discarding => {
+                    if self.too_large(length, synchronizing) {
+                        if synchronizing {
+                            // The client waits for our answer and won't send the data
+                            self.frame.extend_from_slice(content);
+                            let tag = self.tag();
+                            self.reset();
+                            return Ok(Some(Input::LiteralTooLarge {
+                                tag,
+                                synchronizing: true,
+                            }));
+                        }
+
+                        self.frame.extend_from_slice(content);
+                        self.discarding = true;
+                        self.literal_remaining = length;
+                        continue;
+                    }
+
+                    self.frame.extend_from_slice(content);
+                    self.frame.extend_from_slice(b"\r\n");
+                    self.literal_remaining = length;
+
+                    // Even `{0}` needs the continuation, the client waits for it
+                    if synchronizing {
+                        return Ok(Some(Input::ContinuationRequest));
+                    }
+                }
+                Some((length, _)) => {
+                    self.literal_remaining = length;
+                }
+                None => {
+                    // Only non-synchronizing literals are discarded
+                    if self.discarding {
+                        let tag = self.tag();
+                        self.reset();
+                        return Ok(Some(Input::LiteralTooLarge {
+                            tag,
+                            synchronizing: false,
+                        }));
+                    }
+
+                    self.frame.extend_from_slice(content);
+                    let frame = std::mem::take(&mut self.frame);
+                    self.reset();
+                    return Ok(Some(Input::Command(frame)));
+                }
+            }
+        }
+    }
+}
+
+/// Responses are written like with `LinesCodec`: a `\n` gets appended to each message.
+///
+/// The bytes are not touched otherwise, so literals can carry 8-bit data.
+impl Encoder<Vec<u8>> for ImapCodec {
+    type Error = ImapCodecError;
+
+    fn encode(&mut self, message: Vec<u8>, dst: &mut BytesMut) -> Result<(), ImapCodecError> {
+        dst.reserve(message.len() + 1);
+        dst.put(message.as_slice());
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+impl Encoder<String> for ImapCodec {
+    type Error = ImapCodecError;
+
+    fn encode(&mut self, message: String, dst: &mut BytesMut) -> Result<(), ImapCodecError> {
+        self.encode(message.into_bytes(), dst)
+    }
+}
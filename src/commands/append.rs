@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::{DateTime, FixedOffset};
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::Commands;
+use crate::{Shared, State};
+
+impl Commands {
+    pub async fn append(
+        identifier: &str,
+        path: String,
+        flags: Vec<String>,
+        date: Option<DateTime<FixedOffset>>,
+        message: Vec<u8>,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let mailbox = state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .clone()
+            .expect("failed to get mailbox");
+
+        if !mailbox.folder_exists(&path).await {
+            // Tells the client that it may CREATE the folder and try again
+            let response = format!(
+                "{} {}",
+                identifier, "NO [TRYCREATE] Mailbox does not exist\r"
+            );
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!(
+                "Responded: {} {}",
+                identifier, "NO [TRYCREATE] Mailbox does not exist"
+            );
+            return Ok(());
+        }
+
+        // \Recent is managed by the server and can't be set by clients
+        let flags: Vec<String> = flags
+            .into_iter()
+            .filter(|flag| !flag.eq_ignore_ascii_case("\\Recent"))
+            .collect();
+        let internal_date = date.map(SystemTime::from);
+
+        let response = match mailbox
+            .append_message(&path, &message, &flags, internal_date)
+            .await
+        {
+            Ok(uid) => {
+                debug!("Appended message {} to {}", uid, path);
+                state.refresh_idle(&mailbox.user, &path).await;
+                // The EXISTS for the new message goes out before the tagged response
+                let selected = matches!(
+                    &state.peers.get(&addr).expect("unable to find peer").state,
+                    State::Selected(selected) if selected.folder == path
+                );
+                if selected {
+                    state.synchronize(addr).await;
+                }
+                match mailbox.folder_metadata(&path).await {
+                    Ok(metadata) => format!(
+                        "{} OK [APPENDUID {} {}] APPEND completed\r",
+                        identifier, metadata.uid_validity, uid
+                    ),
+                    Err(_) => format!("{} {}", identifier, "OK APPEND completed\r"),
+                }
+            }
+            Err(e) => {
+                error!("Unable to append message to {}: {}", path, e);
+                format!("{} {}", identifier, "NO Unable to store message\r")
+            }
+        };
+
+        state.respond(addr, &response).await?;
+
+        //Print to view for debug
+        debug!("Responded: {}", response);
+
+        Ok(())
+    }
+}
@@ -2,154 +2,274 @@ use std::net::SocketAddr;
 use std::result::Result::{Err, Ok};
 use std::sync::Arc;
 
-use base64::decode;
+use base64::{decode, encode};
 use log::debug;
 use tokio::sync::{mpsc, Mutex};
 
 use IMAPServer_shared::mailbox::Mailbox;
 
+use crate::commands::capability::mechanism_allowed;
+use crate::sasl::{self, Exchange, Mechanism, Step};
 use crate::{Shared, State};
 
 pub(crate) struct Authentication;
 
 impl Authentication {
-    pub async fn parse_login_data(
-        args: Vec<&str>,
+    /// Checks the credentials and logs the connection in if they match.
+    ///
+    /// Shared by LOGIN and AUTHENTICATE so both answer the same way.
+    async fn log_in(
+        state: &mut Shared,
         addr: SocketAddr,
-        state: Arc<Mutex<Shared>>,
+        identifier: &str,
+        user: &str,
+        password: &str,
+        authzid: Option<&str>,
+        success: &str,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let bytes = decode(args[0]).expect("unable to decode");
-        let string = match String::from_utf8(bytes) {
-            Ok(v) => v,
-            Err(e) => format!("Invalid UTF-8 sequence: {}", e),
-        };
-        let string_str = &string;
-        let up: Vec<&str> = string_str.split("\u{0000}").collect();
-
-        let mut state = state.lock().await;
-
-        if up.len() < 2 {
-            return Ok(());
-        }
-
-        let mailbox = Mailbox::load(up[1].to_string()).await;
-
-        match mailbox {
-            Some(mailbox) => {
-                let authenticated = mailbox.check_password_plain(up[2].to_string()).await;
-
-                match authenticated {
-                    Ok(_) => {
-                        state.respond(addr, "+\r").await?;
-                        debug!("Responded: +");
-                        // DO NOT INLINE!
-                        let response = format!(
-                            "{} {}",
-                            &state
-                                .peers
-                                .get(&addr)
-                                .expect("unable to find peer")
-                                .identifier,
-                            "OK PLAIN authentication successful\r"
-                        );
-                        state.respond(addr, &response).await?;
-
-                        state
-                            .peers
-                            .get_mut(&addr)
-                            .expect("unable to find peer")
-                            .mailbox = Some(mailbox);
-
-                        state
-                            .peers
-                            .get_mut(&addr)
-                            .expect("unable to find peer")
-                            .state = State::LoggedIn;
-
-                        //Print to view for debug
-                        debug!(
-                            "Responded: {} {}",
-                            &state
-                                .peers
-                                .get(&addr)
-                                .expect("unable to find peer")
-                                .identifier,
-                            "OK PLAIN authentication successful\r"
-                        );
-                    }
-
-                    Err(_) => {
-                        state.respond(addr, "+\r").await?;
-                        // DO NOT INLINE!
-                        let response = format!(
-                            "{} {}",
-                            &state
-                                .peers
-                                .get(&addr)
-                                .expect("unable to find peer")
-                                .identifier,
-                            "NO credentials rejected\r"
-                        );
-                        state.respond(addr, &response).await?;
-
-                        //Print to view for debug
-                        debug!(
-                            "Responded: {} {}",
-                            &state
-                                .peers
-                                .get(&addr)
-                                .expect("unable to find peer")
-                                .identifier,
-                            "NO credentials rejected\r"
-                        );
-                    }
-                }
+        let config = Arc::clone(&state.config);
+        let mailbox = match Mailbox::load(user.to_string(), &config).await {
+            Some(mailbox)
+                if mailbox
+                    .check_password_plain(password.to_string(), &config)
+                    .await
+                    .is_ok() =>
+            {
+                mailbox
             }
-            None => {
-                state.respond(addr, "+\r").await?;
-                // DO NOT INLINE!
+            _ => {
                 let response = format!(
                     "{} {}",
-                    &state
-                        .peers
-                        .get(&addr)
-                        .expect("unable to find peer")
-                        .identifier,
-                    "NO credentials rejected\r"
+                    identifier, "NO [AUTHENTICATIONFAILED] credentials rejected\r"
                 );
                 state.respond(addr, &response).await?;
 
                 //Print to view for debug
                 debug!(
                     "Responded: {} {}",
-                    &state
-                        .peers
-                        .get(&addr)
-                        .expect("unable to find peer")
-                        .identifier,
-                    "NO credentials rejected\r"
+                    identifier, "NO [AUTHENTICATIONFAILED] credentials rejected"
                 );
+                return Ok(());
             }
+        };
+
+        // Acting as another user is not supported
+        if authzid.is_some_and(|authzid| authzid != user) {
+            let response = format!(
+                "{} {}",
+                identifier, "NO [AUTHORIZATIONFAILED] Not allowed to act as that user\r"
+            );
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!(
+                "Responded: {} {}",
+                identifier, "NO [AUTHORIZATIONFAILED] Not allowed to act as that user"
+            );
+            return Ok(());
         }
+
+        let connection = state.peers.get_mut(&addr).expect("unable to find peer");
+        connection.mailbox = Some(mailbox);
+        connection.state = State::Authenticated;
+
+        // The capabilities change with logging in
+        let response = format!(
+            "{} OK [CAPABILITY {}] {}\r",
+            identifier,
+            state.capabilities(addr),
+            success
+        );
+        state.respond(addr, &response).await?;
+
+        //Print to view for debug
+        debug!("Responded: {}", response);
         Ok(())
     }
 
-    pub async fn authenticate(
-        args: Vec<&str>,
+    /// Continues the exchange with the next state of the mechanism.
+    async fn advance(
+        state: &mut Shared,
+        addr: SocketAddr,
+        identifier: &str,
+        mechanism: Box<dyn Mechanism>,
+        step: Result<Step, String>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        match step {
+            Ok(Step::Challenge(challenge)) => {
+                state
+                    .peers
+                    .get_mut(&addr)
+                    .expect("unable to find peer")
+                    .sasl = Some(Exchange {
+                    tag: identifier.to_string(),
+                    mechanism,
+                });
+
+                let response = format!("+ {}\r", encode(&challenge));
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!("Responded: {}", response);
+                Ok(())
+            }
+            Ok(Step::Done(credentials)) => {
+                Self::log_in(
+                    state,
+                    addr,
+                    identifier,
+                    &credentials.user,
+                    &credentials.password,
+                    credentials.authzid.as_deref(),
+                    "AUTHENTICATE completed",
+                )
+                .await
+            }
+            Err(reason) => {
+                let response = format!("{} BAD {}\r", identifier, reason);
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!("Responded: {} BAD {}", identifier, reason);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handles a line sent while an AUTHENTICATE exchange is running.
+    ///
+    /// Returns false if there is no exchange and the line is a command.
+    pub async fn respond(
+        line: &str,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<bool, mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let Exchange { tag, mut mechanism } = match state
+            .peers
+            .get_mut(&addr)
+            .and_then(|connection| connection.sasl.take())
+        {
+            Some(exchange) => exchange,
+            None => return Ok(false),
+        };
+
+        let line = line.trim();
+        if line == "*" {
+            let response = format!("{} {}", tag, "BAD AUTHENTICATE cancelled\r");
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} {}", tag, "BAD AUTHENTICATE cancelled");
+            return Ok(true);
+        }
+
+        let step = match decode(line) {
+            Ok(response) => mechanism.step(&response),
+            Err(_) => Err("Invalid base64 in the response".to_string()),
+        };
+        Self::advance(&mut state, addr, &tag, mechanism, step).await?;
+        Ok(true)
+    }
+
+    /// Logs in with a plain text user name and password (RFC 3501 section 6.2.3).
+    ///
+    /// Unencrypted connections are refused unless `plaintext_login` is set,
+    /// the same as for the plain text SASL mechanisms.
+    pub async fn login(
+        identifier: &str,
+        user: String,
+        password: String,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
+        let mut state = state.lock().await;
+
+        let tls = state.peers.get(&addr).expect("unable to find peer").tls;
+        if !state.config.login_allowed(tls) {
+            let response = format!(
+                "{} {}",
+                identifier, "NO [PRIVACYREQUIRED] Use STARTTLS before logging in\r"
+            );
+            state.respond(addr, &response).await?;
 
+            //Print to view for debug
+            debug!(
+                "Responded: {} {}",
+                identifier, "NO [PRIVACYREQUIRED] Use STARTTLS before logging in"
+            );
+            return Ok(());
+        }
+
+        Self::log_in(
+            &mut state,
+            addr,
+            identifier,
+            &user,
+            &password,
+            None,
+            "LOGIN completed",
+        )
+        .await
+    }
+
+    /// Starts a SASL exchange (RFC 3501 section 6.2.2), with an optional
+    /// initial response (RFC 4959).
+    pub async fn authenticate(
+        identifier: &str,
+        mechanism: String,
+        initial_response: Option<String>,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
         let mut state = state.lock().await;
-        let mut peer = state.peers.get_mut(&addr).expect("unable to find peer");
 
-        peer.identifier = identifier.to_string();
+        let registered = match sasl::registered(&mechanism) {
+            Some(registered) => registered,
+            None => {
+                let response = format!(
+                    "{} {}",
+                    identifier, "NO Unsupported authentication mechanism\r"
+                );
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!(
+                    "Responded: {} {}",
+                    identifier, "NO Unsupported authentication mechanism"
+                );
+                return Ok(());
+            }
+        };
 
-        state.respond(addr, "+\r").await?;
+        // Plain text passwords are refused where LOGIN would be
+        let tls = state.peers.get(&addr).expect("unable to find peer").tls;
+        if !mechanism_allowed(&state.config, tls, registered) {
+            let response = format!(
+                "{} {}",
+                identifier, "NO [PRIVACYREQUIRED] Use STARTTLS before authenticating\r"
+            );
+            state.respond(addr, &response).await?;
 
-        //Print to view for debug
-        debug!("Responded: +");
-        Ok(())
+            //Print to view for debug
+            debug!(
+                "Responded: {} {}",
+                identifier, "NO [PRIVACYREQUIRED] Use STARTTLS before authenticating"
+            );
+            return Ok(());
+        }
+
+        let mut mechanism = registered.start();
+        // A lone "=" is an empty initial response
+        let step = match initial_response.as_deref() {
+            None => Ok(Step::Challenge(mechanism.initial_challenge())),
+            Some("=") => mechanism.step(&[]),
+            Some(response) => match decode(response) {
+                Ok(response) => mechanism.step(&response),
+                Err(_) => Err("Invalid base64 in the initial response".to_string()),
+            },
+        };
+        Self::advance(&mut state, addr, identifier, mechanism, step).await
     }
 }
@@ -0,0 +1,190 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::config::Config;
+
+use crate::commands::Commands;
+use crate::sasl;
+use crate::{Shared, State};
+
+/// What decides which capabilities a connection gets to see.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Advertising {
+    /// If the connection is encrypted
+    pub tls: bool,
+    /// If the client logged in already
+    pub authenticated: bool,
+    /// If plain text connections can be upgraded, which needs a TLS acceptor
+    pub starttls: bool,
+}
+
+/// Stands for one `AUTH=<name>` for each mechanism in `sasl`.
+const AUTH: &str = "AUTH=";
+
+/// A capability and when it is advertised.
+struct Capability {
+    name: &'static str,
+    advertised: fn(&Config, Advertising) -> bool,
+}
+
+fn always(_: &Config, _: Advertising) -> bool {
+    true
+}
+
+/// Checks if `mechanism` may be used on the connection.
+///
+/// Plain text passwords follow the same rules as LOGIN.
+pub(crate) fn mechanism_allowed(config: &Config, tls: bool, mechanism: &sasl::Registered) -> bool {
+    if mechanism.plaintext {
+        config.login_allowed(tls)
+    } else {
+        tls || !config.tls_required
+    }
+}
+
+/// AUTHENTICATE is possible before logging in if any mechanism may be used.
+fn authenticate(config: &Config, advertising: Advertising) -> bool {
+    !advertising.authenticated
+        && sasl::MECHANISMS
+            .iter()
+            .any(|mechanism| mechanism_allowed(config, advertising.tls, mechanism))
+}
+
+/// Every capability the server implements, in the order they are listed.
+const CAPABILITIES: [Capability; 19] = [
+    Capability {
+        name: "IMAP4rev1",
+        advertised: always,
+    },
+    // STARTTLS is only valid before logging in
+    Capability {
+        name: "STARTTLS",
+        advertised: |_, advertising| {
+            !advertising.tls && !advertising.authenticated && advertising.starttls
+        },
+    },
+    Capability {
+        name: AUTH,
+        advertised: authenticate,
+    },
+    Capability {
+        name: "SASL-IR",
+        advertised: authenticate,
+    },
+    Capability {
+        name: "LOGINDISABLED",
+        advertised: |config, advertising| {
+            !advertising.authenticated && !config.login_allowed(advertising.tls)
+        },
+    },
+    Capability {
+        name: "UTF8=ACCEPT",
+        advertised: always,
+    },
+    Capability {
+        name: "NAMESPACE",
+        advertised: always,
+    },
+    Capability {
+        name: "LIST-EXTENDED",
+        advertised: always,
+    },
+    Capability {
+        name: "ID",
+        advertised: always,
+    },
+    Capability {
+        name: "ENABLE",
+        advertised: always,
+    },
+    Capability {
+        name: "UNSELECT",
+        advertised: always,
+    },
+    Capability {
+        name: "UIDPLUS",
+        advertised: always,
+    },
+    Capability {
+        name: "MOVE",
+        advertised: always,
+    },
+    Capability {
+        name: "IDLE",
+        advertised: always,
+    },
+    Capability {
+        name: "STATUS=SIZE",
+        advertised: always,
+    },
+    Capability {
+        name: "CONDSTORE",
+        advertised: always,
+    },
+    Capability {
+        name: "QRESYNC",
+        advertised: always,
+    },
+    Capability {
+        name: "LITERAL+",
+        advertised: |config, _| !config.literal_minus,
+    },
+    Capability {
+        name: "LITERAL-",
+        advertised: |config, _| config.literal_minus,
+    },
+];
+
+/// The capabilities to advertise, separated by spaces.
+pub(crate) fn capabilities(config: &Config, advertising: Advertising) -> String {
+    CAPABILITIES
+        .iter()
+        .filter(|capability| (capability.advertised)(config, advertising))
+        .flat_map(|capability| match capability.name {
+            AUTH => sasl::MECHANISMS
+                .iter()
+                .filter(|mechanism| mechanism_allowed(config, advertising.tls, mechanism))
+                .map(|mechanism| format!("{}{}", AUTH, mechanism.name))
+                .collect(),
+            name => vec![name.to_string()],
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl Shared {
+    /// The capabilities to advertise to the connection in its current state.
+    pub(crate) fn capabilities(&self, addr: SocketAddr) -> String {
+        let connection = self.peers.get(&addr).expect("unable to find peer");
+        let advertising = Advertising {
+            tls: connection.tls,
+            authenticated: !matches!(connection.state, State::NotAuthenticated),
+            starttls: self.starttls,
+        };
+        capabilities(&self.config, advertising)
+    }
+}
+
+impl Commands {
+    pub async fn capability(
+        identifier: &str,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let one = format!("* CAPABILITY {}\r\n", state.capabilities(addr));
+
+        let response = format!("{}{}", identifier, " OK CAPABILITY completed\r");
+        let complete = [one, response].concat();
+
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {}", complete);
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::expunge::remove_deleted;
+use crate::commands::Commands;
+use crate::{Shared, State};
+
+impl Commands {
+    /// Leaves the selected state and silently expunges the folder unless it was opened read-only.
+    pub async fn close(
+        identifier: &str,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let connection = state.peers.get_mut(&addr).expect("unable to find peer");
+        let selected = match &connection.state {
+            State::Selected(selected) => selected.clone(),
+            _ => return Ok(()),
+        };
+        let mailbox = connection.mailbox.clone().expect("failed to get mailbox");
+        connection.state = State::Authenticated;
+
+        if !selected.read_only {
+            match remove_deleted(&mailbox, &selected.folder, None).await {
+                Ok(_) => state.refresh_idle(&mailbox.user, &selected.folder).await,
+                Err(e) => error!("Unable to expunge {}: {}", selected.folder, e),
+            }
+        }
+
+        let response = format!("{} OK CLOSE completed\r", identifier);
+        state.respond(addr, &response).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} OK CLOSE completed", identifier);
+
+        Ok(())
+    }
+
+    /// Leaves the selected state without expunging (RFC 3691).
+    pub async fn unselect(
+        identifier: &str,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        state
+            .peers
+            .get_mut(&addr)
+            .expect("unable to find peer")
+            .state = State::Authenticated;
+
+        let response = format!("{} OK UNSELECT completed\r", identifier);
+        state.respond(addr, &response).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} OK UNSELECT completed", identifier);
+
+        Ok(())
+    }
+}
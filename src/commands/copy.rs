@@ -0,0 +1,160 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::expunge::expunge_responses;
+use crate::commands::fetch::resolve;
+use crate::commands::Commands;
+use crate::parser::SequenceSet;
+use crate::{Shared, State};
+
+/// Formats UIDs as `uid-set`, merging runs of consecutive values into ranges.
+///
+/// The order is kept so that the sets of COPYUID still match up.
+pub(crate) fn uid_set(uids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for uid in uids {
+        match ranges.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(*uid) => *end = *uid,
+            _ => ranges.push((*uid, *uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}:{}", start, end)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+impl Commands {
+    /// Handles COPY and, with `moving` set, MOVE (RFC 6851).
+    pub async fn copy(
+        identifier: &str,
+        sequence_set: SequenceSet,
+        path: String,
+        uid: bool,
+        moving: bool,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let connection = state.peers.get(&addr).expect("unable to find peer");
+        let selected = match &connection.state {
+            State::Selected(selected) => selected.clone(),
+            _ => return Ok(()),
+        };
+        let mailbox = connection.mailbox.clone().expect("failed to get mailbox");
+        let command = match (uid, moving) {
+            (false, false) => "COPY",
+            (true, false) => "UID COPY",
+            (false, true) => "MOVE",
+            (true, true) => "UID MOVE",
+        };
+
+        if moving && selected.read_only {
+            let response = format!("{} NO Mailbox is read-only\r", identifier);
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} NO Mailbox is read-only", identifier);
+            return Ok(());
+        }
+
+        if !mailbox.folder_exists(&path).await {
+            // Tells the client that it may CREATE the folder and try again
+            let response = format!("{} NO [TRYCREATE] Mailbox does not exist\r", identifier);
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!(
+                "Responded: {} NO [TRYCREATE] Mailbox does not exist",
+                identifier
+            );
+            return Ok(());
+        }
+
+        let sources: Vec<u32> = match resolve(&sequence_set, uid, &selected.uids) {
+            Ok(targets) => targets.into_iter().map(|(_, uid)| uid).collect(),
+            Err(reason) => {
+                let response = format!("{} {}\r", identifier, reason);
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!("Responded: {} {}", identifier, reason);
+                return Ok(());
+            }
+        };
+
+        let copied = match mailbox
+            .copy_messages(&selected.folder, &sources, &path)
+            .await
+        {
+            Ok(copied) => copied,
+            Err(e) => {
+                error!("Unable to copy messages to {}: {}", path, e);
+                let response = format!("{} NO {} failed\r", identifier, command);
+                return state.respond(addr, &response).await;
+            }
+        };
+
+        let code = match mailbox.folder_metadata(&path).await {
+            Ok(metadata) if !copied.is_empty() => format!(
+                "[COPYUID {} {} {}] ",
+                metadata.uid_validity,
+                uid_set(&sources),
+                uid_set(&copied)
+            ),
+            _ => String::new(),
+        };
+        state.refresh_idle(&mailbox.user, &path).await;
+
+        let mut lines: Vec<String> = Vec::new();
+        if moving {
+            let mut removed = Vec::new();
+            for source in &sources {
+                match mailbox.remove_message(&selected.folder, *source).await {
+                    Ok(()) => removed.push(*source),
+                    Err(e) => error!("Unable to remove moved message {}: {}", source, e),
+                }
+            }
+
+            // The COPYUID code has to come before the EXPUNGE responses
+            if !code.is_empty() {
+                lines.push(format!("* OK {}Moved\r\n", code));
+            }
+
+            let qresync = state.extensions(addr).qresync;
+            let mut uids = selected.uids.clone();
+            for line in expunge_responses(&mut uids, &removed, qresync) {
+                lines.push(format!("{}\r\n", line));
+            }
+            if let Some(connection) = state.peers.get_mut(&addr) {
+                if let State::Selected(selected) = &mut connection.state {
+                    selected.uids = uids;
+                }
+            }
+            state.refresh_idle(&mailbox.user, &selected.folder).await;
+
+            lines.push(format!("{} OK {} completed\r", identifier, command));
+        } else {
+            lines.push(format!("{} OK {}{} completed\r", identifier, code, command));
+        }
+
+        let complete = lines.concat();
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} OK {} completed", identifier, command);
+
+        Ok(())
+    }
+}
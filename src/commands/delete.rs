@@ -0,0 +1,55 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::Commands;
+use crate::Shared;
+
+/// The tagged `NO` for a failed folder operation, with a response code from RFC 5530.
+pub(crate) fn folder_error(command: &str, e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::NotFound => "NO [NONEXISTENT] Mailbox does not exist".to_string(),
+        io::ErrorKind::AlreadyExists => "NO [ALREADYEXISTS] Mailbox already exists".to_string(),
+        io::ErrorKind::PermissionDenied => format!("NO [CANNOT] {}", e),
+        io::ErrorKind::InvalidInput => "NO [CANNOT] Invalid mailbox name".to_string(),
+        _ => {
+            error!("{} failed: {}", command, e);
+            format!("NO {} failed", command)
+        }
+    }
+}
+
+impl Commands {
+    pub async fn delete(
+        identifier: &str,
+        path: String,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let mailbox = state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .clone()
+            .expect("failed to get mailbox");
+
+        let response = match mailbox.delete_folder(&path).await {
+            Ok(()) => "OK DELETE completed".to_string(),
+            Err(e) => folder_error("DELETE", &e),
+        };
+
+        let complete = format!("{} {}\r", identifier, response);
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} {}", identifier, response);
+
+        Ok(())
+    }
+}
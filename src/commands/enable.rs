@@ -0,0 +1,82 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::Commands;
+use crate::{Extensions, Shared};
+
+impl Shared {
+    /// The extensions the connection has enabled.
+    pub(crate) fn extensions(&self, addr: SocketAddr) -> Extensions {
+        self.peers
+            .get(&addr)
+            .map(|connection| connection.enabled)
+            .unwrap_or_default()
+    }
+
+    /// Turns on CONDSTORE, as done by the first command that uses mod-sequences.
+    pub(crate) fn enable_condstore(&mut self, addr: SocketAddr) {
+        if let Some(connection) = self.peers.get_mut(&addr) {
+            connection.enabled.condstore = true;
+        }
+    }
+}
+
+impl Commands {
+    /// Enables extensions for the rest of the connection (RFC 5161).
+    ///
+    /// Unknown capabilities and ones that are not advertised are ignored.
+    /// Only the ones that were not enabled before are listed in the ENABLED
+    /// response.
+    pub async fn enable(
+        identifier: &str,
+        capabilities: Vec<String>,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let advertised = state.capabilities(addr);
+        let connection = state.peers.get_mut(&addr).expect("unable to find peer");
+        let enabled = &mut connection.enabled;
+
+        let mut newly_enabled: Vec<&str> = Vec::new();
+        for capability in &capabilities {
+            if !advertised
+                .split(' ')
+                .any(|name| name.eq_ignore_ascii_case(capability))
+            {
+                continue;
+            }
+            match capability.to_uppercase().as_str() {
+                "CONDSTORE" if !enabled.condstore => {
+                    enabled.condstore = true;
+                    newly_enabled.push("CONDSTORE");
+                }
+                "QRESYNC" if !enabled.qresync => {
+                    enabled.qresync = true;
+                    enabled.condstore = true;
+                    newly_enabled.push("QRESYNC");
+                }
+                "UTF8=ACCEPT" if !enabled.utf8_accept => {
+                    enabled.utf8_accept = true;
+                    newly_enabled.push("UTF8=ACCEPT");
+                }
+                _ => {}
+            }
+        }
+
+        let data: String = newly_enabled
+            .iter()
+            .map(|capability| format!(" {}", capability))
+            .collect();
+        let complete = format!("* ENABLED{}\r\n{} OK ENABLE completed\r", data, identifier);
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {}", complete);
+        Ok(())
+    }
+}
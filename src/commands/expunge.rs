@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::mailbox::Mailbox;
+
+use crate::commands::copy::uid_set;
+use crate::commands::Commands;
+use crate::parser::SequenceSet;
+use crate::{Shared, State};
+
+/// Removes the messages of `folder` that have the `\Deleted` flag.
+///
+/// With `only` set just those UIDs are considered. Returns the removed UIDs.
+pub(crate) async fn remove_deleted(
+    mailbox: &Mailbox,
+    folder: &str,
+    only: Option<&[u32]>,
+) -> Result<Vec<u32>, std::io::Error> {
+    let messages = mailbox.list_messages(folder).await?;
+
+    let mut removed = Vec::new();
+    for message in messages {
+        if !message.flags.iter().any(|flag| flag == "\\Deleted") {
+            continue;
+        }
+        if only.is_some_and(|only| !only.contains(&message.uid)) {
+            continue;
+        }
+        match mailbox.remove_message(folder, message.uid).await {
+            Ok(()) => removed.push(message.uid),
+            Err(e) => error!("Unable to expunge message {}: {}", message.uid, e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Drops `removed` from `uids` and returns the matching `* n EXPUNGE` responses.
+///
+/// Each sequence number already accounts for the messages expunged before it.
+/// With QRESYNC enabled a single `* VANISHED` response lists the UIDs instead.
+pub(crate) fn expunge_responses(
+    uids: &mut Vec<u32>,
+    removed: &[u32],
+    qresync: bool,
+) -> Vec<String> {
+    let mut responses = Vec::new();
+    let mut vanished = Vec::new();
+    for uid in removed {
+        if let Some(position) = uids.iter().position(|known| known == uid) {
+            uids.remove(position);
+            responses.push(format!("* {} EXPUNGE", position + 1));
+            vanished.push(*uid);
+        }
+    }
+    if qresync && !vanished.is_empty() {
+        vanished.sort_unstable();
+        return vec![format!("* VANISHED {}", uid_set(&vanished))];
+    }
+    responses
+}
+
+impl Commands {
+    pub async fn expunge(
+        identifier: &str,
+        uids: Option<SequenceSet>,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let connection = state.peers.get(&addr).expect("unable to find peer");
+        let selected = match &connection.state {
+            State::Selected(selected) => selected.clone(),
+            _ => return Ok(()),
+        };
+        let mailbox = connection.mailbox.clone().expect("failed to get mailbox");
+        let command = if uids.is_some() {
+            "UID EXPUNGE"
+        } else {
+            "EXPUNGE"
+        };
+
+        if selected.read_only {
+            let response = format!("{} NO Mailbox is read-only\r", identifier);
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} NO Mailbox is read-only", identifier);
+            return Ok(());
+        }
+
+        // UID EXPUNGE only touches the given messages
+        let only: Option<Vec<u32>> = uids.map(|uids| {
+            let largest = selected.uids.last().copied().unwrap_or(0);
+            selected
+                .uids
+                .iter()
+                .copied()
+                .filter(|uid| uids.contains(*uid, largest))
+                .collect()
+        });
+
+        let removed = match remove_deleted(&mailbox, &selected.folder, only.as_deref()).await {
+            Ok(removed) => removed,
+            Err(e) => {
+                error!("Unable to expunge {}: {}", selected.folder, e);
+                let response = format!("{} NO {} failed\r", identifier, command);
+                return state.respond(addr, &response).await;
+            }
+        };
+
+        let qresync = state.extensions(addr).qresync;
+        let mut uids = selected.uids.clone();
+        let mut lines: Vec<String> = expunge_responses(&mut uids, &removed, qresync)
+            .into_iter()
+            .map(|line| format!("{}\r\n", line))
+            .collect();
+
+        if let Some(connection) = state.peers.get_mut(&addr) {
+            if let State::Selected(selected) = &mut connection.state {
+                selected.uids = uids;
+            }
+        }
+        state.refresh_idle(&mailbox.user, &selected.folder).await;
+
+        let response = format!("{} OK {} completed\r", identifier, command);
+        lines.push(response);
+
+        let complete = lines.concat();
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} OK {} completed", identifier, command);
+
+        Ok(())
+    }
+}
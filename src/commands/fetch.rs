@@ -0,0 +1,381 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Local};
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::mailbox::MessageInfo;
+
+use crate::commands::copy::uid_set;
+use crate::commands::Commands;
+use crate::message::{section_name, Part};
+use crate::parser::{FetchAttribute, FetchModifiers, SequenceSet};
+use crate::{Shared, State};
+
+/// Formats `data` as a fetch item with a literal, keeping the bytes as they are.
+pub(crate) fn literal_item(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut item = format!("{} {{{}}}\r\n", name, data.len()).into_bytes();
+    item.extend_from_slice(data);
+    item
+}
+
+/// The flags of a message as parenthesized list.
+///
+/// `recent` tells if the session reports the message as \Recent.
+pub(crate) fn flag_list(message: &MessageInfo, recent: bool) -> String {
+    let mut flags = message.flags.clone();
+    if recent {
+        flags.push("\\Recent".to_string());
+    }
+    format!("({})", flags.join(" "))
+}
+
+/// The FLAGS item of a FETCH response, followed by MODSEQ once CONDSTORE is enabled.
+pub(crate) fn flags_item(message: &MessageInfo, recent: bool, condstore: bool) -> String {
+    if condstore {
+        format!(
+            "FLAGS {} MODSEQ ({})",
+            flag_list(message, recent),
+            message.modseq
+        )
+    } else {
+        format!("FLAGS {}", flag_list(message, recent))
+    }
+}
+
+/// The UIDs of `uid_set` that were assigned before `uid_next` but are gone.
+///
+/// Expunged UIDs are not remembered, so this includes UIDs which never
+/// existed, as RFC 7162 section 3.2.10 permits.
+pub(crate) fn vanished(uid_set: &SequenceSet, uid_next: u32, messages: &[MessageInfo]) -> Vec<u32> {
+    let largest = uid_next.saturating_sub(1);
+    (1..uid_next)
+        .filter(|uid| uid_set.contains(*uid, largest))
+        .filter(|uid| !messages.iter().any(|message| message.uid == *uid))
+        .collect()
+}
+
+/// The `date-time` format used for INTERNALDATE.
+pub(crate) fn internal_date(message: &MessageInfo) -> String {
+    let date: DateTime<Local> = message.internal_date.into();
+    format!("\"{}\"", date.format("%d-%b-%Y %H:%M:%S %z"))
+}
+
+/// Resolves a sequence set to the sequence numbers and UIDs of the selected messages.
+///
+/// Sequence numbers that don't exist are an error, unknown UIDs are ignored.
+pub(crate) fn resolve(
+    sequence_set: &SequenceSet,
+    uid: bool,
+    uids: &[u32],
+) -> Result<Vec<(usize, u32)>, &'static str> {
+    if uid {
+        let largest = uids.last().copied().unwrap_or(0);
+        return Ok(uids
+            .iter()
+            .enumerate()
+            .filter(|(_, uid)| sequence_set.contains(**uid, largest))
+            .map(|(index, uid)| (index + 1, *uid))
+            .collect());
+    }
+
+    let count = uids.len() as u32;
+    if sequence_set.highest_value().unwrap_or(0) > count {
+        return Err("BAD Invalid sequence number");
+    }
+    Ok(uids
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| sequence_set.contains(*index as u32 + 1, count))
+        .map(|(index, uid)| (index + 1, *uid))
+        .collect())
+}
+
+impl Commands {
+    /// Fetches message data.
+    ///
+    /// With CHANGEDSINCE only messages with a higher mod-sequence are
+    /// returned, VANISHED additionally reports the expunged UIDs (RFC 7162).
+    pub async fn fetch(
+        identifier: &str,
+        sequence_set: SequenceSet,
+        mut attributes: Vec<FetchAttribute>,
+        modifiers: FetchModifiers,
+        uid: bool,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let connection = state.peers.get(&addr).expect("unable to find peer");
+        let selected = match &connection.state {
+            State::Selected(selected) => selected.clone(),
+            _ => return Ok(()),
+        };
+        let mailbox = connection.mailbox.clone().expect("failed to get mailbox");
+        let command = if uid { "UID FETCH" } else { "FETCH" };
+
+        let targets = match resolve(&sequence_set, uid, &selected.uids) {
+            Ok(targets) => targets,
+            Err(reason) => {
+                let response = format!("{} {}\r", identifier, reason);
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!("Responded: {} {}", identifier, reason);
+                return Ok(());
+            }
+        };
+
+        if modifiers.vanished && !state.extensions(addr).qresync {
+            let response = format!("{} BAD QRESYNC is not enabled\r", identifier);
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} BAD QRESYNC is not enabled", identifier);
+            return Ok(());
+        }
+
+        // UID FETCH always returns the UID
+        if uid && !attributes.contains(&FetchAttribute::Uid) {
+            attributes.insert(0, FetchAttribute::Uid);
+        }
+        // CHANGEDSINCE implies fetching the mod-sequence
+        if modifiers.changed_since.is_some() && !attributes.contains(&FetchAttribute::ModSeq) {
+            attributes.push(FetchAttribute::ModSeq);
+        }
+        if attributes.contains(&FetchAttribute::ModSeq) {
+            state.enable_condstore(addr);
+        }
+        let enabled = state.extensions(addr);
+
+        let needs_content = attributes.iter().any(|attribute| {
+            !matches!(
+                attribute,
+                FetchAttribute::Uid
+                    | FetchAttribute::Flags
+                    | FetchAttribute::InternalDate
+                    | FetchAttribute::Rfc822Size
+                    | FetchAttribute::ModSeq
+            )
+        });
+        let sets_seen = !selected.read_only
+            && attributes.iter().any(|attribute| {
+                matches!(
+                    attribute,
+                    FetchAttribute::BodySection { peek: false, .. }
+                        | FetchAttribute::Rfc822
+                        | FetchAttribute::Rfc822Text
+                )
+            });
+
+        let mut messages = match mailbox.list_messages(&selected.folder).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Unable to list messages of {}: {}", selected.folder, e);
+                let response = format!("{} NO {} failed\r", identifier, command);
+                return state.respond(addr, &response).await;
+            }
+        };
+
+        let targets: Vec<(usize, u32)> = targets
+            .into_iter()
+            .filter(|(_, message_uid)| {
+                messages.iter().any(|message| {
+                    message.uid == *message_uid
+                        && modifiers
+                            .changed_since
+                            .is_none_or(|changed_since| message.modseq > changed_since)
+                })
+            })
+            .collect();
+
+        // Setting \Seen changes the mod-sequence, so it happens before the
+        // messages are listed for the responses
+        let mut seen_set: Vec<u32> = Vec::new();
+        if sets_seen {
+            for (_, message_uid) in &targets {
+                let message = match messages.iter().find(|message| message.uid == *message_uid) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if message.flags.iter().any(|flag| flag == "\\Seen") {
+                    continue;
+                }
+                let mut flags = message.flags.clone();
+                flags.push("\\Seen".to_string());
+                match mailbox
+                    .set_flags(&selected.folder, *message_uid, &flags)
+                    .await
+                {
+                    Ok(()) => {
+                        seen_set.push(*message_uid);
+                        state.remember_flags(addr, *message_uid, &flags);
+                    }
+                    Err(e) => error!("Unable to set \\Seen on {}: {}", message_uid, e),
+                }
+            }
+            if !seen_set.is_empty() {
+                messages = match mailbox.list_messages(&selected.folder).await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        error!("Unable to list messages of {}: {}", selected.folder, e);
+                        let response = format!("{} NO {} failed\r", identifier, command);
+                        return state.respond(addr, &response).await;
+                    }
+                };
+            }
+        }
+
+        let mut lines: Vec<Vec<u8>> = Vec::new();
+        if modifiers.vanished {
+            match mailbox.folder_metadata(&selected.folder).await {
+                Ok(metadata) => {
+                    let gone = vanished(&sequence_set, metadata.uid_next, &messages);
+                    if !gone.is_empty() {
+                        lines.push(
+                            format!("* VANISHED (EARLIER) {}\r\n", uid_set(&gone)).into_bytes(),
+                        );
+                    }
+                }
+                Err(e) => error!("Unable to read metadata of {}: {}", selected.folder, e),
+            }
+        }
+
+        // The messages are looked up together instead of once per message
+        let mut contents = if needs_content {
+            let uids: Vec<u32> = targets
+                .iter()
+                .map(|(_, message_uid)| *message_uid)
+                .collect();
+            match mailbox.read_messages(&selected.folder, &uids).await {
+                Ok(contents) => contents,
+                Err(e) => {
+                    error!("Unable to read messages of {}: {}", selected.folder, e);
+                    let response = format!("{} NO {} failed\r", identifier, command);
+                    return state.respond(addr, &response).await;
+                }
+            }
+        } else {
+            Vec::new()
+        }
+        .into_iter();
+
+        for (sequence_number, message_uid) in targets {
+            let content = if needs_content {
+                match contents.next() {
+                    Some(Ok(content)) => content,
+                    Some(Err(e)) => {
+                        error!("Unable to read message {}: {}", message_uid, e);
+                        continue;
+                    }
+                    None => continue,
+                }
+            } else {
+                Vec::new()
+            };
+
+            let message = match messages.iter().find(|message| message.uid == message_uid) {
+                Some(message) => message,
+                None => continue,
+            };
+            let part = if needs_content {
+                Some(Part::parse(&content))
+            } else {
+                None
+            };
+            let recent = selected.recent.contains(&message_uid);
+
+            let mut items: Vec<Vec<u8>> = Vec::new();
+            for attribute in &attributes {
+                let item = match (attribute, &part) {
+                    (FetchAttribute::Uid, _) => format!("UID {}", message.uid),
+                    (FetchAttribute::Flags, _) => format!("FLAGS {}", flag_list(message, recent)),
+                    (FetchAttribute::InternalDate, _) => {
+                        format!("INTERNALDATE {}", internal_date(message))
+                    }
+                    (FetchAttribute::Rfc822Size, _) => format!("RFC822.SIZE {}", message.size),
+                    (FetchAttribute::ModSeq, _) => format!("MODSEQ ({})", message.modseq),
+                    (FetchAttribute::Envelope, Some(part)) => {
+                        format!("ENVELOPE {}", part.envelope(enabled.utf8_accept))
+                    }
+                    (FetchAttribute::Body, Some(part)) => {
+                        format!("BODY {}", part.body_structure(false, enabled.utf8_accept))
+                    }
+                    (FetchAttribute::BodyStructure, Some(part)) => {
+                        format!(
+                            "BODYSTRUCTURE {}",
+                            part.body_structure(true, enabled.utf8_accept)
+                        )
+                    }
+                    (FetchAttribute::Rfc822, _) => {
+                        items.push(literal_item("RFC822", &content));
+                        continue;
+                    }
+                    (FetchAttribute::Rfc822Header, Some(part)) => {
+                        items.push(literal_item("RFC822.HEADER", part.header));
+                        continue;
+                    }
+                    (FetchAttribute::Rfc822Text, Some(part)) => {
+                        items.push(literal_item("RFC822.TEXT", part.body));
+                        continue;
+                    }
+                    (
+                        FetchAttribute::BodySection {
+                            section, partial, ..
+                        },
+                        Some(part),
+                    ) => {
+                        let data = part.section(section);
+                        items.push(match partial {
+                            Some((start, length)) => {
+                                let start = (*start as usize).min(data.len());
+                                let end = start.saturating_add(*length as usize).min(data.len());
+                                literal_item(
+                                    &format!("BODY[{}]<{}>", section_name(section), start),
+                                    &data[start..end],
+                                )
+                            }
+                            None => {
+                                literal_item(&format!("BODY[{}]", section_name(section)), &data)
+                            }
+                        });
+                        continue;
+                    }
+                    // Every other attribute makes `needs_content` true
+                    (_, None) => continue,
+                };
+                items.push(item.into_bytes());
+            }
+
+            // Clients have to learn about the implicitly set flag
+            if seen_set.contains(&message_uid) && !attributes.contains(&FetchAttribute::Flags) {
+                items.push(
+                    flags_item(
+                        message,
+                        recent,
+                        enabled.condstore && !attributes.contains(&FetchAttribute::ModSeq),
+                    )
+                    .into_bytes(),
+                );
+            }
+
+            let mut line = format!("* {} FETCH (", sequence_number).into_bytes();
+            line.extend_from_slice(&items.join(&b' '));
+            line.extend_from_slice(b")\r\n");
+            lines.push(line);
+        }
+
+        let response = format!("{} OK {} completed\r", identifier, command);
+        lines.push(response.into_bytes());
+
+        let complete = lines.concat();
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} OK {} completed", identifier, command);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::mailbox::MessageInfo;
+
+use crate::commands::expunge::expunge_responses;
+use crate::commands::fetch::flags_item;
+use crate::commands::Commands;
+use crate::{Extensions, Selected, Shared, State};
+
+/// Brings `selected` up to date with the messages currently in the folder.
+///
+/// Returns the untagged responses telling the client about removed messages,
+/// changed flags and new messages, in that order. New messages which are
+/// still recent become \Recent for this session. `enabled` decides between
+/// EXPUNGE and VANISHED and if mod-sequences are included.
+pub(crate) fn changes(
+    selected: &mut Selected,
+    messages: &[MessageInfo],
+    enabled: Extensions,
+) -> Vec<String> {
+    let removed: Vec<u32> = selected
+        .uids
+        .iter()
+        .filter(|uid| !messages.iter().any(|message| message.uid == **uid))
+        .copied()
+        .collect();
+    let mut lines = expunge_responses(&mut selected.uids, &removed, enabled.qresync);
+
+    let mut added = 0;
+    for message in messages {
+        match selected.uids.iter().position(|uid| *uid == message.uid) {
+            Some(position) => {
+                if selected.flags.get(&message.uid) != Some(&message.flags) {
+                    let recent = selected.recent.contains(&message.uid);
+                    lines.push(format!(
+                        "* {} FETCH ({})",
+                        position + 1,
+                        flags_item(message, recent, enabled.condstore)
+                    ));
+                }
+            }
+            None => {
+                selected.uids.push(message.uid);
+                if message.recent {
+                    selected.recent.push(message.uid);
+                }
+                added += 1;
+            }
+        }
+        selected.flags.insert(message.uid, message.flags.clone());
+    }
+
+    if added > 0 {
+        lines.push(format!("* {} EXISTS", selected.uids.len()));
+        let recent = selected
+            .uids
+            .iter()
+            .filter(|uid| selected.recent.contains(uid))
+            .count();
+        lines.push(format!("* {} RECENT", recent));
+    }
+    lines
+}
+
+impl Shared {
+    /// Sends the changes of the selected folder to the connection.
+    pub(crate) async fn synchronize(&mut self, addr: SocketAddr) {
+        let connection = match self.peers.get_mut(&addr) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let mailbox = match &connection.mailbox {
+            Some(mailbox) => mailbox.clone(),
+            None => return,
+        };
+        let enabled = connection.enabled;
+        let selected = match &mut connection.state {
+            State::Selected(selected) => selected,
+            _ => return,
+        };
+
+        let messages = match mailbox.list_messages(&selected.folder).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Unable to list messages of {}: {}", selected.folder, e);
+                return;
+            }
+        };
+
+        let claimed = selected.recent.len();
+        let lines = changes(selected, &messages, enabled);
+        if !selected.read_only {
+            // Storing the flags moves the message out of `new`
+            for uid in &selected.recent[claimed..] {
+                if let Err(e) = mailbox
+                    .set_flags(&selected.folder, *uid, &selected.flags[uid])
+                    .await
+                {
+                    error!("Unable to clear \\Recent of {}: {}", uid, e);
+                }
+            }
+        }
+
+        if !lines.is_empty()
+            && connection
+                .tx
+                .send(format!("{}\r", lines.join("\r\n")).into_bytes())
+                .is_err()
+        {
+            debug!("{} is gone, dropping notification", addr);
+        }
+    }
+
+    /// Synchronizes every idling connection of `user` which has `folder` selected.
+    pub(crate) async fn refresh_idle(&mut self, user: &str, folder: &str) {
+        let idling: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, connection)| {
+                connection.idle.is_some()
+                    && connection
+                        .mailbox
+                        .as_ref()
+                        .is_some_and(|mailbox| mailbox.user == user)
+                    && matches!(&connection.state, State::Selected(selected) if selected.folder == folder)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in idling {
+            self.synchronize(addr).await;
+        }
+    }
+
+    /// Records flags the connection was told about, so they are not reported again.
+    pub(crate) fn remember_flags(&mut self, addr: SocketAddr, uid: u32, flags: &[String]) {
+        if let Some(connection) = self.peers.get_mut(&addr) {
+            if let State::Selected(selected) = &mut connection.state {
+                selected.flags.insert(uid, flags.to_vec());
+            }
+        }
+    }
+}
+
+impl Commands {
+    /// Starts IDLE (RFC 2177). Changes of the selected folder are pushed until DONE.
+    pub async fn idle(
+        identifier: &str,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let connection = state.peers.get_mut(&addr).expect("unable to find peer");
+        connection.idle = Some(identifier.to_string());
+
+        state.respond(addr, "+ idling\r").await?;
+
+        //Print to view for debug
+        debug!("Responded: + idling");
+
+        // Report what changed before IDLE started
+        state.synchronize(addr).await;
+
+        Ok(())
+    }
+
+    /// Ends a running IDLE command with `line`, which should be DONE.
+    ///
+    /// Returns false if the connection is not idling and `line` is a regular command.
+    pub async fn done(
+        line: &str,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<bool, mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let tag = match state
+            .peers
+            .get_mut(&addr)
+            .and_then(|connection| connection.idle.take())
+        {
+            Some(tag) => tag,
+            None => return Ok(false),
+        };
+
+        let response = if line.trim().eq_ignore_ascii_case("DONE") {
+            "OK IDLE terminated"
+        } else {
+            "BAD Expected DONE"
+        };
+        let complete = format!("{} {}\r", tag, response);
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} {}", tag, response);
+
+        Ok(true)
+    }
+}
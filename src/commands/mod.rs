@@ -5,45 +5,325 @@ use std::sync::Arc;
 use log::debug;
 use tokio::sync::{mpsc, Mutex};
 
+use IMAPServer_shared::mailbox::{safe_folder_name, DELIMITER};
+
+use crate::parser::{Command, IdParameters, Request};
 use crate::{Shared, State};
 
+mod append;
 pub mod authenticate;
+pub mod capability;
+mod close;
+pub mod copy;
+mod delete;
+mod enable;
+pub mod expunge;
+pub mod fetch;
+pub mod idle;
+mod rename;
+mod search;
+pub mod select;
+mod starttls;
+mod store;
+mod subscribe;
 
 pub(crate) struct Commands;
 
+/// Formats `value` as a quoted string for use in responses.
+pub(crate) fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The status data items STATUS understands. SIZE is from RFC 8438.
+const STATUS_ITEMS: [&str; 7] = [
+    "MESSAGES",
+    "RECENT",
+    "UIDNEXT",
+    "UIDVALIDITY",
+    "UNSEEN",
+    "SIZE",
+    "HIGHESTMODSEQ",
+];
+
+/// Checks if `command` may be used in the connection `state`.
+///
+/// Returns the response to send if it may not.
+pub(crate) fn validate_state(command: &Command, state: &State) -> Result<(), &'static str> {
+    let authenticated = match state {
+        State::NotAuthenticated => false,
+        State::Authenticated | State::Selected(_) => true,
+        State::Logout => return Err("BAD Connection is logging out"),
+    };
+    let selected = matches!(state, State::Selected(_));
+
+    match command {
+        Command::Capability | Command::Noop | Command::Logout | Command::Id { .. } => Ok(()),
+        Command::Authenticate { .. } | Command::Login { .. } | Command::StartTls => {
+            if authenticated {
+                Err("BAD Already authenticated")
+            } else {
+                Ok(())
+            }
+        }
+        Command::Enable { .. } => {
+            if !authenticated {
+                Err("NO Please Login first!")
+            } else if selected {
+                Err("BAD ENABLE is not allowed in the selected state")
+            } else {
+                Ok(())
+            }
+        }
+        Command::Select { .. }
+        | Command::Examine { .. }
+        | Command::Create { .. }
+        | Command::Delete { .. }
+        | Command::Rename { .. }
+        | Command::Subscribe { .. }
+        | Command::Unsubscribe { .. }
+        | Command::List { .. }
+        | Command::Lsub { .. }
+        | Command::Status { .. }
+        | Command::Namespace
+        | Command::Append { .. }
+        | Command::Idle => {
+            if authenticated {
+                Ok(())
+            } else {
+                Err("NO Please Login first!")
+            }
+        }
+        Command::Fetch { .. }
+        | Command::Store { .. }
+        | Command::Search { .. }
+        | Command::Expunge { .. }
+        | Command::Copy { .. }
+        | Command::Move { .. }
+        | Command::Close
+        | Command::Unselect => {
+            if selected {
+                Ok(())
+            } else if authenticated {
+                Err("BAD No mailbox selected")
+            } else {
+                Err("NO Please Login first!")
+            }
+        }
+    }
+}
+
+/// Checks if changes made by other sessions may be reported before `command` runs.
+///
+/// EXPUNGE responses would shift the sequence numbers a command refers to
+/// (RFC 3501 section 7.4.1), and there is no point once the folder is left.
+pub(crate) fn reports_changes(command: &Command) -> bool {
+    match command {
+        Command::Fetch { uid, .. }
+        | Command::Store { uid, .. }
+        | Command::Search { uid, .. }
+        | Command::Copy { uid, .. }
+        | Command::Move { uid, .. } => *uid,
+        Command::Select { .. }
+        | Command::Examine { .. }
+        | Command::Close
+        | Command::Unselect
+        | Command::Logout => false,
+        _ => true,
+    }
+}
+
+/// Checks the mailbox names of `command` before any folder is touched.
+///
+/// Returns the response to send if one of them can't be used.
+pub(crate) fn validate_mailbox_names(command: &Command, utf8: bool) -> Result<(), &'static str> {
+    let names = command.mailbox_names();
+
+    // The empty reference and pattern of LIST and LSUB are not folder names
+    let patterns = matches!(command, Command::List { .. } | Command::Lsub { .. });
+
+    // A name like `/../other@host` would reach into the mailbox of another user
+    if names
+        .iter()
+        .filter(|name| !patterns || !name.is_empty())
+        .any(|name| !safe_folder_name(name))
+    {
+        return Err("NO [CANNOT] Invalid mailbox name");
+    }
+
+    // Names with UTF-8 are only exchanged after ENABLE UTF8=ACCEPT (RFC 6855)
+    if !utf8 && names.iter().any(|name| !name.is_ascii()) {
+        return Err("NO [CANNOT] Non-ASCII mailbox names need ENABLE UTF8=ACCEPT");
+    }
+    Ok(())
+}
+
 impl Commands {
-    pub async fn capability(
-        args: Vec<&str>,
+    /// Hands a parsed request to the matching command handler.
+    ///
+    /// Commands that are not allowed in the current connection state get rejected here.
+    pub async fn dispatch(
+        request: Request,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
+        let identifier = request.tag.as_str();
+        debug!("{} {} by {}", identifier, request.command.name(), addr);
+
+        {
+            let mut state = state.lock().await;
+            let connection_state = match state.peers.get(&addr) {
+                Some(connection) => &connection.state,
+                None => return Ok(()),
+            };
+
+            if let Err(reason) = validate_state(&request.command, connection_state) {
+                let response = format!("{} {}\r", identifier, reason);
+                state.respond(addr, &response).await?;
 
-        let mut state = state.lock().await;
+                //Print to view for debug
+                debug!("Responded: {} {}", identifier, reason);
+                return Ok(());
+            }
 
-        let one = "* CAPABILITY IMAP4rev1 AUTH=PLAIN UTF8=ONLY NAMESPACE LIST-EXTENDED ID ENABLE LOGINDISABLED\r\n";
+            let utf8 = state.extensions(addr).utf8_accept;
+            if let Err(reason) = validate_mailbox_names(&request.command, utf8) {
+                let response = format!("{} {}\r", identifier, reason);
+                state.respond(addr, &response).await?;
 
-        let response = format!("{}{}", identifier, " OK CAPABILITY completed\r");
-        let complete = [one, &response].concat();
+                //Print to view for debug
+                debug!("Responded: {} {}", identifier, reason);
+                return Ok(());
+            }
 
-        state.respond(addr, &complete).await?;
+            // Changes of other sessions are only reported while a command is in progress
+            if reports_changes(&request.command) {
+                state.synchronize(addr).await;
+            }
+        }
 
-        //Print to view for debug
-        debug!(
-            "Responded: {}",
-            "* CAPABILITY IMAP4rev1 AUTH=PLAIN UTF8=ACCEPT LOGINDISABLED"
-        );
-        debug!("Responded: {}{}", identifier, " OK CAPABILITY completed");
-        Ok(())
+        match request.command {
+            Command::Capability => Commands::capability(identifier, addr, state).await,
+            Command::Logout => Commands::logout(identifier, addr, state).await,
+            Command::Noop => Commands::noop(identifier, addr, state).await,
+            Command::Idle => Commands::idle(identifier, addr, state).await,
+            Command::StartTls => Commands::starttls(identifier, addr, state).await,
+            Command::Namespace => Commands::namespace(identifier, addr, state).await,
+            Command::Select {
+                mailbox,
+                parameters,
+            } => Commands::select(identifier, mailbox, parameters, false, addr, state).await,
+            Command::Examine {
+                mailbox,
+                parameters,
+            } => Commands::select(identifier, mailbox, parameters, true, addr, state).await,
+            Command::Create { mailbox } => Commands::create(identifier, mailbox, addr, state).await,
+            Command::Delete { mailbox } => Commands::delete(identifier, mailbox, addr, state).await,
+            Command::Rename { from, to } => {
+                Commands::rename(identifier, from, to, addr, state).await
+            }
+            Command::Subscribe { mailbox } => {
+                Commands::subscribe(identifier, mailbox, addr, state).await
+            }
+            Command::Unsubscribe { mailbox } => {
+                Commands::unsubscribe(identifier, mailbox, addr, state).await
+            }
+            Command::List {
+                selection,
+                reference,
+                patterns,
+                return_options,
+            } => {
+                Commands::list(
+                    identifier,
+                    selection,
+                    reference,
+                    patterns,
+                    return_options,
+                    addr,
+                    state,
+                )
+                .await
+            }
+            Command::Lsub { reference, pattern } => {
+                Commands::lsub(identifier, reference, pattern, addr, state).await
+            }
+            Command::Status { mailbox, items } => {
+                Commands::status(identifier, mailbox, items, addr, state).await
+            }
+            Command::Id { parameters } => Commands::id(identifier, parameters, addr, state).await,
+            Command::Enable { capabilities } => {
+                Commands::enable(identifier, capabilities, addr, state).await
+            }
+            Command::Append {
+                mailbox,
+                flags,
+                date,
+                message,
+            } => Commands::append(identifier, mailbox, flags, date, message, addr, state).await,
+            Command::Fetch {
+                sequence_set,
+                attributes,
+                modifiers,
+                uid,
+            } => {
+                Commands::fetch(
+                    identifier,
+                    sequence_set,
+                    attributes,
+                    modifiers,
+                    uid,
+                    addr,
+                    state,
+                )
+                .await
+            }
+            Command::Store {
+                sequence_set,
+                action,
+                uid,
+            } => Commands::store(identifier, sequence_set, action, uid, addr, state).await,
+            Command::Search {
+                charset,
+                criteria,
+                uid,
+            } => Commands::search(identifier, charset, criteria, uid, addr, state).await,
+            Command::Expunge { uids } => Commands::expunge(identifier, uids, addr, state).await,
+            Command::Copy {
+                sequence_set,
+                mailbox,
+                uid,
+            } => Commands::copy(identifier, sequence_set, mailbox, uid, false, addr, state).await,
+            Command::Move {
+                sequence_set,
+                mailbox,
+                uid,
+            } => Commands::copy(identifier, sequence_set, mailbox, uid, true, addr, state).await,
+            Command::Close => Commands::close(identifier, addr, state).await,
+            Command::Unselect => Commands::unselect(identifier, addr, state).await,
+            Command::Login { user, password } => {
+                authenticate::Authentication::login(identifier, user, password, addr, state).await
+            }
+            Command::Authenticate {
+                mechanism,
+                initial_response,
+            } => {
+                authenticate::Authentication::authenticate(
+                    identifier,
+                    mechanism,
+                    initial_response,
+                    addr,
+                    state,
+                )
+                .await
+            }
+        }
     }
 
     pub async fn logout(
-        args: Vec<&str>,
+        identifier: &str,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-
         let mut state = state.lock().await;
 
         let one = "* BYE IMAP4rev1 Server logging out\r\n";
@@ -54,7 +334,11 @@ impl Commands {
 
         state.respond(addr, &complete).await?;
 
-        state.peers.remove(&addr);
+        state
+            .peers
+            .get_mut(&addr)
+            .expect("unable to find peer")
+            .state = State::Logout;
 
         //Print to view for debug
         debug!("Responded: {}", "* BYE IMAP4rev1 Server logging out\r");
@@ -63,14 +347,13 @@ impl Commands {
     }
 
     pub async fn noop(
-        args: Vec<&str>,
+        identifier: &str,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-
         let mut state = state.lock().await;
 
+        // What changed in the selected folder was already reported by `dispatch`
         let response = format!("{} {}", identifier, "OK NOOP completed\r");
 
         state.respond(addr, &response).await?;
@@ -80,338 +363,287 @@ impl Commands {
         Ok(())
     }
 
-    // TODO actually implement
-    pub async fn enable(
-        args: Vec<&str>,
-        addr: SocketAddr,
-        state: Arc<Mutex<Shared>>,
-    ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-
-        let mut state = state.lock().await;
-
-        let response = format!("{} {}", identifier, "OK enabled\r");
-
-        state.respond(addr, &response).await?;
-
-        //Print to view for debug
-        debug!("Responded: {} {}", identifier, "OK enabled");
-        Ok(())
-    }
-
     pub async fn list(
-        args: Vec<&str>,
+        identifier: &str,
+        selection: Vec<String>,
+        reference: String,
+        patterns: Vec<String>,
+        return_options: Vec<String>,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-
         let mut state = state.lock().await;
 
-        match state.peers.get(&addr).expect("unable to find peer").state {
-            State::LoggedIn => {
-                let mailbox = &state
-                    .peers
-                    .get(&addr)
-                    .expect("unable to find peer")
-                    .mailbox
-                    .borrow()
-                    .as_ref()
-                    .expect("failed to get mailbox");
-
-                let mut folders: Vec<String> =
-                    mailbox.get_list(args).await.expect("unable to get folders");
-
-                let response = format!("{} {}", identifier, "OK LIST completed\r");
-                folders.push(response);
-
-                let complete = folders.concat();
-                debug!("Responded: {}", complete);
+        let utf8 = state.extensions(addr).utf8_accept;
+        let mailbox = &state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .borrow()
+            .as_ref()
+            .expect("failed to get mailbox");
+
+        let subscribed = selection.iter().any(|option| option == "SUBSCRIBED");
+        // The SUBSCRIBED selection option implies the return option of the same name
+        let return_subscribed =
+            subscribed || return_options.iter().any(|option| option == "SUBSCRIBED");
+
+        let mut folders: Vec<String> = Vec::new();
+        for pattern in &patterns {
+            // An empty pattern asks for the delimiter and the root of the reference
+            if pattern.is_empty() {
+                let root = match reference.find(DELIMITER) {
+                    Some(index) => &reference[..=index],
+                    None => "",
+                };
+                folders.push(format!(
+                    "* LIST (\\Noselect) \"{}\" {}\r\n",
+                    DELIMITER,
+                    quote(root)
+                ));
+                continue;
+            }
+            if let Some(found) = mailbox
+                .get_list(&reference, pattern, subscribed, return_subscribed)
+                .await
+            {
+                folders.extend(found);
+            }
+        }
+        // Clients without UTF-8 support can't use those names anyway
+        folders.retain(|line| utf8 || line.is_ascii());
 
-                state.respond(addr, &complete).await?;
+        let response = format!("{} {}", identifier, "OK LIST completed\r");
+        folders.push(response);
 
-                //Print to view for debug
+        let complete = folders.concat();
+        debug!("Responded: {}", complete);
 
-                debug!("Responded: {} {}", identifier, "OK LIST Completed");
-            }
-            _ => {
-                let response = format!("{} {}", identifier, "NO Please Login first!\r");
+        state.respond(addr, &complete).await?;
 
-                state.respond(addr, &response).await?;
+        //Print to view for debug
 
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "NO Please Login first!");
-            }
-        }
+        debug!("Responded: {} {}", identifier, "OK LIST Completed");
 
         Ok(())
     }
 
     pub async fn lsub(
-        args: Vec<&str>,
+        identifier: &str,
+        reference: String,
+        pattern: String,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-
         let mut state = state.lock().await;
 
-        match state.peers.get(&addr).expect("unable to find peer").state {
-            State::LoggedIn => {
-                let mailbox = &state
-                    .peers
-                    .get(&addr)
-                    .expect("unable to find peer")
-                    .mailbox
-                    .borrow()
-                    .as_ref()
-                    .expect("failed to get mailbox");
-
-                let mut folders: Vec<String> =
-                    mailbox.get_lsub(args).await.expect("unable to get folders");
+        let utf8 = state.extensions(addr).utf8_accept;
+        let mailbox = &state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .borrow()
+            .as_ref()
+            .expect("failed to get mailbox");
 
-                let response = format!("{} {}", identifier, "OK LSUB completed\r");
-                folders.push(response);
+        let mut folders: Vec<String> = mailbox
+            .get_lsub(&reference, &pattern)
+            .await
+            .unwrap_or_default();
+        folders.retain(|line| utf8 || line.is_ascii());
 
-                let complete = folders.concat();
-                debug!("Responded: {}", complete);
+        let response = format!("{} {}", identifier, "OK LSUB completed\r");
+        folders.push(response);
 
-                state.respond(addr, &complete).await?;
+        let complete = folders.concat();
+        debug!("Responded: {}", complete);
 
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "OK LSUB Completed");
-            }
-            _ => {
-                let response = format!("{} {}", identifier, "NO Please Login first!\r");
-
-                state.respond(addr, &response).await?;
+        state.respond(addr, &complete).await?;
 
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "NO Please Login first!");
-            }
-        }
+        //Print to view for debug
+        debug!("Responded: {} {}", identifier, "OK LSUB Completed");
 
         Ok(())
     }
 
     pub async fn status(
-        args: Vec<&str>,
+        identifier: &str,
+        path: String,
+        items: Vec<String>,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-        let path = args[2];
-
         let mut state = state.lock().await;
 
-        match state.peers.get(&addr).expect("unable to find peer").state {
-            State::LoggedIn => {
-                let response = format!(
-                    "* STATUS {} (MESSAGES 2 UIDNEXT 2 UNSEEN 0 RECENT 0)\r\n",
-                    path.replace("\"", "")
-                );
-
-                let response_completed = format!("{} {}", identifier, "OK STATUS Completed\r");
-
-                let complete = [response, response_completed].concat();
+        let mailbox = state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .clone()
+            .expect("failed to get mailbox");
+
+        if let Some(item) = items
+            .iter()
+            .find(|item| !STATUS_ITEMS.contains(&item.as_str()))
+        {
+            let response = format!("{} BAD Unknown status item {}\r", identifier, item);
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} BAD Unknown status item {}", identifier, item);
+            return Ok(());
+        }
+        if items.iter().any(|item| item == "HIGHESTMODSEQ") {
+            state.enable_condstore(addr);
+        }
 
-                state.respond(addr, &complete).await?;
+        let status = match mailbox.folder_metadata(&path).await {
+            Ok(metadata) => mailbox
+                .list_messages(&path)
+                .await
+                .map(|messages| (metadata, messages)),
+            Err(e) => Err(e),
+        };
+        let (metadata, messages) = match status {
+            Ok(status) => status,
+            Err(e) => {
+                let response = format!("{} {}\r", identifier, delete::folder_error("STATUS", &e));
+                state.respond(addr, &response).await?;
 
                 //Print to view for debug
-                debug!(
-                    "Responded: * STATUS {} (MESSAGES 2 UIDNEXT 44292 UNSEEN 1 RECENT 1)",
-                    path
-                );
-                debug!("Responded: {} {}", identifier, "OK STATUS Completed");
+                debug!("Responded: {}", response);
+                return Ok(());
             }
-            _ => {
-                let response = format!("{} {}", identifier, "NO Please Login first!\r");
+        };
+
+        let values: Vec<String> = items
+            .iter()
+            .map(|item| {
+                let value = match item.as_str() {
+                    "MESSAGES" => messages.len() as u64,
+                    "RECENT" => messages.iter().filter(|message| message.recent).count() as u64,
+                    "UIDNEXT" => u64::from(metadata.uid_next),
+                    "UIDVALIDITY" => u64::from(metadata.uid_validity),
+                    "UNSEEN" => messages
+                        .iter()
+                        .filter(|message| !message.flags.iter().any(|flag| flag == "\\Seen"))
+                        .count() as u64,
+                    "HIGHESTMODSEQ" => metadata.highest_modseq,
+                    // SIZE, unknown items were rejected above
+                    _ => messages.iter().map(|message| message.size).sum(),
+                };
+                format!("{} {}", item, value)
+            })
+            .collect();
+
+        let response = format!("* STATUS {} ({})\r\n", quote(&path), values.join(" "));
+
+        let response_completed = format!("{} {}", identifier, "OK STATUS Completed\r");
+
+        let complete = [response, response_completed].concat();
 
-                state.respond(addr, &response).await?;
+        state.respond(addr, &complete).await?;
 
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "NO Please Login first!");
-            }
-        }
+        //Print to view for debug
+        debug!("Responded: {}", complete);
 
         Ok(())
     }
 
     pub async fn namespace(
-        args: Vec<&str>,
+        identifier: &str,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-
         let mut state = state.lock().await;
 
-        match state.peers.get(&addr).expect("unable to find peer").state {
-            State::LoggedIn => {
-                let one = "* NAMESPACE ((\"\" \".\")) NIL NIL\r\n";
+        let one = format!("* NAMESPACE ((\"\" \"{}\")) NIL NIL\r\n", DELIMITER);
 
-                let response = format!("{} {}", identifier, "OK Namespace completed.\r");
+        let response = format!("{} {}", identifier, "OK Namespace completed.\r");
 
-                let complete = [one, &response].concat();
+        let complete = [one, response].concat();
 
-                //Print to view for debug
-                debug!("Responded: {}", complete);
-                state.respond(addr, &complete).await?;
-
-            }
-            _ => {
-                let response = format!("{} {}", identifier, "NO Please Login first!\r");
-
-                state.respond(addr, &response).await?;
-
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "NO Please Login first!");
-            }
-        }
+        //Print to view for debug
+        debug!("Responded: {}", complete);
+        state.respond(addr, &complete).await?;
 
         Ok(())
     }
 
     pub async fn id(
-        args: Vec<&str>,
+        identifier: &str,
+        parameters: Option<IdParameters>,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-
-        let mut state = state.lock().await;
-
-        match state.peers.get(&addr).expect("unable to find peer").state {
-            State::LoggedIn => {
-                let one = "* ID (\"name\" \"IMAPServer-rs\" \"version\" \"0.1.0\")\r\n";
-
-                let response = format!("{} {}", identifier, "OK ID Completed\r");
-
-                let complete = [one, &response].concat();
-
-                state.respond(addr, &complete).await?;
-
-                //Print to view for debug
-                debug!(
-                    "Responded: {}",
-                    "* ID (\"name\" \"IMAPServer-rs\" \"version\" \"0.1.0\")"
-                );
-                debug!("Responded: {} {}", identifier, "OK ID Completed");
-            }
-            _ => {
-                let response = format!("{} {}", identifier, "NO Please Login first!\r");
-
-                state.respond(addr, &response).await?;
-
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "NO Please Login first!");
-            }
+        if let Some(parameters) = parameters {
+            debug!("Client ID: {:?}", parameters);
         }
 
-        Ok(())
-    }
-
-    pub async fn select(
-        args: Vec<&str>,
-        addr: SocketAddr,
-        state: Arc<Mutex<Shared>>,
-    ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-        let command = args[1];
         let mut state = state.lock().await;
 
-        match state.peers.get(&addr).expect("unable to find peer").state {
-            State::LoggedIn => {
-                let one = "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft NonJunk Junk)\r\n";
-                let two = "* OK [PERMANENTFLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft NonJunk Junk \\*)] Flags permitted\r\n";
-                let three = "* OK [UIDVALIDITY 1] UIDs valid\r\n";
-                let four = "* OK [UIDNEXT 2] Predicted next UID\r\n";
-                let five = "* 1 EXISTS\r\n";
-                let six = "* 0 RECENT\r\n";
-                //let seven = "* OK [UNSEEN 1] First unseen\r\n";
-
-                if command == "select" {
-                    let response =
-                        format!("{} {}", identifier, "OK [READ-WRITE] SELECT completed\r");
+        let one = "* ID (\"name\" \"IMAPServer-rs\" \"version\" \"0.1.0\")\r\n";
 
-                    let complete = [one, two, three, four, five, six, &response].concat();
+        let response = format!("{} {}", identifier, "OK ID Completed\r");
 
-                    debug!("{}", complete);
-                    state.respond(addr, &complete).await?;
-                } else {
-                    let response =
-                        format!("{} {}", identifier, "OK [READ-ONLY] SELECT completed\r");
-
-                    let complete = [one, two, three, four, five, six, &response].concat();
-
-                    state.respond(addr, &complete).await?;
-                }
-
-                //Print to view for debug
-                debug!(
-                    "Responded (truncated): {} {}",
-                    identifier, "OK [READ-WRITE] SELECT completed"
-                );
-            }
-            _ => {
-                let response = format!("{} {}", identifier, "NO Please Login first!\r");
+        let complete = [one, &response].concat();
 
-                state.respond(addr, &response).await?;
+        state.respond(addr, &complete).await?;
 
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "NO Please Login first!");
-            }
-        }
+        //Print to view for debug
+        debug!(
+            "Responded: {}",
+            "* ID (\"name\" \"IMAPServer-rs\" \"version\" \"0.1.0\")"
+        );
+        debug!("Responded: {} {}", identifier, "OK ID Completed");
 
         Ok(())
     }
 
     pub async fn create(
-        args: Vec<&str>,
+        identifier: &str,
+        path: String,
         addr: SocketAddr,
         state: Arc<Mutex<Shared>>,
     ) -> Result<(), mpsc::error::SendError<String>> {
-        let identifier = args[0];
-        let path = args[2];
-
         let mut state = state.lock().await;
 
-        match state.peers.get(&addr).expect("unable to find peer").state {
-            State::LoggedIn => {
-                let mailbox = &state
-                    .peers
-                    .get(&addr)
-                    .expect("unable to find peer")
-                    .mailbox
-                    .borrow()
-                    .as_ref()
-                    .expect("failed to get mailbox");
-
-                let path = path.replace("\"", "").replace(".", "/");
-                debug!("{}", path);
-                mailbox
-                    .create_folder(path)
-                    .await
-                    .expect("failed to create folder");
-
-                // TODO handle error and respond that one to the client
-
-                let response = format!("{} {}", identifier, "OK CREATE Completed\r");
-
-                state.respond(addr, &response).await?;
+        let mailbox = &state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .borrow()
+            .as_ref()
+            .expect("failed to get mailbox");
+
+        // A trailing delimiter only announces that subfolders will follow
+        let path = path.trim_end_matches(DELIMITER);
+        debug!("{}", path);
+
+        // Empty levels like in `a..b` can't be stored
+        let invalid = path.split(DELIMITER).any(str::is_empty) || path.contains('/');
+        if invalid || path.eq_ignore_ascii_case("INBOX") {
+            let response = format!("{} {}", identifier, "NO Invalid mailbox name\r");
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} {}", identifier, "NO Invalid mailbox name");
+            return Ok(());
+        }
 
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "OK CREATE Completed");
-            }
-            _ => {
-                let response = format!("{} {}", identifier, "NO Please Login first!\r");
+        let response = match mailbox.create_folder(path).await {
+            Ok(()) => format!("{} {}", identifier, "OK CREATE Completed\r"),
+            Err(e) => format!("{} {}\r", identifier, delete::folder_error("CREATE", &e)),
+        };
 
-                state.respond(addr, &response).await?;
+        state.respond(addr, &response).await?;
 
-                //Print to view for debug
-                debug!("Responded: {} {}", identifier, "NO Please Login first!");
-            }
-        }
+        //Print to view for debug
+        debug!("Responded: {}", response);
 
         Ok(())
     }
@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::mailbox::DELIMITER;
+
+use crate::commands::delete::folder_error;
+use crate::commands::Commands;
+use crate::Shared;
+
+impl Commands {
+    pub async fn rename(
+        identifier: &str,
+        from: String,
+        to: String,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let mailbox = state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .clone()
+            .expect("failed to get mailbox");
+
+        let to = to.trim_end_matches(DELIMITER);
+        let response = if to.is_empty() || to.contains('/') {
+            "NO Invalid mailbox name".to_string()
+        } else {
+            match mailbox.rename_folder(&from, to).await {
+                Ok(()) => "OK RENAME completed".to_string(),
+                Err(e) => folder_error("RENAME", &e),
+            }
+        };
+
+        let complete = format!("{} {}\r", identifier, response);
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} {}", identifier, response);
+
+        Ok(())
+    }
+}
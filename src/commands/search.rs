@@ -0,0 +1,316 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use log::{debug, error};
+use mailparse::{MailHeaderMap, ParsedMail};
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::mailbox::MessageInfo;
+
+use crate::commands::Commands;
+use crate::parser::SearchKey;
+use crate::{Shared, State};
+
+/// The charsets SEARCH understands. Both are handled as UTF-8.
+const CHARSETS: [&str; 2] = ["UTF-8", "US-ASCII"];
+
+/// A message of the selected folder while it gets checked against the criteria.
+struct Candidate<'a> {
+    sequence_number: u32,
+    message: &'a MessageInfo,
+    /// If the session reports the message as \Recent
+    recent: bool,
+    /// Only parsed if one of the keys looks at the content
+    mail: Option<&'a ParsedMail<'a>>,
+    /// The number of messages, `*` in a sequence set
+    count: u32,
+    /// The highest UID, `*` in a UID set
+    largest_uid: u32,
+}
+
+/// Checks if any key of the tree needs the content of the message.
+fn needs_content(key: &SearchKey) -> bool {
+    use SearchKey::*;
+
+    match key {
+        Bcc(_) | Cc(_) | From(_) | Subject(_) | To(_) | Header(..) | Body(_) | Text(_)
+        | SentBefore(_) | SentOn(_) | SentSince(_) => true,
+        Not(key) => needs_content(key),
+        Or(first, second) => needs_content(first) || needs_content(second),
+        And(keys) => keys.iter().any(needs_content),
+        _ => false,
+    }
+}
+
+/// Checks if any key of the tree is MODSEQ, which adds the mod-sequence to the response.
+fn uses_modseq(key: &SearchKey) -> bool {
+    use SearchKey::*;
+
+    match key {
+        ModSeq(_) => true,
+        Not(key) => uses_modseq(key),
+        Or(first, second) => uses_modseq(first) || uses_modseq(second),
+        And(keys) => keys.iter().any(uses_modseq),
+        _ => false,
+    }
+}
+
+/// Case-insensitive substring match as required for all string criteria.
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+fn has_flag(message: &MessageInfo, flag: &str) -> bool {
+    message
+        .flags
+        .iter()
+        .any(|known| known.eq_ignore_ascii_case(flag))
+}
+
+fn header_contains(mail: &ParsedMail, name: &str, value: &str) -> bool {
+    mail.headers
+        .get_all_values(name)
+        .iter()
+        .any(|known| contains(known, value))
+}
+
+/// The decoded text of all textual parts of a message.
+fn body_text(mail: &ParsedMail) -> String {
+    if !mail.subparts.is_empty() {
+        return mail
+            .subparts
+            .iter()
+            .map(body_text)
+            .collect::<Vec<String>>()
+            .join("\n");
+    }
+    if mail.ctype.mimetype.starts_with("text/") || mail.ctype.mimetype.starts_with("message/") {
+        return mail.get_body().unwrap_or_default();
+    }
+    String::new()
+}
+
+fn header_text(mail: &ParsedMail) -> String {
+    mail.headers
+        .iter()
+        .map(|header| format!("{}: {}", header.get_key(), header.get_value()))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The date of the `Date` header, disregarding time and timezone.
+fn sent_date(mail: &ParsedMail) -> Option<NaiveDate> {
+    let value = mail.headers.get_first_value("Date")?;
+    if let Ok(date) = DateTime::parse_from_rfc2822(value.trim()) {
+        return Some(date.naive_local().date());
+    }
+    mailparse::dateparse(&value)
+        .ok()
+        .map(|timestamp| Utc.timestamp(timestamp, 0).naive_utc().date())
+}
+
+fn internal_date(message: &MessageInfo) -> NaiveDate {
+    let date: DateTime<Local> = message.internal_date.into();
+    date.naive_local().date()
+}
+
+fn matches(key: &SearchKey, candidate: &Candidate) -> bool {
+    use SearchKey::*;
+
+    let message = candidate.message;
+    match key {
+        All => true,
+        Answered => has_flag(message, "\\Answered"),
+        Deleted => has_flag(message, "\\Deleted"),
+        Draft => has_flag(message, "\\Draft"),
+        Flagged => has_flag(message, "\\Flagged"),
+        Seen => has_flag(message, "\\Seen"),
+        Unanswered => !has_flag(message, "\\Answered"),
+        Undeleted => !has_flag(message, "\\Deleted"),
+        Undraft => !has_flag(message, "\\Draft"),
+        Unflagged => !has_flag(message, "\\Flagged"),
+        Unseen => !has_flag(message, "\\Seen"),
+        Recent => candidate.recent,
+        New => candidate.recent && !has_flag(message, "\\Seen"),
+        Old => !candidate.recent,
+        Keyword(keyword) => has_flag(message, keyword),
+        Unkeyword(keyword) => !has_flag(message, keyword),
+        Larger(size) => message.size > u64::from(*size),
+        Smaller(size) => message.size < u64::from(*size),
+        Before(date) => internal_date(message) < *date,
+        On(date) => internal_date(message) == *date,
+        Since(date) => internal_date(message) >= *date,
+        SequenceSet(set) => set.contains(candidate.sequence_number, candidate.count),
+        Uid(set) => set.contains(message.uid, candidate.largest_uid),
+        ModSeq(modseq) => message.modseq >= *modseq,
+        Not(key) => !matches(key, candidate),
+        Or(first, second) => matches(first, candidate) || matches(second, candidate),
+        And(keys) => keys.iter().all(|key| matches(key, candidate)),
+        _ => {
+            let mail = match candidate.mail {
+                Some(mail) => mail,
+                None => return false,
+            };
+            match key {
+                Bcc(value) => header_contains(mail, "Bcc", value),
+                Cc(value) => header_contains(mail, "Cc", value),
+                From(value) => header_contains(mail, "From", value),
+                Subject(value) => header_contains(mail, "Subject", value),
+                To(value) => header_contains(mail, "To", value),
+                Header(name, value) => header_contains(mail, name, value),
+                Body(value) => contains(&body_text(mail), value),
+                Text(value) => {
+                    contains(&header_text(mail), value) || contains(&body_text(mail), value)
+                }
+                SentBefore(date) => sent_date(mail).is_some_and(|sent| sent < *date),
+                SentOn(date) => sent_date(mail) == Some(*date),
+                SentSince(date) => sent_date(mail).is_some_and(|sent| sent >= *date),
+                _ => false,
+            }
+        }
+    }
+}
+
+impl Commands {
+    pub async fn search(
+        identifier: &str,
+        charset: Option<String>,
+        criteria: SearchKey,
+        uid: bool,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let connection = state.peers.get(&addr).expect("unable to find peer");
+        let selected = match &connection.state {
+            State::Selected(selected) => selected.clone(),
+            _ => return Ok(()),
+        };
+        let mailbox = connection.mailbox.clone().expect("failed to get mailbox");
+        let command = if uid { "UID SEARCH" } else { "SEARCH" };
+
+        if let Some(charset) = charset {
+            if !CHARSETS.contains(&charset.as_str()) {
+                let response = format!(
+                    "{} NO [BADCHARSET ({})] Unsupported charset\r",
+                    identifier,
+                    CHARSETS.join(" ")
+                );
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!("Responded: {} NO [BADCHARSET]", identifier);
+                return Ok(());
+            }
+        }
+
+        let messages = match mailbox.list_messages(&selected.folder).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Unable to list messages of {}: {}", selected.folder, e);
+                let response = format!("{} NO {} failed\r", identifier, command);
+                return state.respond(addr, &response).await;
+            }
+        };
+
+        let with_modseq = uses_modseq(&criteria);
+        if with_modseq {
+            state.enable_condstore(addr);
+        }
+
+        let with_content = needs_content(&criteria);
+        let count = selected.uids.len() as u32;
+        let largest_uid = selected.uids.last().copied().unwrap_or(0);
+
+        // The messages are looked up together instead of once per message
+        let mut contents = if with_content {
+            match mailbox
+                .read_messages(&selected.folder, &selected.uids)
+                .await
+            {
+                Ok(contents) => contents,
+                Err(e) => {
+                    error!("Unable to read messages of {}: {}", selected.folder, e);
+                    let response = format!("{} NO {} failed\r", identifier, command);
+                    return state.respond(addr, &response).await;
+                }
+            }
+        } else {
+            Vec::new()
+        }
+        .into_iter();
+
+        let mut results: Vec<u32> = Vec::new();
+        let mut highest_modseq = 0;
+        for (index, message_uid) in selected.uids.iter().enumerate() {
+            let content = if with_content {
+                match contents.next() {
+                    Some(Ok(content)) => content,
+                    Some(Err(e)) => {
+                        error!("Unable to read message {}: {}", message_uid, e);
+                        continue;
+                    }
+                    None => continue,
+                }
+            } else {
+                Vec::new()
+            };
+
+            let message = match messages.iter().find(|message| message.uid == *message_uid) {
+                Some(message) => message,
+                None => continue,
+            };
+            let mail = if with_content {
+                mailparse::parse_mail(&content).ok()
+            } else {
+                None
+            };
+
+            let candidate = Candidate {
+                sequence_number: index as u32 + 1,
+                message,
+                recent: selected.recent.contains(&message.uid),
+                mail: mail.as_ref(),
+                count,
+                largest_uid,
+            };
+            if matches(&criteria, &candidate) {
+                let number = if uid {
+                    *message_uid
+                } else {
+                    candidate.sequence_number
+                };
+                results.push(number);
+                highest_modseq = highest_modseq.max(message.modseq);
+            }
+        }
+
+        let mut lines: Vec<String> = Vec::new();
+        let numbers: String = results
+            .iter()
+            .map(|number| format!(" {}", number))
+            .collect();
+        if with_modseq && !results.is_empty() {
+            // The highest mod-sequence of the returned messages (RFC 7162 section 3.1.5)
+            lines.push(format!(
+                "* SEARCH{} (MODSEQ {})\r\n",
+                numbers, highest_modseq
+            ));
+        } else {
+            lines.push(format!("* SEARCH{}\r\n", numbers));
+        }
+
+        let response = format!("{} OK {} completed\r", identifier, command);
+        lines.push(response);
+
+        let complete = lines.concat();
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} OK {} completed", identifier, command);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::mailbox::MessageInfo;
+
+use crate::commands::copy::uid_set;
+use crate::commands::delete::folder_error;
+use crate::commands::fetch::{flag_list, vanished};
+use crate::commands::Commands;
+use crate::parser::{SelectParameters, SeqNumber, SequenceSet};
+use crate::{Selected, Shared, State};
+
+/// The flags defined by RFC 3501 that clients may set.
+const SYSTEM_FLAGS: [&str; 5] = ["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft"];
+
+/// The system flags followed by the keywords used in the folder, sorted.
+pub(crate) fn folder_flags(messages: &[MessageInfo]) -> Vec<String> {
+    let mut keywords: Vec<String> = messages
+        .iter()
+        .flat_map(|message| message.flags.iter())
+        .filter(|flag| !flag.starts_with('\\'))
+        .cloned()
+        .collect();
+    keywords.sort();
+    keywords.dedup();
+
+    SYSTEM_FLAGS
+        .iter()
+        .map(|flag| flag.to_string())
+        .chain(keywords)
+        .collect()
+}
+
+impl Commands {
+    /// Selects a folder. EXAMINE does the same with `read_only` set.
+    ///
+    /// Only SELECT claims the \Recent messages, so they are not recent
+    /// anymore for sessions selecting the folder later on.
+    ///
+    /// With QRESYNC the client gets the changes since the mod-sequence it
+    /// knows right away (RFC 7162 section 3.2.5).
+    pub async fn select(
+        identifier: &str,
+        path: String,
+        parameters: SelectParameters,
+        read_only: bool,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        if parameters.qresync.is_some() && !state.extensions(addr).qresync {
+            let response = format!("{} BAD QRESYNC is not enabled\r", identifier);
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} BAD QRESYNC is not enabled", identifier);
+            return Ok(());
+        }
+        if parameters.condstore {
+            state.enable_condstore(addr);
+        }
+        let enabled = state.extensions(addr);
+
+        let mailbox = state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .clone()
+            .expect("failed to get mailbox");
+        let command = if read_only { "EXAMINE" } else { "SELECT" };
+
+        let status = match mailbox.folder_metadata(&path).await {
+            Ok(metadata) => mailbox
+                .list_messages(&path)
+                .await
+                .map(|messages| (metadata, messages)),
+            Err(e) => Err(e),
+        };
+
+        let connection = state.peers.get_mut(&addr).expect("unable to find peer");
+
+        // QRESYNC clients need to know where the responses of the old folder end
+        let mut lines: Vec<String> = Vec::new();
+        if enabled.qresync && matches!(connection.state, State::Selected(_)) {
+            lines.push("* OK [CLOSED] Previous mailbox closed\r\n".to_string());
+        }
+
+        // A failed SELECT or EXAMINE closes the currently selected folder
+        let (metadata, messages) = match status {
+            Ok(status) => status,
+            Err(e) => {
+                connection.state = State::Authenticated;
+
+                lines.push(format!("{} {}\r", identifier, folder_error(command, &e)));
+                let response = lines.concat();
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!("Responded: {}", response);
+                return Ok(());
+            }
+        };
+
+        let uids: Vec<u32> = messages.iter().map(|message| message.uid).collect();
+        let recent: Vec<u32> = messages
+            .iter()
+            .filter(|message| message.recent)
+            .map(|message| message.uid)
+            .collect();
+
+        if !read_only {
+            // Storing the flags moves the message out of `new`
+            for message in messages.iter().filter(|message| message.recent) {
+                if let Err(e) = mailbox.set_flags(&path, message.uid, &message.flags).await {
+                    error!("Unable to clear \\Recent of {}: {}", message.uid, e);
+                }
+            }
+        }
+
+        let flags = folder_flags(&messages).join(" ");
+        lines.push(format!("* FLAGS ({})\r\n", flags));
+        lines.push(format!("* {} EXISTS\r\n", uids.len()));
+        lines.push(format!("* {} RECENT\r\n", recent.len()));
+        let first_unseen = messages
+            .iter()
+            .position(|message| !message.flags.iter().any(|flag| flag == "\\Seen"));
+        if let Some(index) = first_unseen {
+            lines.push(format!("* OK [UNSEEN {}] First unseen\r\n", index + 1));
+        }
+        if read_only {
+            lines.push("* OK [PERMANENTFLAGS ()] No permanent flags permitted\r\n".to_string());
+        } else {
+            lines.push(format!(
+                "* OK [PERMANENTFLAGS ({} \\*)] Flags permitted\r\n",
+                flags
+            ));
+        }
+        lines.push(format!(
+            "* OK [UIDVALIDITY {}] UIDs valid\r\n",
+            metadata.uid_validity
+        ));
+        lines.push(format!(
+            "* OK [UIDNEXT {}] Predicted next UID\r\n",
+            metadata.uid_next
+        ));
+        if enabled.condstore {
+            lines.push(format!(
+                "* OK [HIGHESTMODSEQ {}] Highest\r\n",
+                metadata.highest_modseq
+            ));
+        }
+
+        // Changes since the state the client has cached, unless the UIDs changed meanwhile
+        if let Some(qresync) = parameters
+            .qresync
+            .filter(|qresync| qresync.uid_validity == metadata.uid_validity)
+        {
+            let known_uids = qresync
+                .known_uids
+                .unwrap_or_else(|| SequenceSet(vec![(SeqNumber::Value(1), SeqNumber::Largest)]));
+            let gone = vanished(&known_uids, metadata.uid_next, &messages);
+            if !gone.is_empty() {
+                lines.push(format!("* VANISHED (EARLIER) {}\r\n", uid_set(&gone)));
+            }
+            for (index, message) in messages.iter().enumerate() {
+                if message.modseq > qresync.modseq {
+                    lines.push(format!(
+                        "* {} FETCH (UID {} FLAGS {} MODSEQ ({}))\r\n",
+                        index + 1,
+                        message.uid,
+                        flag_list(message, recent.contains(&message.uid)),
+                        message.modseq
+                    ));
+                }
+            }
+        }
+
+        connection.state = State::Selected(Selected {
+            folder: path,
+            read_only,
+            uids,
+            recent,
+            flags: messages
+                .into_iter()
+                .map(|message| (message.uid, message.flags))
+                .collect(),
+        });
+
+        let response = if read_only {
+            "OK [READ-ONLY] EXAMINE completed"
+        } else {
+            "OK [READ-WRITE] SELECT completed"
+        };
+        lines.push(format!("{} {}\r", identifier, response));
+
+        let complete = lines.concat();
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded (truncated): {} {}", identifier, response);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::Commands;
+use crate::Shared;
+
+impl Commands {
+    /// Starts the TLS negotiation (RFC 3501 section 6.2.1).
+    ///
+    /// The handshake itself happens in `process` once the response was sent.
+    pub async fn starttls(
+        identifier: &str,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        // Only the acceptor built at startup can do the handshake
+        let available = state.starttls;
+        let connection = state.peers.get_mut(&addr).expect("unable to find peer");
+        let response = if connection.tls {
+            "BAD TLS is already active"
+        } else if !available {
+            "BAD STARTTLS is not available"
+        } else {
+            connection.start_tls = true;
+            "OK Begin TLS negotiation now"
+        };
+
+        let complete = format!("{} {}\r", identifier, response);
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} {}", identifier, response);
+        Ok(())
+    }
+}
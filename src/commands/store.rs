@@ -0,0 +1,231 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::copy::uid_set;
+use crate::commands::fetch::{flags_item, resolve};
+use crate::commands::Commands;
+use crate::parser::{SequenceSet, StoreAction, StoreOperation};
+use crate::{Shared, State};
+
+const SYSTEM_FLAGS: [&str; 5] = ["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft"];
+
+/// Brings system flags into their canonical spelling. Returns `None` for flags that can't be stored.
+fn normalize_flag(flag: &str) -> Option<String> {
+    if !flag.starts_with('\\') {
+        return Some(flag.to_string());
+    }
+    SYSTEM_FLAGS
+        .iter()
+        .find(|system| system.eq_ignore_ascii_case(flag))
+        .map(|system| system.to_string())
+}
+
+/// Applies a STORE operation to the current flags of a message.
+pub(crate) fn apply_flags(
+    current: &[String],
+    operation: StoreOperation,
+    flags: &[String],
+) -> Vec<String> {
+    let contains =
+        |list: &[String], flag: &str| list.iter().any(|known| known.eq_ignore_ascii_case(flag));
+
+    match operation {
+        StoreOperation::Replace => {
+            let mut updated: Vec<String> = Vec::new();
+            for flag in flags {
+                if !contains(&updated, flag) {
+                    updated.push(flag.clone());
+                }
+            }
+            updated
+        }
+        StoreOperation::Add => {
+            let mut updated = current.to_vec();
+            for flag in flags {
+                if !contains(&updated, flag) {
+                    updated.push(flag.clone());
+                }
+            }
+            updated
+        }
+        StoreOperation::Remove => current
+            .iter()
+            .filter(|flag| !contains(flags, flag))
+            .cloned()
+            .collect(),
+    }
+}
+
+impl Commands {
+    pub async fn store(
+        identifier: &str,
+        sequence_set: SequenceSet,
+        action: StoreAction,
+        uid: bool,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let connection = state.peers.get(&addr).expect("unable to find peer");
+        let selected = match &connection.state {
+            State::Selected(selected) => selected.clone(),
+            _ => return Ok(()),
+        };
+        let mailbox = connection.mailbox.clone().expect("failed to get mailbox");
+        let command = if uid { "UID STORE" } else { "STORE" };
+
+        if selected.read_only {
+            let response = format!("{} NO Mailbox is read-only\r", identifier);
+            state.respond(addr, &response).await?;
+
+            //Print to view for debug
+            debug!("Responded: {} NO Mailbox is read-only", identifier);
+            return Ok(());
+        }
+
+        let targets = match resolve(&sequence_set, uid, &selected.uids) {
+            Ok(targets) => targets,
+            Err(reason) => {
+                let response = format!("{} {}\r", identifier, reason);
+                state.respond(addr, &response).await?;
+
+                //Print to view for debug
+                debug!("Responded: {} {}", identifier, reason);
+                return Ok(());
+            }
+        };
+
+        // \Recent is managed by the server
+        let flags: Vec<String> = action
+            .flags
+            .iter()
+            .filter_map(|flag| normalize_flag(flag))
+            .collect();
+
+        if action.unchanged_since.is_some() {
+            state.enable_condstore(addr);
+        }
+        let enabled = state.extensions(addr);
+
+        let messages = match mailbox.list_messages(&selected.folder).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Unable to list messages of {}: {}", selected.folder, e);
+                let response = format!("{} NO {} failed\r", identifier, command);
+                return state.respond(addr, &response).await;
+            }
+        };
+
+        // Messages changed after UNCHANGEDSINCE are left alone (RFC 7162 section 3.1.3)
+        let mut modified: Vec<u32> = Vec::new();
+        let mut stored: Vec<(usize, u32, bool)> = Vec::new();
+        let mut failed = false;
+        for (sequence_number, message_uid) in targets {
+            let message = match messages.iter().find(|message| message.uid == message_uid) {
+                Some(message) => message,
+                None => continue,
+            };
+            if action
+                .unchanged_since
+                .is_some_and(|unchanged_since| message.modseq > unchanged_since)
+            {
+                modified.push(if uid {
+                    message_uid
+                } else {
+                    sequence_number as u32
+                });
+                continue;
+            }
+
+            let updated = apply_flags(&message.flags, action.operation, &flags);
+            let changed = updated != message.flags;
+            if changed {
+                if let Err(e) = mailbox
+                    .set_flags(&selected.folder, message_uid, &updated)
+                    .await
+                {
+                    error!("Unable to store flags of {}: {}", message_uid, e);
+                    failed = true;
+                    continue;
+                }
+            }
+            stored.push((sequence_number, message_uid, changed));
+        }
+
+        // The new mod-sequences are assigned when the flags are stored
+        let messages = match mailbox.list_messages(&selected.folder).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Unable to list messages of {}: {}", selected.folder, e);
+                let response = format!("{} NO {} failed\r", identifier, command);
+                return state.respond(addr, &response).await;
+            }
+        };
+
+        let mut lines: Vec<String> = Vec::new();
+        let mut notify = false;
+        for (sequence_number, message_uid, changed) in stored {
+            let message = match messages.iter().find(|message| message.uid == message_uid) {
+                Some(message) => message,
+                None => continue,
+            };
+
+            if changed {
+                state.remember_flags(addr, message_uid, &message.flags);
+                notify = true;
+            }
+
+            let uid_item = if uid {
+                format!("UID {} ", message_uid)
+            } else {
+                String::new()
+            };
+            if !action.silent {
+                let recent = selected.recent.contains(&message_uid);
+                lines.push(format!(
+                    "* {} FETCH ({}{})\r\n",
+                    sequence_number,
+                    uid_item,
+                    flags_item(message, recent, enabled.condstore)
+                ));
+            } else if action.unchanged_since.is_some() {
+                // Even silent conditional stores report the new mod-sequence
+                lines.push(format!(
+                    "* {} FETCH ({}MODSEQ ({}))\r\n",
+                    sequence_number, uid_item, message.modseq
+                ));
+            }
+        }
+
+        // Other sessions learn about the new flags with their next command
+        if notify {
+            state.refresh_idle(&mailbox.user, &selected.folder).await;
+        }
+
+        let response = if failed {
+            format!("{} NO {} failed for some messages\r", identifier, command)
+        } else if !modified.is_empty() {
+            format!(
+                "{} OK [MODIFIED {}] Conditional {} failed\r",
+                identifier,
+                uid_set(&modified),
+                command
+            )
+        } else {
+            format!("{} OK {} completed\r", identifier, command)
+        };
+        lines.push(response.clone());
+
+        let complete = lines.concat();
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {}", response);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::commands::Commands;
+use crate::Shared;
+
+impl Commands {
+    /// Subscribes to a folder. The folder does not need to exist.
+    pub async fn subscribe(
+        identifier: &str,
+        path: String,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let mailbox = state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .clone()
+            .expect("failed to get mailbox");
+
+        let response = match mailbox.subscribe(&path).await {
+            Ok(()) => "OK SUBSCRIBE completed",
+            Err(e) => {
+                error!("Unable to subscribe to {}: {}", path, e);
+                "NO SUBSCRIBE failed"
+            }
+        };
+
+        let complete = format!("{} {}\r", identifier, response);
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} {}", identifier, response);
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe(
+        identifier: &str,
+        path: String,
+        addr: SocketAddr,
+        state: Arc<Mutex<Shared>>,
+    ) -> Result<(), mpsc::error::SendError<String>> {
+        let mut state = state.lock().await;
+
+        let mailbox = state
+            .peers
+            .get(&addr)
+            .expect("unable to find peer")
+            .mailbox
+            .clone()
+            .expect("failed to get mailbox");
+
+        let response = match mailbox.unsubscribe(&path).await {
+            Ok(true) => "OK UNSUBSCRIBE completed",
+            Ok(false) => "NO [NONEXISTENT] Not subscribed to that mailbox",
+            Err(e) => {
+                error!("Unable to unsubscribe from {}: {}", path, e);
+                "NO UNSUBSCRIBE failed"
+            }
+        };
+
+        let complete = format!("{} {}\r", identifier, response);
+        state.respond(addr, &complete).await?;
+
+        //Print to view for debug
+        debug!("Responded: {} {}", identifier, response);
+
+        Ok(())
+    }
+}
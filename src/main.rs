@@ -2,44 +2,61 @@
 
 use std::collections::HashMap;
 use std::error::Error;
-use std::io::{BufWriter, Write};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use futures::io::ErrorKind::{ConnectionAborted, ConnectionReset};
 use futures::sink::SinkExt;
-use futures::Stream;
-use futures::StreamExt;
 use futures::task::Context;
 use futures::task::Poll;
+use futures::Stream;
+use futures::StreamExt;
 use log::{debug, error, info};
-use tokio::io;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::TcpListener;
 use tokio::sync::{mpsc, Mutex};
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
 
 use IMAPServer_shared::config::Config;
 use IMAPServer_shared::mailbox::Mailbox;
 use IMAPServer_shared::setup;
 
+use crate::codec::{ImapCodec, ImapCodecError, Input};
+use crate::tls::ImapStream;
+
+mod codec;
 mod commands;
-mod config;
 mod log_helper;
+mod message;
+mod parser;
+mod sasl;
+mod tls;
+mod watcher;
+
+#[cfg(test)]
+mod tests;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     log_helper::setup_logger().expect("Unable to start logger.");
 
     setup();
-    Config::load().await;
+    let config = Arc::new(Config::load().await.expect("unable to load config"));
+
+    // IMAPS and STARTTLS are only offered with a certificate
+    let acceptor = tls::acceptor(&config)?;
 
     // Create the shared state. This is how all the peers communicate.
     //
     // The server task will hold a handle to this. For every new client, the
     // `state` handle is cloned and passed into the task that processes the
     // client connection.
-    let state = Arc::new(Mutex::new(Shared::new()));
+    let state = Arc::new(Mutex::new(Shared::new(
+        Arc::clone(&config),
+        acceptor.is_some(),
+    )));
 
     let addr: SocketAddr = "0.0.0.0:143".parse()?;
     let mut listener = TcpListener::bind(&addr).await?;
@@ -48,7 +65,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Startup procedure
     info!("Starting up...");
 
-    let mailboxes = Mailbox::load_all().await.expect("failed to get mailbox");
+    let mailboxes = Mailbox::load_all(&config)
+        .await
+        .expect("failed to get mailbox");
 
     for mailbox in &mailboxes {
         mailbox.check_mailbox_root().await?;
@@ -69,6 +88,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
         mailbox.check_mailbox_folder("Junk").await?;
     }
 
+    // Push changes from outside of the server to idling clients
+    if let Err(e) = watcher::spawn(&mailboxes, Arc::clone(&state)) {
+        error!("Unable to watch the mailboxes: {}", e);
+    }
+
+    if let Some(acceptor) = &acceptor {
+        let addr: SocketAddr = "0.0.0.0:993".parse()?;
+        let listener = TcpListener::bind(&addr).await?;
+        info!("Listening for IMAPS on: {}", addr);
+        tokio::spawn(listen_tls(listener, acceptor.clone(), Arc::clone(&state)));
+    }
+
     // Listening
     info!("Start listening...");
     loop {
@@ -77,11 +108,45 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
         // Clone a handle to the `Shared` state for the new connection.
         let state = Arc::clone(&state);
+        let acceptor = acceptor.clone();
 
         // Spawn our handler to be run asynchronously.
         tokio::spawn(async move {
             info!("{} connected", addr);
-            if let Err(e) = process(state, stream, addr).await {
+            let stream = ImapStream::Plain(stream);
+            if let Err(e) = process(state, stream, addr, acceptor).await {
+                error!("an error occurred; error = {:?}", e);
+            }
+        });
+    }
+}
+
+/// Accepts IMAPS connections, which start with the TLS handshake.
+async fn listen_tls(mut listener: TcpListener, acceptor: TlsAcceptor, state: Arc<Mutex<Shared>>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Unable to accept IMAPS connection: {}", e);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let state = Arc::clone(&state);
+
+        // The handshake runs in the task so a slow client doesn't block others
+        tokio::spawn(async move {
+            info!("{} connected using TLS", addr);
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+            };
+            let stream = ImapStream::Tls(Box::new(stream));
+            if let Err(e) = process(state, stream, addr, None).await {
                 error!("an error occurred; error = {:?}", e);
             }
         });
@@ -89,22 +154,57 @@ async fn main() -> Result<(), Box<dyn Error>> {
 }
 
 /// Shorthand for the transmit half of the message channel.
-type Tx = mpsc::UnboundedSender<String>;
+type Tx = mpsc::UnboundedSender<Vec<u8>>;
 
 /// Shorthand for the receive half of the message channel.
-type Rx = mpsc::UnboundedReceiver<String>;
+type Rx = mpsc::UnboundedReceiver<Vec<u8>>;
+
+/// The folder a connection has opened using SELECT or EXAMINE.
+#[derive(Debug, Clone, PartialEq)]
+struct Selected {
+    folder: String,
+    read_only: bool,
+    /// The UIDs of the messages in the order of their sequence numbers
+    uids: Vec<u32>,
+    /// The UIDs this session reports as \Recent
+    recent: Vec<u32>,
+    /// The flags the session last saw for each UID, to find changes made elsewhere
+    flags: HashMap<u32, Vec<String>>,
+}
+
+/// The extensions a connection turned on with ENABLE or by using them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Extensions {
+    /// RFC 7162, also turned on by the first command that uses mod-sequences
+    condstore: bool,
+    /// RFC 7162, which implies CONDSTORE
+    qresync: bool,
+    /// RFC 6855, mailbox names may contain UTF-8
+    utf8_accept: bool,
+}
 
-#[derive(PartialEq)]
+/// The connection states as described in RFC 3501 section 3.
+#[derive(Debug, Clone, PartialEq)]
 enum State {
-    LoggedOut,
-    LoggedIn,
+    NotAuthenticated,
+    Authenticated,
+    Selected(Selected),
+    Logout,
 }
 
 struct Connection {
     state: State,
-    identifier: String,
+    /// The AUTHENTICATE exchange waiting for the next client response
+    sasl: Option<sasl::Exchange>,
     tx: Tx,
     mailbox: Option<Mailbox>,
+    /// The tag of the running IDLE command
+    idle: Option<String>,
+    enabled: Extensions,
+    /// If the connection is encrypted
+    tls: bool,
+    /// STARTTLS was accepted, the handshake follows once the response is sent
+    start_tls: bool,
 }
 
 /// Data that is shared between all peers in the chat server.
@@ -115,16 +215,21 @@ struct Connection {
 /// `Tx`.
 struct Shared {
     peers: HashMap<SocketAddr, Connection>,
+    /// Loaded once at startup, the listeners and the TLS acceptor are set up from it
+    config: Arc<Config>,
+    /// If there is a TLS acceptor to upgrade plain text connections with
+    starttls: bool,
 }
 
 /// The state for each connected client.
 struct Peer {
-    /// The TCP socket wrapped with the `Lines` codec, defined below.
+    /// The TCP socket wrapped with the `ImapCodec`, defined in `codec.rs`.
     ///
-    /// This handles sending and receiving data on the socket. When using
-    /// `Lines`, we can work at the line level instead of having to manage the
-    /// raw byte operations.
-    lines: Framed<TcpStream, LinesCodec>,
+    /// This handles sending and receiving data on the socket. The codec
+    /// assembles complete commands including their literals so that we can
+    /// work at the command level instead of having to manage the raw byte
+    /// operations.
+    lines: Framed<ImapStream, ImapCodec>,
 
     /// Receive half of the message channel.
     ///
@@ -135,24 +240,31 @@ struct Peer {
 
 impl Shared {
     /// Create a new, empty, instance of `Shared`.
-    fn new() -> Self {
+    fn new(config: Arc<Config>, starttls: bool) -> Self {
         Shared {
             peers: HashMap::new(),
+            config,
+            starttls,
         }
     }
 
     /// Send a `LineCodec` encoded message to every peer, except
     /// for the sender.
-    async fn respond(
+    ///
+    /// The message is sent as it is, so literals may contain any bytes.
+    async fn respond<M>(
         &mut self,
         sender: SocketAddr,
-        message: &str,
-    ) -> Result<(), mpsc::error::SendError<String>> {
+        message: M,
+    ) -> Result<(), mpsc::error::SendError<String>>
+    where
+        M: AsRef<[u8]>,
+    {
         for peer in self.peers.iter_mut() {
             if *peer.0 == sender {
                 peer.1
                     .tx
-                    .send(message.into())
+                    .send(message.as_ref().to_vec())
                     .expect("failed to send message");
                 break;
             }
@@ -166,19 +278,21 @@ impl Peer {
     /// Create a new instance of `Peer`.
     async fn new(
         state: Arc<Mutex<Shared>>,
-        lines: Framed<TcpStream, LinesCodec>,
+        lines: Framed<ImapStream, ImapCodec>,
+        addr: SocketAddr,
     ) -> io::Result<Peer> {
-        // Get the client socket address
-        let addr = lines.get_ref().peer_addr()?;
-
         // Create a channel for this peer
         let (tx, rx) = mpsc::unbounded_channel();
 
         // Add an entry for this `Peer` in the shared state map.
         let connection = Connection {
-            identifier: "".to_string(),
-            state: State::LoggedOut,
+            state: State::NotAuthenticated,
+            sasl: None,
             mailbox: None,
+            idle: None,
+            enabled: Extensions::default(),
+            tls: lines.get_ref().is_tls(),
+            start_tls: false,
             tx,
         };
         state.lock().await.peers.insert(addr, connection);
@@ -189,17 +303,26 @@ impl Peer {
 
 #[derive(Debug)]
 enum Message {
-    // Response from the server
-    Response(String),
+    // Response from the server, which may contain binary literals
+    Response(Vec<u8>),
 
     /// A message that contains a command
-    Command(String),
+    Command(Vec<u8>),
+
+    /// The client waits for a continuation request before sending a literal
+    ContinuationRequest,
+
+    /// The client sent a literal that was too large
+    LiteralTooLarge {
+        tag: Option<String>,
+        synchronizing: bool,
+    },
 }
 
 // Peer implements `Stream` in a way that polls both the `Rx`, and `Framed` types.
 // A message is produced whenever an event is ready until the `Framed` stream returns `None`.
 impl Stream for Peer {
-    type Item = Result<Message, LinesCodecError>;
+    type Item = Result<Message, ImapCodecError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         // First poll the `UnboundedReceiver`.
@@ -213,7 +336,11 @@ impl Stream for Peer {
 
         Poll::Ready(match result {
             // We've received a message we should broadcast to others.
-            Some(Ok(message)) => Some(Ok(Message::Command(message))),
+            Some(Ok(Input::Command(message))) => Some(Ok(Message::Command(message))),
+            Some(Ok(Input::ContinuationRequest)) => Some(Ok(Message::ContinuationRequest)),
+            Some(Ok(Input::LiteralTooLarge { tag, synchronizing })) => {
+                Some(Ok(Message::LiteralTooLarge { tag, synchronizing }))
+            }
 
             // An error occurred.
             Some(Err(e)) => Some(Err(e)),
@@ -225,77 +352,119 @@ impl Stream for Peer {
 }
 
 /// Process an individual imap connection
+///
+/// `acceptor` is used for STARTTLS on plain text connections.
 async fn process(
     state: Arc<Mutex<Shared>>,
-    stream: TcpStream,
+    stream: ImapStream,
     addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
 ) -> Result<(), Box<dyn Error>> {
-    let mut lines = Framed::new(stream, LinesCodec::new());
+    let config = Arc::clone(&state.lock().await.config);
+    let lines = Framed::new(
+        stream,
+        ImapCodec::new(config.max_literal_size, config.literal_minus),
+    );
+
+    // Register our peer with state which internally sets up some channels.
+    let mut peer = Peer::new(state.clone(), lines, addr).await?;
 
     // Send Capabilities
-    lines
-        .send(String::from("* OK [CAPABILITY IMAP4rev1 AUTH=PLAIN UTF8=ONLY NAMESPACE LIST-EXTENDED ID ENABLE LOGINDISABLED] IMAP4rev1 Service Ready\r"))
+    let capabilities = state.lock().await.capabilities(addr);
+    peer.lines
+        .send(format!(
+            "* OK [CAPABILITY {}] IMAP4rev1 Service Ready\r",
+            capabilities
+        ))
         .await?;
 
-    // Register our peer with state which internally sets up some channels.
-    let mut peer = Peer::new(state.clone(), lines).await?;
-
     // Process incoming messages until our stream is exhausted by a disconnect.
     while let Some(result) = peer.next().await {
         match result {
             // A message was received from the current user, we should
             // treat it as a command
-            Ok(Message::Command(msg)) => {
+            Ok(Message::Command(frame)) => {
+                let msg = String::from_utf8_lossy(&frame);
                 debug!("Message raw: {}", msg);
-                let args: Vec<&str> = msg.split_whitespace().collect();
-                if args.len() > 1 {
-                    let command = args[1].to_lowercase();
-
-                    if command == "capability" {
-                        commands::Commands::capability(args, addr, state.clone()).await?;
-                    } else if command == "logout" {
-                        commands::Commands::logout(args, addr, state.clone()).await?;
-                    } else if command == "select" || command == "examine" {
-                        commands::Commands::select(args, addr, state.clone()).await?;
-                    } else if command == "list" {
-                        commands::Commands::list(args, addr, state.clone()).await?;
-                    } else if command == "lsub" {
-                        commands::Commands::lsub(args, addr, state.clone()).await?;
-                    } else if command == "create" {
-                        commands::Commands::create(args, addr, state.clone()).await?;
-                    } else if command == "namespace" {
-                        commands::Commands::namespace(args, addr, state.clone()).await?;
-                    } else if command == "status" {
-                        commands::Commands::status(args, addr, state.clone()).await?;
-                    } else if command == "id" {
-                        commands::Commands::id(args, addr, state.clone()).await?;
-                    } else if command == "noop" {
-                        commands::Commands::noop(args, addr, state.clone()).await?;
-                    } else if command == "enable" {
-                        commands::Commands::enable(args, addr, state.clone()).await?;
-                    } else if command == "authenticate" {
-                        commands::authenticate::Authentication::authenticate(
-                            args,
-                            addr,
-                            state.clone(),
-                        )
-                            .await?;
-                    } else {
-                        error!("Command {} by {} is not known. dropping it.", command, addr);
+                if commands::Commands::done(&msg, addr, state.clone()).await? {
+                    continue;
+                }
+                if commands::authenticate::Authentication::respond(&msg, addr, state.clone())
+                    .await?
+                {
+                    continue;
+                }
+                match parser::parse_command(&frame) {
+                    Ok(request) => {
+                        commands::Commands::dispatch(request, addr, state.clone()).await?;
+
+                        let logged_out = match state.lock().await.peers.get(&addr) {
+                            Some(connection) => connection.state == State::Logout,
+                            None => true,
+                        };
+                        if logged_out {
+                            // Flush the responses that are still queued before closing
+                            while let Ok(msg) = peer.rx.try_recv() {
+                                peer.lines.send(msg).await?;
+                            }
+                            break;
+                        }
+
+                        let start_tls = match state.lock().await.peers.get_mut(&addr) {
+                            Some(connection) => std::mem::take(&mut connection.start_tls),
+                            None => false,
+                        };
+                        if let (true, Some(acceptor)) = (start_tls, &acceptor) {
+                            // The OK response has to go out in plain text
+                            while let Ok(msg) = peer.rx.try_recv() {
+                                peer.lines.send(msg).await?;
+                            }
+
+                            // Dropping the codec discards commands the client
+                            // pipelined in plain text
+                            let Peer { lines, rx } = peer;
+                            let stream = match lines.into_inner().upgrade(acceptor).await {
+                                Ok(stream) => stream,
+                                Err(e) => {
+                                    error!("TLS handshake with {} failed: {}", addr, e);
+                                    state.lock().await.peers.remove(&addr);
+                                    return Ok(());
+                                }
+                            };
+                            peer = Peer {
+                                lines: Framed::new(
+                                    stream,
+                                    ImapCodec::new(config.max_literal_size, config.literal_minus),
+                                ),
+                                rx,
+                            };
+                            let capabilities = {
+                                let mut state = state.lock().await;
+                                if let Some(connection) = state.peers.get_mut(&addr) {
+                                    connection.tls = true;
+                                }
+                                state.capabilities(addr)
+                            };
+
+                            // Capabilities seen before TLS must not be trusted anymore
+                            peer.lines
+                                .send(format!("* CAPABILITY {}\r", capabilities))
+                                .await?;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Unable to parse command by {}: {}", addr, e);
 
+                        let response = match e.tag {
+                            Some(tag) => format!("{} BAD {}\r", tag, e.message),
+                            None => format!("* BAD {}\r", e.message),
+                        };
                         let mut state = state.lock().await;
                         state
-                            .respond(addr, "* BAD Command not known\r")
+                            .respond(addr, &response)
                             .await
                             .expect("Unable to write");
                     }
-                } else if args.len() == 1 {
-                    commands::authenticate::Authentication::parse_login_data(
-                        args,
-                        addr,
-                        state.clone(),
-                    )
-                        .await?;
                 }
             }
 
@@ -307,8 +476,12 @@ async fn process(
 
                 // Handle some extra errors specially
                 match e {
-                    LinesCodecError::MaxLineLengthExceeded => {}
-                    LinesCodecError::Io(e) => {
+                    ImapCodecError::MaxLineLengthExceeded => {
+                        peer.lines
+                            .send(String::from("* BAD Line too long\r"))
+                            .await?;
+                    }
+                    ImapCodecError::Io(e) => {
                         match e.kind() {
                             ConnectionReset => {
                                 error!("connection reset");
@@ -331,9 +504,31 @@ async fn process(
             Ok(Message::Response(msg)) => {
                 peer.lines.send(msg).await?;
             }
+            Ok(Message::ContinuationRequest) => {
+                peer.lines
+                    .send(String::from("+ Ready for literal\r"))
+                    .await?;
+            }
+            Ok(Message::LiteralTooLarge { tag, synchronizing }) => {
+                // A refused synchronizing literal was never sent, a
+                // non-synchronizing one that is too large is a protocol
+                // error (RFC 7888 section 4)
+                let result = if synchronizing { "NO" } else { "BAD" };
+                let response = format!(
+                    "{} {} [TOOBIG] Literal exceeds the maximum size\r",
+                    tag.as_deref().unwrap_or("*"),
+                    result
+                );
+                peer.lines.send(response).await?;
+            }
         }
     }
 
+    // Lets TLS clients know that the server is done sending
+    if let Err(e) = peer.lines.get_mut().shutdown().await {
+        debug!("Unable to shut down the connection to {}: {}", addr, e);
+    }
+
     // If this section is reached it means that the client was disconnected!
     {
         let mut state = state.lock().await;
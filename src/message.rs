@@ -0,0 +1,528 @@
+//! MIME structure of stored messages as needed for FETCH.
+//!
+//! The raw bytes are kept around for every part because sections like
+//! `BODY[1.MIME]` have to be returned exactly as stored. `mailparse` is used
+//! for the structured header values (content types, addresses, dates).
+
+use mailparse::{
+    addrparse, parse_content_disposition, parse_content_type, parse_header, MailAddr, SingleInfo,
+};
+
+use crate::parser::{Section, SectionText};
+
+/// How deep multiparts and encapsulated messages are taken apart.
+///
+/// Parts below this are shown as opaque bodies so a message can't nest deep
+/// enough to overflow the stack.
+pub(crate) const MAX_DEPTH: usize = 64;
+
+/// A single MIME part. The message itself is the root part.
+#[derive(Debug)]
+pub(crate) struct Part<'a> {
+    /// The header block including the empty line that ends it
+    pub header: &'a [u8],
+    pub body: &'a [u8],
+    /// Header names with their unfolded values
+    fields: Vec<(String, String)>,
+    mime_type: String,
+    params: Vec<(String, String)>,
+    /// The subparts of a multipart or the encapsulated message of a message/rfc822 part
+    pub children: Vec<Part<'a>>,
+}
+
+/// Formats `value` as quoted string or as literal if it can't be quoted.
+fn string(value: &str) -> String {
+    if value.contains('\r') || value.contains('\n') {
+        format!("{{{}}}\r\n{}", value.len(), value)
+    } else {
+        crate::commands::quote(value)
+    }
+}
+
+fn nstring(value: Option<&str>) -> String {
+    match value {
+        Some(value) => string(value),
+        None => "NIL".to_string(),
+    }
+}
+
+/// Splits `raw` into lines that keep their line endings.
+fn lines(raw: &[u8]) -> impl Iterator<Item = &[u8]> {
+    raw.split_inclusive(|c| *c == b'\n')
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Returns the length of the header block including the empty line.
+fn header_length(raw: &[u8]) -> usize {
+    let mut length = 0;
+    for line in lines(raw) {
+        length += line.len();
+        if trim_line_end(line).is_empty() {
+            return length;
+        }
+    }
+    raw.len()
+}
+
+/// Groups the header block into fields. Each field keeps its continuation lines.
+fn header_fields(header: &[u8]) -> Vec<&[u8]> {
+    let mut fields: Vec<&[u8]> = Vec::new();
+    let mut start = 0;
+    let mut position = 0;
+    for line in lines(header) {
+        let continuation = matches!(line.first(), Some(b' ') | Some(b'\t'));
+        if !continuation && position > start {
+            fields.push(&header[start..position]);
+            start = position;
+        }
+        if trim_line_end(line).is_empty() {
+            break;
+        }
+        position += line.len();
+    }
+    if position > start {
+        fields.push(&header[start..position]);
+    }
+    fields
+}
+
+fn field_name(field: &[u8]) -> Option<String> {
+    let colon = field.iter().position(|c| *c == b':')?;
+    Some(String::from_utf8_lossy(&field[..colon]).trim().to_string())
+}
+
+fn unfold(value: &[u8]) -> String {
+    let value = String::from_utf8_lossy(value);
+    let mut unfolded = String::with_capacity(value.len());
+    for line in value.split('\n') {
+        unfolded.push_str(line.trim_end_matches('\r'));
+    }
+    unfolded.trim().to_string()
+}
+
+impl<'a> Part<'a> {
+    pub fn parse(raw: &'a [u8]) -> Part<'a> {
+        Part::parse_with_default(raw, "text/plain", 0)
+    }
+
+    /// Parses a part. `default_type` is used if there is no Content-Type (message/rfc822 in multipart/digest).
+    ///
+    /// `depth` counts the multiparts and messages around the part.
+    fn parse_with_default(raw: &'a [u8], default_type: &str, depth: usize) -> Part<'a> {
+        let split = header_length(raw);
+        let header = &raw[..split];
+        let body = &raw[split..];
+
+        let fields: Vec<(String, String)> = header_fields(header)
+            .into_iter()
+            .filter_map(|field| {
+                let name = field_name(field)?;
+                let colon = field.iter().position(|c| *c == b':')?;
+                Some((name, unfold(&field[colon + 1..])))
+            })
+            .collect();
+
+        let content_type = fields
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| parse_content_type(value));
+        let (mime_type, params) = match content_type {
+            Some(content_type) if content_type.mimetype.contains('/') => (
+                content_type.mimetype,
+                content_type.params.into_iter().collect(),
+            ),
+            _ if default_type == "text/plain" => (
+                default_type.to_string(),
+                vec![("charset".to_string(), "us-ascii".to_string())],
+            ),
+            _ => (default_type.to_string(), Vec::new()),
+        };
+
+        // Too deeply nested containers are not split any further
+        let (mime_type, params) = if depth >= MAX_DEPTH
+            && (mime_type.starts_with("multipart/") || mime_type == "message/rfc822")
+        {
+            ("application/octet-stream".to_string(), Vec::new())
+        } else {
+            (mime_type, params)
+        };
+
+        let mut part = Part {
+            header,
+            body,
+            fields,
+            mime_type,
+            params,
+            children: Vec::new(),
+        };
+
+        if part.is_multipart() {
+            let default = if part.mime_type == "multipart/digest" {
+                "message/rfc822"
+            } else {
+                "text/plain"
+            };
+            if let Some(boundary) = part.param("boundary") {
+                part.children = split_multipart(body, boundary)
+                    .into_iter()
+                    .map(|raw| Part::parse_with_default(raw, default, depth + 1))
+                    .collect();
+            }
+        } else if part.mime_type == "message/rfc822" {
+            part.children = vec![Part::parse_with_default(body, "text/plain", depth + 1)];
+        }
+
+        part
+    }
+
+    fn is_multipart(&self) -> bool {
+        self.mime_type.starts_with("multipart/")
+    }
+
+    fn is_message(&self) -> bool {
+        self.mime_type == "message/rfc822"
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The unfolded value of the first header field called `name`.
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn media_type(&self) -> (&str, &str) {
+        let mut split = self.mime_type.splitn(2, '/');
+        let main = split.next().unwrap_or("text");
+        let sub = split.next().unwrap_or("plain");
+        (main, sub)
+    }
+
+    /// Only the header fields whose names are (not) in `names`, followed by an empty line.
+    fn header_fields(&self, names: &[String], not: bool) -> Vec<u8> {
+        let mut selected = Vec::new();
+        for field in header_fields(self.header) {
+            let name = field_name(field).unwrap_or_default();
+            let listed = names
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(&name));
+            if listed != not {
+                selected.extend_from_slice(field);
+            }
+        }
+        selected.extend_from_slice(b"\r\n");
+        selected
+    }
+
+    fn params_list(params: &[(String, String)]) -> String {
+        if params.is_empty() {
+            return "NIL".to_string();
+        }
+        let params: Vec<String> = params
+            .iter()
+            .map(|(key, value)| format!("{} {}", string(key), string(value)))
+            .collect();
+        format!("({})", params.join(" "))
+    }
+
+    fn disposition(&self) -> String {
+        match self.header_value("Content-Disposition") {
+            Some(value) => {
+                let disposition = parse_content_disposition(value);
+                let kind = value.split(';').next().unwrap_or("").trim();
+                let params: Vec<(String, String)> = disposition.params.into_iter().collect();
+                format!("({} {})", string(kind), Part::params_list(&params))
+            }
+            None => "NIL".to_string(),
+        }
+    }
+
+    /// The ENVELOPE structure of a message (RFC 3501 section 7.4.2).
+    ///
+    /// The names in addresses keep their encoded-words unless `utf8` is set,
+    /// which a client asks for by enabling UTF8=ACCEPT.
+    pub fn envelope(&self, utf8: bool) -> String {
+        let from = self.address_list("From", utf8);
+        let sender = match self.address_list("Sender", utf8) {
+            list if list == "NIL" => from.clone(),
+            list => list,
+        };
+        let reply_to = match self.address_list("Reply-To", utf8) {
+            list if list == "NIL" => from.clone(),
+            list => list,
+        };
+
+        format!(
+            "({} {} {} {} {} {} {} {} {} {})",
+            nstring(self.header_value("Date")),
+            nstring(self.header_value("Subject")),
+            from,
+            sender,
+            reply_to,
+            self.address_list("To", utf8),
+            self.address_list("Cc", utf8),
+            self.address_list("Bcc", utf8),
+            nstring(self.header_value("In-Reply-To")),
+            nstring(self.header_value("Message-ID")),
+        )
+    }
+
+    fn address_list(&self, name: &str, utf8: bool) -> String {
+        let value = match self.header_value(name) {
+            Some(value) if !value.trim().is_empty() => value,
+            _ => return "NIL".to_string(),
+        };
+        let list = match addrparse(value) {
+            Ok(list) => list,
+            Err(_) => return "NIL".to_string(),
+        };
+
+        let mut addresses = String::new();
+        for addr in list.iter() {
+            match addr {
+                MailAddr::Single(info) => addresses.push_str(&address(info, utf8)),
+                MailAddr::Group(group) => {
+                    addresses.push_str(&format!(
+                        "(NIL NIL {} NIL)",
+                        string(&display_name(&group.group_name, utf8))
+                    ));
+                    for info in &group.addrs {
+                        addresses.push_str(&address(info, utf8));
+                    }
+                    addresses.push_str("(NIL NIL NIL NIL)");
+                }
+            }
+        }
+
+        if addresses.is_empty() {
+            "NIL".to_string()
+        } else {
+            format!("({})", addresses)
+        }
+    }
+
+    /// The BODY (`extensible` false) or BODYSTRUCTURE (`extensible` true) of this part.
+    ///
+    /// `utf8` is passed on to the envelopes of encapsulated messages.
+    pub fn body_structure(&self, extensible: bool, utf8: bool) -> String {
+        let (main, sub) = self.media_type();
+
+        if self.is_multipart() {
+            let children: String = if self.children.is_empty() {
+                // A multipart without parts is shown as empty text part
+                "(\"text\" \"plain\" NIL NIL NIL \"7bit\" 0 0)".to_string()
+            } else {
+                self.children
+                    .iter()
+                    .map(|child| child.body_structure(extensible, utf8))
+                    .collect()
+            };
+            let mut structure = format!("({} {}", children, string(sub));
+            if extensible {
+                structure.push_str(&format!(
+                    " {} {} {} {}",
+                    Part::params_list(&self.params),
+                    self.disposition(),
+                    nstring(self.header_value("Content-Language")),
+                    nstring(self.header_value("Content-Location")),
+                ));
+            }
+            structure.push(')');
+            return structure;
+        }
+
+        let encoding = self
+            .header_value("Content-Transfer-Encoding")
+            .unwrap_or("7bit");
+        let mut structure = format!(
+            "({} {} {} {} {} {} {}",
+            string(main),
+            string(sub),
+            Part::params_list(&self.params),
+            nstring(self.header_value("Content-ID")),
+            nstring(self.header_value("Content-Description")),
+            string(encoding),
+            self.body.len(),
+        );
+
+        // An unterminated last line counts as well
+        let mut line_count = self.body.iter().filter(|c| **c == b'\n').count();
+        if !self.body.is_empty() && !self.body.ends_with(b"\n") {
+            line_count += 1;
+        }
+        if self.is_message() {
+            if let Some(message) = self.children.first() {
+                structure.push_str(&format!(
+                    " {} {} {}",
+                    message.envelope(utf8),
+                    message.body_structure(extensible, utf8),
+                    line_count
+                ));
+            }
+        } else if main.eq_ignore_ascii_case("text") {
+            structure.push_str(&format!(" {}", line_count));
+        }
+
+        if extensible {
+            structure.push_str(&format!(
+                " {} {} {} {}",
+                nstring(self.header_value("Content-MD5")),
+                self.disposition(),
+                nstring(self.header_value("Content-Language")),
+                nstring(self.header_value("Content-Location")),
+            ));
+        }
+        structure.push(')');
+        structure
+    }
+
+    /// Finds the part addressed by a section part number like `1.2`.
+    fn find(&self, numbers: &[u32]) -> Option<&Part<'a>> {
+        let mut current = self;
+        for (index, number) in numbers.iter().enumerate() {
+            let number = *number as usize;
+            // Parts of an encapsulated message are numbered like those of the top level message
+            let container = match current.children.first() {
+                Some(message) if index > 0 && current.is_message() => message,
+                _ => current,
+            };
+            current = if container.is_multipart() {
+                container.children.get(number - 1)?
+            } else if number == 1 {
+                container
+            } else {
+                return None;
+            };
+        }
+        Some(current)
+    }
+
+    /// The content of a `BODY[section]`. Sections that don't exist are empty.
+    pub fn section(&self, section: &Section) -> Vec<u8> {
+        let part = match self.find(&section.part) {
+            Some(part) => part,
+            None => return Vec::new(),
+        };
+
+        // HEADER and TEXT of a part refer to the message encapsulated in it
+        let message = if section.part.is_empty() {
+            Some(self)
+        } else if part.is_message() {
+            part.children.first()
+        } else {
+            None
+        };
+
+        match (&section.text, message) {
+            (None, _) if section.part.is_empty() => [self.header, self.body].concat(),
+            (None, _) => part.body.to_vec(),
+            (Some(SectionText::Mime), _) => part.header.to_vec(),
+            (Some(SectionText::Header), Some(message)) => message.header.to_vec(),
+            (Some(SectionText::HeaderFields(names)), Some(message)) => {
+                message.header_fields(names, false)
+            }
+            (Some(SectionText::HeaderFieldsNot(names)), Some(message)) => {
+                message.header_fields(names, true)
+            }
+            (Some(SectionText::Text), Some(message)) => message.body.to_vec(),
+            (Some(_), None) => Vec::new(),
+        }
+    }
+}
+
+/// Decodes the encoded-words (RFC 2047) of a name if the client accepts UTF-8.
+fn display_name(name: &str, utf8: bool) -> String {
+    if !utf8 {
+        return name.to_string();
+    }
+    match parse_header(format!("Name: {}", name).as_bytes()) {
+        Ok((header, _)) => header.get_value(),
+        Err(_) => name.to_string(),
+    }
+}
+
+fn address(info: &SingleInfo, utf8: bool) -> String {
+    let (mailbox, host) = match info.addr.rfind('@') {
+        Some(at) => (&info.addr[..at], Some(&info.addr[at + 1..])),
+        None => (info.addr.as_str(), None),
+    };
+    let name = info
+        .display_name
+        .as_ref()
+        .map(|name| display_name(name, utf8));
+    format!(
+        "({} NIL {} {})",
+        nstring(name.as_deref()),
+        string(mailbox),
+        nstring(host)
+    )
+}
+
+/// Splits the body of a multipart into the raw subparts.
+///
+/// The line break before a boundary line belongs to the boundary.
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut position = 0;
+
+    for line in lines(body) {
+        let content = trim_line_end(line);
+        if content.starts_with(delimiter.as_bytes()) {
+            let rest = &content[delimiter.len()..];
+            let is_end = rest.starts_with(b"--");
+            if rest.iter().all(u8::is_ascii_whitespace) || is_end {
+                if let Some(start) = start {
+                    let mut end = position;
+                    if body[..end].ends_with(b"\r\n") {
+                        end -= 2;
+                    } else if body[..end].ends_with(b"\n") {
+                        end -= 1;
+                    }
+                    parts.push(&body[start..end.max(start)]);
+                }
+                if is_end {
+                    return parts;
+                }
+                start = Some(position + line.len());
+            }
+        }
+        position += line.len();
+    }
+
+    // A missing closing delimiter ends the last part at the end of the body
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// The name of a section as used in the FETCH response, e.g. `1.HEADER.FIELDS (From)`.
+pub(crate) fn section_name(section: &Section) -> String {
+    let mut parts: Vec<String> = section.part.iter().map(u32::to_string).collect();
+    match &section.text {
+        Some(SectionText::Header) => parts.push("HEADER".to_string()),
+        Some(SectionText::Text) => parts.push("TEXT".to_string()),
+        Some(SectionText::Mime) => parts.push("MIME".to_string()),
+        Some(SectionText::HeaderFields(names)) => {
+            parts.push(format!("HEADER.FIELDS ({})", names.join(" ")))
+        }
+        Some(SectionText::HeaderFieldsNot(names)) => {
+            parts.push(format!("HEADER.FIELDS.NOT ({})", names.join(" ")))
+        }
+        None => {}
+    }
+    parts.join(".")
+}
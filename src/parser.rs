@@ -0,0 +1,1333 @@
+//! Parser for client commands following the grammar in RFC 3501 section 9.
+//!
+//! The parser works on a complete command frame (everything up to, but not
+//! including, the final CRLF). Literals are expected to be inlined in the
+//! frame as `{N}\r\n` followed by exactly `N` bytes.
+
+use std::fmt;
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+
+/// A single element of a sequence set. `Largest` represents `*`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SeqNumber {
+    Value(u32),
+    Largest,
+}
+
+/// A `sequence-set` made of single numbers and ranges.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SequenceSet(pub Vec<(SeqNumber, SeqNumber)>);
+
+impl SequenceSet {
+    /// Checks if `value` is part of the set. `largest` is the value `*` stands for.
+    pub fn contains(&self, value: u32, largest: u32) -> bool {
+        let resolve = |number: SeqNumber| match number {
+            SeqNumber::Value(value) => value,
+            SeqNumber::Largest => largest,
+        };
+        self.0.iter().any(|(start, end)| {
+            let (start, end) = (resolve(*start), resolve(*end));
+            start.min(end) <= value && value <= start.max(end)
+        })
+    }
+
+    /// The biggest number that was given explicitly, ignoring `*`.
+    pub fn highest_value(&self) -> Option<u32> {
+        self.0
+            .iter()
+            .flat_map(|(start, end)| vec![*start, *end])
+            .filter_map(|number| match number {
+                SeqNumber::Value(value) => Some(value),
+                SeqNumber::Largest => None,
+            })
+            .max()
+    }
+}
+
+/// The textual part of a section specifier.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SectionText {
+    Header,
+    HeaderFields(Vec<String>),
+    HeaderFieldsNot(Vec<String>),
+    Text,
+    Mime,
+}
+
+/// A section specifier like `1.2.HEADER.FIELDS (From)`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Section {
+    pub part: Vec<u32>,
+    pub text: Option<SectionText>,
+}
+
+/// A single `fetch-att`. The macros ALL, FAST and FULL get expanded while parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum FetchAttribute {
+    Envelope,
+    Flags,
+    InternalDate,
+    Rfc822,
+    Rfc822Header,
+    Rfc822Size,
+    Rfc822Text,
+    Body,
+    BodyStructure,
+    Uid,
+    ModSeq,
+    BodySection {
+        peek: bool,
+        section: Section,
+        partial: Option<(u32, u32)>,
+    },
+}
+
+/// The modifiers of FETCH from RFC 7162.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct FetchModifiers {
+    /// Only messages with a higher mod-sequence are returned
+    pub changed_since: Option<u64>,
+    /// Reports expunged UIDs of the set with VANISHED (EARLIER)
+    pub vanished: bool,
+}
+
+/// The QRESYNC parameter of SELECT and EXAMINE (RFC 7162 section 3.2.5).
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Qresync {
+    pub uid_validity: u32,
+    pub modseq: u64,
+    pub known_uids: Option<SequenceSet>,
+    /// Sequence numbers and the UIDs the client knows them as
+    pub known_sequence: Option<(SequenceSet, SequenceSet)>,
+}
+
+/// The optional parameters of SELECT and EXAMINE.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SelectParameters {
+    pub condstore: bool,
+    pub qresync: Option<Qresync>,
+}
+
+/// How STORE changes the flags of a message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StoreOperation {
+    Replace,
+    Add,
+    Remove,
+}
+
+/// The flag change requested by STORE.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StoreAction {
+    pub operation: StoreOperation,
+    /// Set for `FLAGS.SILENT` which suppresses the untagged FETCH responses
+    pub silent: bool,
+    pub flags: Vec<String>,
+    /// Messages changed after this mod-sequence are left alone (RFC 7162)
+    pub unchanged_since: Option<u64>,
+}
+
+/// A single `search-key`. Multiple keys in a row are combined with `And`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SearchKey {
+    All,
+    Answered,
+    Deleted,
+    Draft,
+    Flagged,
+    New,
+    Old,
+    Recent,
+    Seen,
+    Unanswered,
+    Undeleted,
+    Undraft,
+    Unflagged,
+    Unseen,
+    Keyword(String),
+    Unkeyword(String),
+    Bcc(String),
+    Cc(String),
+    From(String),
+    Subject(String),
+    To(String),
+    Header(String, String),
+    Body(String),
+    Text(String),
+    Before(NaiveDate),
+    On(NaiveDate),
+    Since(NaiveDate),
+    SentBefore(NaiveDate),
+    SentOn(NaiveDate),
+    SentSince(NaiveDate),
+    Larger(u32),
+    Smaller(u32),
+    SequenceSet(SequenceSet),
+    Uid(SequenceSet),
+    /// Messages with at least this mod-sequence. Entry names are ignored.
+    ModSeq(u64),
+    Not(Box<SearchKey>),
+    Or(Box<SearchKey>, Box<SearchKey>),
+    And(Vec<SearchKey>),
+}
+
+/// A parsed command without its tag.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Command {
+    Capability,
+    Noop,
+    Logout,
+    StartTls,
+    Login {
+        user: String,
+        password: String,
+    },
+    Authenticate {
+        mechanism: String,
+        /// The SASL-IR initial response (RFC 4959), still base64 encoded
+        initial_response: Option<String>,
+    },
+    Select {
+        mailbox: String,
+        parameters: SelectParameters,
+    },
+    Examine {
+        mailbox: String,
+        parameters: SelectParameters,
+    },
+    Create {
+        mailbox: String,
+    },
+    Delete {
+        mailbox: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Subscribe {
+        mailbox: String,
+    },
+    Unsubscribe {
+        mailbox: String,
+    },
+    List {
+        selection: Vec<String>,
+        reference: String,
+        patterns: Vec<String>,
+        return_options: Vec<String>,
+    },
+    Lsub {
+        reference: String,
+        pattern: String,
+    },
+    Status {
+        mailbox: String,
+        items: Vec<String>,
+    },
+    Namespace,
+    Id {
+        parameters: Option<IdParameters>,
+    },
+    Enable {
+        capabilities: Vec<String>,
+    },
+    Fetch {
+        sequence_set: SequenceSet,
+        attributes: Vec<FetchAttribute>,
+        modifiers: FetchModifiers,
+        uid: bool,
+    },
+    Append {
+        mailbox: String,
+        flags: Vec<String>,
+        date: Option<DateTime<FixedOffset>>,
+        message: Vec<u8>,
+    },
+    Store {
+        sequence_set: SequenceSet,
+        action: StoreAction,
+        uid: bool,
+    },
+    Search {
+        charset: Option<String>,
+        criteria: SearchKey,
+        uid: bool,
+    },
+    /// `uids` is set for UID EXPUNGE which only removes the given messages
+    Expunge {
+        uids: Option<SequenceSet>,
+    },
+    Copy {
+        sequence_set: SequenceSet,
+        mailbox: String,
+        uid: bool,
+    },
+    Move {
+        sequence_set: SequenceSet,
+        mailbox: String,
+        uid: bool,
+    },
+    Close,
+    Unselect,
+    Idle,
+}
+
+impl Command {
+    /// The name of the command as used in responses like `OK SELECT completed`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Capability => "CAPABILITY",
+            Command::Noop => "NOOP",
+            Command::Logout => "LOGOUT",
+            Command::StartTls => "STARTTLS",
+            Command::Login { .. } => "LOGIN",
+            Command::Authenticate { .. } => "AUTHENTICATE",
+            Command::Select { .. } => "SELECT",
+            Command::Examine { .. } => "EXAMINE",
+            Command::Create { .. } => "CREATE",
+            Command::Delete { .. } => "DELETE",
+            Command::Rename { .. } => "RENAME",
+            Command::Subscribe { .. } => "SUBSCRIBE",
+            Command::Unsubscribe { .. } => "UNSUBSCRIBE",
+            Command::List { .. } => "LIST",
+            Command::Lsub { .. } => "LSUB",
+            Command::Status { .. } => "STATUS",
+            Command::Namespace => "NAMESPACE",
+            Command::Id { .. } => "ID",
+            Command::Enable { .. } => "ENABLE",
+            Command::Fetch { .. } => "FETCH",
+            Command::Append { .. } => "APPEND",
+            Command::Store { .. } => "STORE",
+            Command::Search { .. } => "SEARCH",
+            Command::Expunge { .. } => "EXPUNGE",
+            Command::Copy { .. } => "COPY",
+            Command::Move { .. } => "MOVE",
+            Command::Close => "CLOSE",
+            Command::Unselect => "UNSELECT",
+            Command::Idle => "IDLE",
+        }
+    }
+
+    /// The mailbox names and patterns the command refers to.
+    pub fn mailbox_names(&self) -> Vec<&str> {
+        match self {
+            Command::Select { mailbox, .. }
+            | Command::Examine { mailbox, .. }
+            | Command::Create { mailbox }
+            | Command::Delete { mailbox }
+            | Command::Subscribe { mailbox }
+            | Command::Unsubscribe { mailbox }
+            | Command::Status { mailbox, .. }
+            | Command::Append { mailbox, .. }
+            | Command::Copy { mailbox, .. }
+            | Command::Move { mailbox, .. } => vec![mailbox],
+            Command::Rename { from, to } => vec![from, to],
+            Command::List {
+                reference,
+                patterns,
+                ..
+            } => std::iter::once(reference)
+                .chain(patterns)
+                .map(String::as_str)
+                .collect(),
+            Command::Lsub { reference, pattern } => vec![reference, pattern],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A complete tagged command as sent by the client.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Request {
+    pub tag: String,
+    pub command: Command,
+}
+
+/// Returned if a frame does not match the grammar.
+///
+/// `tag` is set if the tag could be parsed so that a tagged `BAD` can be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ParseError {
+    pub tag: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+type PResult<T> = Result<T, String>;
+
+/// The field/value pairs sent with the ID command.
+pub(crate) type IdParameters = Vec<(String, Option<String>)>;
+
+/// Parses a complete command frame.
+pub(crate) fn parse_command(input: &[u8]) -> Result<Request, ParseError> {
+    let mut parser = Parser::new(input);
+
+    let tag = parser
+        .tag()
+        .map_err(|message| ParseError { tag: None, message })?;
+
+    let command = parser
+        .sp()
+        .and_then(|_| parser.command())
+        .and_then(|command| parser.end().map(|_| command))
+        .map_err(|message| ParseError {
+            tag: Some(tag.clone()),
+            message,
+        })?;
+
+    Ok(Request { tag, command })
+}
+
+fn is_atom_char(c: u8) -> bool {
+    !matches!(
+        c,
+        b'(' | b')' | b'{' | b' ' | b'%' | b'*' | b'"' | b'\\' | b']' | 0x00..=0x1f | 0x7f..=0xff
+    )
+}
+
+fn is_astring_char(c: u8) -> bool {
+    is_atom_char(c) || c == b']'
+}
+
+fn is_list_char(c: u8) -> bool {
+    is_atom_char(c) || c == b'%' || c == b'*' || c == b']'
+}
+
+fn is_tag_char(c: u8) -> bool {
+    is_astring_char(c) && c != b'+'
+}
+
+/// Characters of base64, including "=" which alone is an empty initial response.
+fn is_base64_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'+' || c == b'/' || c == b'='
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Parser { input, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> PResult<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at position {}", c as char, self.pos))
+        }
+    }
+
+    fn sp(&mut self) -> PResult<()> {
+        self.expect(b' ')
+    }
+
+    fn end(&self) -> PResult<()> {
+        if self.pos == self.input.len() {
+            Ok(())
+        } else {
+            Err(format!("unexpected data at position {}", self.pos))
+        }
+    }
+
+    fn take_while<F>(&mut self, f: F) -> &'a [u8]
+    where
+        F: Fn(u8) -> bool,
+    {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !f(c) {
+                break;
+            }
+            self.pos += 1;
+        }
+        &self.input[start..self.pos]
+    }
+
+    fn take_while1<F>(&mut self, f: F, what: &str) -> PResult<&'a [u8]>
+    where
+        F: Fn(u8) -> bool,
+    {
+        let taken = self.take_while(f);
+        if taken.is_empty() {
+            Err(format!("expected {} at position {}", what, self.pos))
+        } else {
+            Ok(taken)
+        }
+    }
+
+    /// Consumes `keyword` case-insensitively if it is next in the input.
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let end = self.pos + keyword.len();
+        if end <= self.input.len()
+            && self.input[self.pos..end].eq_ignore_ascii_case(keyword.as_bytes())
+        {
+            self.pos = end;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn tag(&mut self) -> PResult<String> {
+        let tag = self.take_while1(is_tag_char, "tag")?;
+        Ok(String::from_utf8_lossy(tag).into_owned())
+    }
+
+    fn atom(&mut self) -> PResult<String> {
+        let atom = self.take_while1(is_atom_char, "atom")?;
+        Ok(String::from_utf8_lossy(atom).into_owned())
+    }
+
+    fn number(&mut self) -> PResult<u32> {
+        let digits = self.take_while1(|c| c.is_ascii_digit(), "number")?;
+        std::str::from_utf8(digits)
+            .expect("digits are valid utf8")
+            .parse()
+            .map_err(|_| "number too large".to_string())
+    }
+
+    /// A `mod-sequence-value`, which is bigger than other numbers.
+    fn mod_sequence(&mut self) -> PResult<u64> {
+        let digits = self.take_while1(|c| c.is_ascii_digit(), "mod-sequence")?;
+        std::str::from_utf8(digits)
+            .expect("digits are valid utf8")
+            .parse()
+            .ok()
+            .filter(|value| *value <= i64::MAX as u64)
+            .ok_or_else(|| "mod-sequence too large".to_string())
+    }
+
+    fn nz_number(&mut self) -> PResult<u32> {
+        match self.number()? {
+            0 => Err("number must not be zero".to_string()),
+            n => Ok(n),
+        }
+    }
+
+    fn quoted(&mut self) -> PResult<Vec<u8>> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c) if c == b'"' || c == b'\\' => {
+                            out.push(c);
+                            self.pos += 1;
+                        }
+                        _ => return Err("invalid escape in quoted string".to_string()),
+                    }
+                }
+                Some(b'\r') | Some(b'\n') | None => {
+                    return Err("unterminated quoted string".to_string());
+                }
+                Some(c) => {
+                    out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn literal(&mut self) -> PResult<Vec<u8>> {
+        self.expect(b'{')?;
+        let length = self.number()? as usize;
+        // LITERAL+ / LITERAL- non-synchronizing literal
+        self.eat(b'+');
+        self.expect(b'}')?;
+        self.expect(b'\r')?;
+        self.expect(b'\n')?;
+
+        let end = self.pos + length;
+        if end > self.input.len() {
+            return Err("literal is shorter than announced".to_string());
+        }
+        let data = self.input[self.pos..end].to_vec();
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn string(&mut self) -> PResult<Vec<u8>> {
+        match self.peek() {
+            Some(b'"') => self.quoted(),
+            Some(b'{') => self.literal(),
+            _ => Err(format!("expected string at position {}", self.pos)),
+        }
+    }
+
+    fn utf8(bytes: Vec<u8>) -> PResult<String> {
+        String::from_utf8(bytes).map_err(|_| "invalid UTF-8 in string".to_string())
+    }
+
+    fn astring(&mut self) -> PResult<String> {
+        match self.peek() {
+            Some(b'"') | Some(b'{') => {
+                let bytes = self.string()?;
+                Self::utf8(bytes)
+            }
+            _ => {
+                let atom = self.take_while1(is_astring_char, "astring")?;
+                Ok(String::from_utf8_lossy(atom).into_owned())
+            }
+        }
+    }
+
+    fn nstring(&mut self) -> PResult<Option<String>> {
+        if self.eat_keyword("NIL") {
+            return Ok(None);
+        }
+        let bytes = self.string()?;
+        Ok(Some(Self::utf8(bytes)?))
+    }
+
+    /// A mailbox name. `INBOX` is case-insensitive and always normalized.
+    fn mailbox(&mut self) -> PResult<String> {
+        let name = self.astring()?;
+        if name.eq_ignore_ascii_case("INBOX") {
+            Ok("INBOX".to_string())
+        } else {
+            Ok(name)
+        }
+    }
+
+    fn list_mailbox(&mut self) -> PResult<String> {
+        match self.peek() {
+            Some(b'"') | Some(b'{') => {
+                let bytes = self.string()?;
+                Self::utf8(bytes)
+            }
+            _ => {
+                let pattern = self.take_while1(is_list_char, "list-mailbox")?;
+                Ok(String::from_utf8_lossy(pattern).into_owned())
+            }
+        }
+    }
+
+    /// A parenthesized, space separated list of elements parsed by `element`.
+    fn list<T, F>(&mut self, mut element: F) -> PResult<Vec<T>>
+    where
+        F: FnMut(&mut Self) -> PResult<T>,
+    {
+        self.expect(b'(')?;
+        let mut elements = Vec::new();
+        if self.eat(b')') {
+            return Ok(elements);
+        }
+        loop {
+            elements.push(element(self)?);
+            if self.eat(b')') {
+                return Ok(elements);
+            }
+            self.sp()?;
+        }
+    }
+
+    fn seq_number(&mut self) -> PResult<SeqNumber> {
+        if self.eat(b'*') {
+            Ok(SeqNumber::Largest)
+        } else {
+            Ok(SeqNumber::Value(self.nz_number()?))
+        }
+    }
+
+    fn sequence_set(&mut self) -> PResult<SequenceSet> {
+        let mut ranges = Vec::new();
+        loop {
+            let start = self.seq_number()?;
+            let end = if self.eat(b':') {
+                self.seq_number()?
+            } else {
+                start
+            };
+            ranges.push((start, end));
+            if !self.eat(b',') {
+                return Ok(SequenceSet(ranges));
+            }
+        }
+    }
+
+    /// A system flag like `\\Seen` or a keyword.
+    fn flag(&mut self) -> PResult<String> {
+        if self.eat(b'\\') {
+            return Ok(format!("\\{}", self.atom()?));
+        }
+        self.atom()
+    }
+
+    fn flag_list(&mut self) -> PResult<Vec<String>> {
+        self.list(|p| p.flag())
+    }
+
+    /// A quoted `date-time` like `"17-Jul-1996 02:44:25 -0700"`.
+    fn date_time(&mut self) -> PResult<DateTime<FixedOffset>> {
+        let value = Self::utf8(self.quoted()?)?;
+        DateTime::parse_from_str(value.trim_start(), "%d-%b-%Y %H:%M:%S %z")
+            .map_err(|_| format!("invalid date-time {}", value))
+    }
+
+    /// A search `date` like `1-Feb-1994`, optionally quoted.
+    fn date(&mut self) -> PResult<NaiveDate> {
+        let quoted = self.eat(b'"');
+        let value = self.take_while1(|c| c.is_ascii_alphanumeric() || c == b'-', "date")?;
+        let value = String::from_utf8_lossy(value).into_owned();
+        if quoted {
+            self.expect(b'"')?;
+        }
+        NaiveDate::parse_from_str(&value, "%d-%b-%Y").map_err(|_| format!("invalid date {}", value))
+    }
+
+    fn header_list(&mut self) -> PResult<Vec<String>> {
+        self.list(|p| p.astring())
+    }
+
+    fn section_text(&mut self) -> PResult<SectionText> {
+        if self.eat_keyword("HEADER.FIELDS.NOT") {
+            self.sp()?;
+            Ok(SectionText::HeaderFieldsNot(self.header_list()?))
+        } else if self.eat_keyword("HEADER.FIELDS") {
+            self.sp()?;
+            Ok(SectionText::HeaderFields(self.header_list()?))
+        } else if self.eat_keyword("HEADER") {
+            Ok(SectionText::Header)
+        } else if self.eat_keyword("TEXT") {
+            Ok(SectionText::Text)
+        } else if self.eat_keyword("MIME") {
+            Ok(SectionText::Mime)
+        } else {
+            Err(format!("invalid section text at position {}", self.pos))
+        }
+    }
+
+    fn section(&mut self) -> PResult<Section> {
+        self.expect(b'[')?;
+        let mut section = Section::default();
+
+        if self.eat(b']') {
+            return Ok(section);
+        }
+
+        if matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            loop {
+                section.part.push(self.nz_number()?);
+                if !self.eat(b'.') {
+                    break;
+                }
+                if !matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+                    section.text = Some(self.section_text()?);
+                    break;
+                }
+            }
+        } else {
+            let text = self.section_text()?;
+            if text == SectionText::Mime {
+                return Err("MIME requires a part number".to_string());
+            }
+            section.text = Some(text);
+        }
+
+        self.expect(b']')?;
+        Ok(section)
+    }
+
+    fn partial(&mut self) -> PResult<Option<(u32, u32)>> {
+        if !self.eat(b'<') {
+            return Ok(None);
+        }
+        let start = self.number()?;
+        self.expect(b'.')?;
+        let length = self.nz_number()?;
+        self.expect(b'>')?;
+        Ok(Some((start, length)))
+    }
+
+    fn fetch_att(&mut self) -> PResult<FetchAttribute> {
+        let name = self.take_while1(
+            |c| c.is_ascii_alphanumeric() || c == b'.',
+            "fetch attribute",
+        )?;
+        let name = String::from_utf8_lossy(name).to_uppercase();
+
+        match name.as_str() {
+            "ENVELOPE" => Ok(FetchAttribute::Envelope),
+            "FLAGS" => Ok(FetchAttribute::Flags),
+            "INTERNALDATE" => Ok(FetchAttribute::InternalDate),
+            "RFC822" => Ok(FetchAttribute::Rfc822),
+            "RFC822.HEADER" => Ok(FetchAttribute::Rfc822Header),
+            "RFC822.SIZE" => Ok(FetchAttribute::Rfc822Size),
+            "RFC822.TEXT" => Ok(FetchAttribute::Rfc822Text),
+            "BODYSTRUCTURE" => Ok(FetchAttribute::BodyStructure),
+            "UID" => Ok(FetchAttribute::Uid),
+            "MODSEQ" => Ok(FetchAttribute::ModSeq),
+            "BODY" | "BODY.PEEK" => {
+                if self.peek() != Some(b'[') {
+                    if name == "BODY" {
+                        return Ok(FetchAttribute::Body);
+                    }
+                    return Err("BODY.PEEK requires a section".to_string());
+                }
+                let section = self.section()?;
+                let partial = self.partial()?;
+                Ok(FetchAttribute::BodySection {
+                    peek: name == "BODY.PEEK",
+                    section,
+                    partial,
+                })
+            }
+            _ => Err(format!("unknown fetch attribute {}", name)),
+        }
+    }
+
+    fn fetch_attributes(&mut self) -> PResult<Vec<FetchAttribute>> {
+        use FetchAttribute::*;
+
+        if self.peek() == Some(b'(') {
+            return self.list(|p| p.fetch_att());
+        }
+        if self.eat_keyword("ALL") {
+            return Ok(vec![Flags, InternalDate, Rfc822Size, Envelope]);
+        }
+        if self.eat_keyword("FAST") {
+            return Ok(vec![Flags, InternalDate, Rfc822Size]);
+        }
+        if self.eat_keyword("FULL") {
+            return Ok(vec![Flags, InternalDate, Rfc822Size, Envelope, Body]);
+        }
+        Ok(vec![self.fetch_att()?])
+    }
+
+    fn list_command(&mut self) -> PResult<Command> {
+        let selection = if self.peek() == Some(b'(') {
+            let selection = self.list(|p| p.atom().map(|a| a.to_uppercase()))?;
+            self.sp()?;
+            selection
+        } else {
+            Vec::new()
+        };
+
+        let reference = self.mailbox()?;
+        self.sp()?;
+
+        let patterns = if self.peek() == Some(b'(') {
+            self.list(|p| p.list_mailbox())?
+        } else {
+            vec![self.list_mailbox()?]
+        };
+
+        let mut return_options = Vec::new();
+        if self.peek() == Some(b' ') {
+            self.sp()?;
+            if !self.eat_keyword("RETURN") {
+                return Err("expected RETURN".to_string());
+            }
+            self.sp()?;
+            return_options = self.list(|p| p.atom().map(|a| a.to_uppercase()))?;
+        }
+
+        Ok(Command::List {
+            selection,
+            reference,
+            patterns,
+            return_options,
+        })
+    }
+
+    fn id_parameters(&mut self) -> PResult<Option<IdParameters>> {
+        if self.eat_keyword("NIL") {
+            return Ok(None);
+        }
+        self.expect(b'(')?;
+        let mut parameters = Vec::new();
+        if self.eat(b')') {
+            return Ok(Some(parameters));
+        }
+        loop {
+            let key = Self::utf8(self.string()?)?;
+            self.sp()?;
+            let value = self.nstring()?;
+            parameters.push((key, value));
+            if self.eat(b')') {
+                return Ok(Some(parameters));
+            }
+            self.sp()?;
+        }
+    }
+
+    fn select_parameter(&mut self, parameters: &mut SelectParameters) -> PResult<()> {
+        let name = self.atom()?.to_uppercase();
+        match name.as_str() {
+            "CONDSTORE" => parameters.condstore = true,
+            "QRESYNC" => {
+                self.sp()?;
+                self.expect(b'(')?;
+                let uid_validity = self.nz_number()?;
+                self.sp()?;
+                let modseq = self.mod_sequence()?;
+
+                let mut known_uids = None;
+                let mut known_sequence = None;
+                if self.eat(b' ') {
+                    if self.peek() != Some(b'(') {
+                        known_uids = Some(self.sequence_set()?);
+                        self.eat(b' ');
+                    }
+                    if self.eat(b'(') {
+                        let numbers = self.sequence_set()?;
+                        self.sp()?;
+                        let uids = self.sequence_set()?;
+                        self.expect(b')')?;
+                        known_sequence = Some((numbers, uids));
+                    }
+                }
+                self.expect(b')')?;
+
+                parameters.qresync = Some(Qresync {
+                    uid_validity,
+                    modseq,
+                    known_uids,
+                    known_sequence,
+                });
+            }
+            _ => return Err(format!("unknown select parameter {}", name)),
+        }
+        Ok(())
+    }
+
+    fn fetch_modifier(&mut self, modifiers: &mut FetchModifiers) -> PResult<()> {
+        let name = self.atom()?.to_uppercase();
+        match name.as_str() {
+            "CHANGEDSINCE" => {
+                self.sp()?;
+                modifiers.changed_since = Some(self.mod_sequence()?);
+            }
+            "VANISHED" => modifiers.vanished = true,
+            _ => return Err(format!("unknown fetch modifier {}", name)),
+        }
+        Ok(())
+    }
+
+    fn fetch_command(&mut self, uid: bool) -> PResult<Command> {
+        self.sp()?;
+        let sequence_set = self.sequence_set()?;
+        self.sp()?;
+        let attributes = self.fetch_attributes()?;
+
+        let mut modifiers = FetchModifiers::default();
+        if self.eat(b' ') {
+            self.list(|p| p.fetch_modifier(&mut modifiers))?;
+        }
+        if modifiers.vanished && (!uid || modifiers.changed_since.is_none()) {
+            return Err("VANISHED requires UID FETCH with CHANGEDSINCE".to_string());
+        }
+
+        Ok(Command::Fetch {
+            sequence_set,
+            attributes,
+            modifiers,
+            uid,
+        })
+    }
+
+    fn store_command(&mut self, uid: bool) -> PResult<Command> {
+        self.sp()?;
+        let sequence_set = self.sequence_set()?;
+        self.sp()?;
+
+        let mut unchanged_since = None;
+        if self.eat(b'(') {
+            if !self.eat_keyword("UNCHANGEDSINCE ") {
+                return Err("expected UNCHANGEDSINCE".to_string());
+            }
+            unchanged_since = Some(self.mod_sequence()?);
+            self.expect(b')')?;
+            self.sp()?;
+        }
+
+        let operation = if self.eat(b'+') {
+            StoreOperation::Add
+        } else if self.eat(b'-') {
+            StoreOperation::Remove
+        } else {
+            StoreOperation::Replace
+        };
+        if !self.eat_keyword("FLAGS") {
+            return Err("expected FLAGS".to_string());
+        }
+        let silent = self.eat_keyword(".SILENT");
+        self.sp()?;
+
+        // The flags may be given with or without parentheses
+        let flags = if self.peek() == Some(b'(') {
+            self.flag_list()?
+        } else {
+            let mut flags = vec![self.flag()?];
+            while self.eat(b' ') {
+                flags.push(self.flag()?);
+            }
+            flags
+        };
+
+        Ok(Command::Store {
+            sequence_set,
+            action: StoreAction {
+                operation,
+                silent,
+                flags,
+                unchanged_since,
+            },
+            uid,
+        })
+    }
+
+    fn search_key(&mut self) -> PResult<SearchKey> {
+        use SearchKey::*;
+
+        match self.peek() {
+            Some(b'(') => {
+                let mut keys = self.list(|p| p.search_key())?;
+                if keys.is_empty() {
+                    return Err("empty search key list".to_string());
+                }
+                if keys.len() == 1 {
+                    return Ok(keys.remove(0));
+                }
+                return Ok(And(keys));
+            }
+            Some(c) if c.is_ascii_digit() || c == b'*' => {
+                return Ok(SequenceSet(self.sequence_set()?));
+            }
+            _ => {}
+        }
+
+        let name = self.atom()?.to_uppercase();
+        let key = match name.as_str() {
+            "ALL" => All,
+            "ANSWERED" => Answered,
+            "DELETED" => Deleted,
+            "DRAFT" => Draft,
+            "FLAGGED" => Flagged,
+            "NEW" => New,
+            "OLD" => Old,
+            "RECENT" => Recent,
+            "SEEN" => Seen,
+            "UNANSWERED" => Unanswered,
+            "UNDELETED" => Undeleted,
+            "UNDRAFT" => Undraft,
+            "UNFLAGGED" => Unflagged,
+            "UNSEEN" => Unseen,
+            "KEYWORD" | "UNKEYWORD" => {
+                self.sp()?;
+                let keyword = self.atom()?;
+                if name == "KEYWORD" {
+                    Keyword(keyword)
+                } else {
+                    Unkeyword(keyword)
+                }
+            }
+            "BCC" | "CC" | "FROM" | "SUBJECT" | "TO" | "BODY" | "TEXT" => {
+                self.sp()?;
+                let value = self.astring()?;
+                match name.as_str() {
+                    "BCC" => Bcc(value),
+                    "CC" => Cc(value),
+                    "FROM" => From(value),
+                    "SUBJECT" => Subject(value),
+                    "TO" => To(value),
+                    "BODY" => Body(value),
+                    _ => Text(value),
+                }
+            }
+            "HEADER" => {
+                self.sp()?;
+                let field = self.astring()?;
+                self.sp()?;
+                Header(field, self.astring()?)
+            }
+            "BEFORE" | "ON" | "SINCE" | "SENTBEFORE" | "SENTON" | "SENTSINCE" => {
+                self.sp()?;
+                let date = self.date()?;
+                match name.as_str() {
+                    "BEFORE" => Before(date),
+                    "ON" => On(date),
+                    "SINCE" => Since(date),
+                    "SENTBEFORE" => SentBefore(date),
+                    "SENTON" => SentOn(date),
+                    _ => SentSince(date),
+                }
+            }
+            "LARGER" => {
+                self.sp()?;
+                Larger(self.number()?)
+            }
+            "SMALLER" => {
+                self.sp()?;
+                Smaller(self.number()?)
+            }
+            "UID" => {
+                self.sp()?;
+                Uid(self.sequence_set()?)
+            }
+            "MODSEQ" => {
+                self.sp()?;
+                // An optional entry name and type like "/flags/\\draft" all
+                if self.peek() == Some(b'"') {
+                    self.quoted()?;
+                    self.sp()?;
+                    let kind = self.atom()?.to_lowercase();
+                    if !matches!(kind.as_str(), "priv" | "shared" | "all") {
+                        return Err(format!("invalid entry type {}", kind));
+                    }
+                    self.sp()?;
+                }
+                ModSeq(self.mod_sequence()?)
+            }
+            "NOT" => {
+                self.sp()?;
+                Not(Box::new(self.search_key()?))
+            }
+            "OR" => {
+                self.sp()?;
+                let first = self.search_key()?;
+                self.sp()?;
+                Or(Box::new(first), Box::new(self.search_key()?))
+            }
+            _ => return Err(format!("unknown search key {}", name)),
+        };
+        Ok(key)
+    }
+
+    fn search_command(&mut self, uid: bool) -> PResult<Command> {
+        self.sp()?;
+        let mut charset = None;
+        if self.eat_keyword("CHARSET ") {
+            charset = Some(self.astring()?.to_uppercase());
+            self.sp()?;
+        }
+
+        let mut keys = vec![self.search_key()?];
+        while self.eat(b' ') {
+            keys.push(self.search_key()?);
+        }
+        let criteria = if keys.len() == 1 {
+            keys.remove(0)
+        } else {
+            SearchKey::And(keys)
+        };
+
+        Ok(Command::Search {
+            charset,
+            criteria,
+            uid,
+        })
+    }
+
+    fn copy_command(&mut self, moving: bool, uid: bool) -> PResult<Command> {
+        self.sp()?;
+        let sequence_set = self.sequence_set()?;
+        self.sp()?;
+        let mailbox = self.mailbox()?;
+        if moving {
+            Ok(Command::Move {
+                sequence_set,
+                mailbox,
+                uid,
+            })
+        } else {
+            Ok(Command::Copy {
+                sequence_set,
+                mailbox,
+                uid,
+            })
+        }
+    }
+
+    fn append_command(&mut self) -> PResult<Command> {
+        self.sp()?;
+        let mailbox = self.mailbox()?;
+        self.sp()?;
+
+        let mut flags = Vec::new();
+        if self.peek() == Some(b'(') {
+            flags = self.flag_list()?;
+            self.sp()?;
+        }
+
+        let mut date = None;
+        if self.peek() == Some(b'"') {
+            date = Some(self.date_time()?);
+            self.sp()?;
+        }
+
+        // The UTF8 data extension (RFC 6855) sends the message as `UTF8 (~{N}...)`
+        let message = if self.eat_keyword("UTF8 (") {
+            self.expect(b'~')?;
+            let message = self.literal()?;
+            self.expect(b')')?;
+            message
+        } else {
+            self.literal()?
+        };
+        Ok(Command::Append {
+            mailbox,
+            flags,
+            date,
+            message,
+        })
+    }
+
+    fn command(&mut self) -> PResult<Command> {
+        let name = self.atom()?.to_uppercase();
+
+        match name.as_str() {
+            "CAPABILITY" => Ok(Command::Capability),
+            "NOOP" => Ok(Command::Noop),
+            "LOGOUT" => Ok(Command::Logout),
+            "NAMESPACE" => Ok(Command::Namespace),
+            "EXPUNGE" => Ok(Command::Expunge { uids: None }),
+            "CLOSE" => Ok(Command::Close),
+            "UNSELECT" => Ok(Command::Unselect),
+            "IDLE" => Ok(Command::Idle),
+            "STARTTLS" => Ok(Command::StartTls),
+            "LOGIN" => {
+                self.sp()?;
+                let user = self.astring()?;
+                self.sp()?;
+                let password = self.astring()?;
+                Ok(Command::Login { user, password })
+            }
+            "AUTHENTICATE" => {
+                self.sp()?;
+                let mechanism = self.atom()?.to_uppercase();
+                let initial_response = if self.eat(b' ') {
+                    let response = self.take_while1(is_base64_char, "initial response")?;
+                    Some(String::from_utf8_lossy(response).into_owned())
+                } else {
+                    None
+                };
+                Ok(Command::Authenticate {
+                    mechanism,
+                    initial_response,
+                })
+            }
+            "SELECT" | "EXAMINE" => {
+                self.sp()?;
+                let mailbox = self.mailbox()?;
+                let mut parameters = SelectParameters::default();
+                if self.eat(b' ') {
+                    self.list(|p| p.select_parameter(&mut parameters))?;
+                }
+                if name == "SELECT" {
+                    Ok(Command::Select {
+                        mailbox,
+                        parameters,
+                    })
+                } else {
+                    Ok(Command::Examine {
+                        mailbox,
+                        parameters,
+                    })
+                }
+            }
+            "CREATE" | "DELETE" | "SUBSCRIBE" | "UNSUBSCRIBE" => {
+                self.sp()?;
+                let mailbox = self.mailbox()?;
+                Ok(match name.as_str() {
+                    "DELETE" => Command::Delete { mailbox },
+                    "SUBSCRIBE" => Command::Subscribe { mailbox },
+                    "UNSUBSCRIBE" => Command::Unsubscribe { mailbox },
+                    _ => Command::Create { mailbox },
+                })
+            }
+            "RENAME" => {
+                self.sp()?;
+                let from = self.mailbox()?;
+                self.sp()?;
+                let to = self.mailbox()?;
+                Ok(Command::Rename { from, to })
+            }
+            "LIST" => {
+                self.sp()?;
+                self.list_command()
+            }
+            "LSUB" => {
+                self.sp()?;
+                let reference = self.mailbox()?;
+                self.sp()?;
+                let pattern = self.list_mailbox()?;
+                Ok(Command::Lsub { reference, pattern })
+            }
+            "STATUS" => {
+                self.sp()?;
+                let mailbox = self.mailbox()?;
+                self.sp()?;
+                let items = self.list(|p| p.atom().map(|a| a.to_uppercase()))?;
+                Ok(Command::Status { mailbox, items })
+            }
+            "ID" => {
+                self.sp()?;
+                let parameters = self.id_parameters()?;
+                Ok(Command::Id { parameters })
+            }
+            "ENABLE" => {
+                let mut capabilities = Vec::new();
+                while self.peek() == Some(b' ') {
+                    self.sp()?;
+                    capabilities.push(self.atom()?.to_uppercase());
+                }
+                if capabilities.is_empty() {
+                    return Err("ENABLE requires at least one capability".to_string());
+                }
+                Ok(Command::Enable { capabilities })
+            }
+            "FETCH" => self.fetch_command(false),
+            "APPEND" => self.append_command(),
+            "STORE" => self.store_command(false),
+            "SEARCH" => self.search_command(false),
+            "COPY" | "MOVE" => self.copy_command(name == "MOVE", false),
+            "UID" => {
+                self.sp()?;
+                let name = self.atom()?.to_uppercase();
+                match name.as_str() {
+                    "FETCH" => self.fetch_command(true),
+                    "STORE" => self.store_command(true),
+                    "SEARCH" => self.search_command(true),
+                    "COPY" | "MOVE" => self.copy_command(name == "MOVE", true),
+                    "EXPUNGE" => {
+                        self.sp()?;
+                        let uids = Some(self.sequence_set()?);
+                        Ok(Command::Expunge { uids })
+                    }
+                    _ => Err(format!("unknown UID command {}", name)),
+                }
+            }
+            _ => Err(format!("unknown command {}", name)),
+        }
+    }
+}
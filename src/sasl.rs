@@ -0,0 +1,137 @@
+//! SASL mechanisms for AUTHENTICATE (RFC 4422).
+//!
+//! The exchange around them (base64, cancelling, the continuation lines) is
+//! run by `commands::authenticate`, a mechanism only sees decoded responses.
+
+use std::fmt;
+
+/// What the client proved to be once a mechanism finished.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Credentials {
+    /// The identity the client wants to act as, if it differs from `user`
+    pub authzid: Option<String>,
+    pub user: String,
+    pub password: String,
+}
+
+/// The outcome of a single client response.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Step {
+    /// Send this challenge and wait for the next response
+    Challenge(Vec<u8>),
+    /// The exchange is over and the credentials can be checked
+    Done(Credentials),
+}
+
+/// A SASL mechanism in the middle of an exchange.
+///
+/// An `Err` means the response does not follow the mechanism and ends the
+/// exchange with `BAD`.
+pub(crate) trait Mechanism: Send + fmt::Debug {
+    /// The first challenge if the client sent no initial response.
+    fn initial_challenge(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn step(&mut self, response: &[u8]) -> Result<Step, String>;
+}
+
+/// PLAIN as described in RFC 4616.
+#[derive(Debug, Default)]
+struct Plain;
+
+impl Mechanism for Plain {
+    fn step(&mut self, response: &[u8]) -> Result<Step, String> {
+        let message =
+            std::str::from_utf8(response).map_err(|_| "PLAIN message is not UTF-8".to_string())?;
+
+        // [authzid] NUL authcid NUL passwd
+        let parts: Vec<&str> = message.split('\u{0000}').collect();
+        match parts[..] {
+            [authzid, user, password] if !user.is_empty() && !password.is_empty() => {
+                Ok(Step::Done(Credentials {
+                    authzid: Some(authzid)
+                        .filter(|authzid| !authzid.is_empty())
+                        .map(str::to_string),
+                    user: user.to_string(),
+                    password: password.to_string(),
+                }))
+            }
+            _ => Err("PLAIN message needs an authentication identity and a password".to_string()),
+        }
+    }
+}
+
+/// The obsolete LOGIN mechanism, still used by some clients.
+///
+/// The user name and password are asked for one after another.
+#[derive(Debug, Default)]
+struct Login {
+    user: Option<String>,
+}
+
+impl Mechanism for Login {
+    fn initial_challenge(&self) -> Vec<u8> {
+        b"Username:".to_vec()
+    }
+
+    fn step(&mut self, response: &[u8]) -> Result<Step, String> {
+        let response =
+            std::str::from_utf8(response).map_err(|_| "LOGIN response is not UTF-8".to_string())?;
+
+        match self.user.take() {
+            None => {
+                self.user = Some(response.to_string());
+                Ok(Step::Challenge(b"Password:".to_vec()))
+            }
+            Some(user) => Ok(Step::Done(Credentials {
+                authzid: None,
+                user,
+                password: response.to_string(),
+            })),
+        }
+    }
+}
+
+/// A mechanism and how to start an exchange with it.
+pub(crate) struct Registered {
+    pub name: &'static str,
+    /// If the password is sent in plain text, which is only allowed where LOGIN is
+    pub plaintext: bool,
+    start: fn() -> Box<dyn Mechanism>,
+}
+
+impl Registered {
+    pub fn start(&self) -> Box<dyn Mechanism> {
+        (self.start)()
+    }
+}
+
+/// Every mechanism the server implements, advertised as `AUTH=<name>`.
+pub(crate) const MECHANISMS: [Registered; 2] = [
+    Registered {
+        name: "PLAIN",
+        plaintext: true,
+        start: || Box::new(Plain),
+    },
+    Registered {
+        name: "LOGIN",
+        plaintext: true,
+        start: || Box::new(Login::default()),
+    },
+];
+
+/// Looks up a mechanism by its name.
+pub(crate) fn registered(name: &str) -> Option<&'static Registered> {
+    MECHANISMS
+        .iter()
+        .find(|mechanism| mechanism.name.eq_ignore_ascii_case(name))
+}
+
+/// A running AUTHENTICATE command waiting for the next client response.
+#[derive(Debug)]
+pub(crate) struct Exchange {
+    /// The tag of the AUTHENTICATE command
+    pub tag: String,
+    pub mechanism: Box<dyn Mechanism>,
+}
@@ -0,0 +1,1479 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use bytes::BytesMut;
+use chrono::NaiveDate;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::codec::{Decoder, Encoder};
+
+use IMAPServer_shared::config::Config;
+use IMAPServer_shared::mailbox::{Mailbox, MessageInfo};
+use IMAPServer_shared::setup;
+
+use crate::codec::{ImapCodec, Input};
+use crate::commands::capability::{capabilities, Advertising};
+use crate::commands::copy::uid_set;
+use crate::commands::expunge::expunge_responses;
+use crate::commands::fetch::literal_item;
+use crate::commands::idle::changes;
+use crate::commands::select::folder_flags;
+use crate::commands::Commands;
+use crate::commands::{reports_changes, validate_mailbox_names, validate_state};
+use crate::message::{section_name, Part};
+use crate::parser::{
+    parse_command, Command, FetchAttribute, FetchModifiers, Qresync, SearchKey, Section,
+    SectionText, SelectParameters, SeqNumber, SequenceSet, StoreAction, StoreOperation,
+};
+use crate::sasl::{self, Credentials, Step};
+use crate::tls;
+use crate::{Connection, Extensions, Rx, Selected, Shared, State};
+
+#[test]
+fn parse_simple_commands() {
+    let request = parse_command(b"a1 CAPABILITY").expect("failed to parse");
+    assert_eq!(request.tag, "a1");
+    assert_eq!(request.command, Command::Capability);
+
+    let request = parse_command(b"a2 noop").expect("failed to parse");
+    assert_eq!(request.command, Command::Noop);
+
+    let request = parse_command(b"a3 IDLE").expect("failed to parse");
+    assert_eq!(request.command, Command::Idle);
+
+    let request = parse_command(b"a4 StartTLS").expect("failed to parse");
+    assert_eq!(request.command, Command::StartTls);
+
+    let request = parse_command(b"a5 LOGIN \"test@localhost\" secret").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Login {
+            user: "test@localhost".to_string(),
+            password: "secret".to_string(),
+        }
+    );
+
+    let request = parse_command(b"a6 authenticate plain").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Authenticate {
+            mechanism: "PLAIN".to_string(),
+            initial_response: None,
+        }
+    );
+
+    let request = parse_command(b"a7 AUTHENTICATE PLAIN AHRlc3RAbG9jYWxob3N0AHNlY3JldA==")
+        .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Authenticate {
+            mechanism: "PLAIN".to_string(),
+            initial_response: Some("AHRlc3RAbG9jYWxob3N0AHNlY3JldA==".to_string()),
+        }
+    );
+
+    let request = parse_command(b"a8 AUTHENTICATE PLAIN =").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Authenticate {
+            mechanism: "PLAIN".to_string(),
+            initial_response: Some("=".to_string()),
+        }
+    );
+
+    parse_command(b"a9 AUTHENTICATE PLAIN not-base64").expect_err("parsed invalid response");
+}
+
+#[test]
+fn parse_mailbox_names() {
+    let request = parse_command(b"a1 SELECT inbox").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Select {
+            mailbox: "INBOX".to_string(),
+            parameters: SelectParameters::default(),
+        }
+    );
+
+    let request = parse_command(b"a2 CREATE \"Old \\\"Mail\\\"\"").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Create {
+            mailbox: "Old \"Mail\"".to_string()
+        }
+    );
+
+    let request = parse_command(b"a3 EXAMINE {9}\r\nMy Folder").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Examine {
+            mailbox: "My Folder".to_string(),
+            parameters: SelectParameters::default(),
+        }
+    );
+}
+
+#[test]
+fn command_mailbox_names() {
+    let request = parse_command(b"a1 RENAME Archiv \"Entw\xc3\xbcrfe\"").expect("failed to parse");
+    assert_eq!(request.command.mailbox_names(), vec!["Archiv", "Entwürfe"]);
+
+    let request = parse_command(b"a2 LIST \"\" (INBOX Sent.%)").expect("failed to parse");
+    assert_eq!(request.command.mailbox_names(), vec!["", "INBOX", "Sent.%"]);
+
+    let request = parse_command(b"a3 NOOP").expect("failed to parse");
+    assert!(request.command.mailbox_names().is_empty());
+}
+
+#[test]
+fn command_states() {
+    // The result in the not authenticated, authenticated, selected and logout states
+    let check = |command: &[u8]| {
+        let request = parse_command(command).expect("failed to parse");
+        let selected = State::Selected(Selected {
+            folder: "INBOX".to_string(),
+            read_only: false,
+            uids: Vec::new(),
+            recent: Vec::new(),
+            flags: Default::default(),
+        });
+        let states = [
+            State::NotAuthenticated,
+            State::Authenticated,
+            selected,
+            State::Logout,
+        ];
+        states
+            .iter()
+            .map(|state| validate_state(&request.command, state))
+            .collect::<Vec<_>>()
+    };
+    let login_first = Err("NO Please Login first!");
+    let authenticated = Err("BAD Already authenticated");
+    let not_selected = Err("BAD No mailbox selected");
+    let logout = Err("BAD Connection is logging out");
+
+    assert_eq!(
+        check(b"a1 CAPABILITY"),
+        vec![Ok(()), Ok(()), Ok(()), logout]
+    );
+    assert_eq!(check(b"a2 NOOP"), vec![Ok(()), Ok(()), Ok(()), logout]);
+    assert_eq!(
+        check(b"a3 LOGIN user secret"),
+        vec![Ok(()), authenticated, authenticated, logout]
+    );
+    assert_eq!(
+        check(b"a4 STARTTLS"),
+        vec![Ok(()), authenticated, authenticated, logout]
+    );
+    assert_eq!(
+        check(b"a5 ENABLE CONDSTORE"),
+        vec![
+            login_first,
+            Ok(()),
+            Err("BAD ENABLE is not allowed in the selected state"),
+            logout
+        ]
+    );
+    assert_eq!(
+        check(b"a6 SELECT INBOX"),
+        vec![login_first, Ok(()), Ok(()), logout]
+    );
+    assert_eq!(
+        check(b"a7 STATUS INBOX (MESSAGES)"),
+        vec![login_first, Ok(()), Ok(()), logout]
+    );
+    assert_eq!(
+        check(b"a8 FETCH 1 FLAGS"),
+        vec![login_first, not_selected, Ok(()), logout]
+    );
+    assert_eq!(
+        check(b"a9 CLOSE"),
+        vec![login_first, not_selected, Ok(()), logout]
+    );
+}
+
+#[test]
+fn changes_wait_for_safe_commands() {
+    let reports = |command: &[u8]| {
+        let request = parse_command(command).expect("failed to parse");
+        reports_changes(&request.command)
+    };
+
+    assert!(reports(b"a1 NOOP"));
+    assert!(reports(b"a2 UID FETCH 1:* FLAGS"));
+    assert!(reports(b"a3 EXPUNGE"));
+    // Sequence numbers must keep their meaning while the command runs
+    assert!(!reports(b"a4 FETCH 1:* FLAGS"));
+    assert!(!reports(b"a5 STORE 2 +FLAGS (\\Seen)"));
+    assert!(!reports(b"a6 SEARCH 3:5"));
+    assert!(!reports(b"a7 COPY 1 Sent"));
+    assert!(!reports(b"a8 CLOSE"));
+}
+
+#[test]
+fn mailbox_name_checks() {
+    let valid = |command: &[u8], utf8: bool| {
+        let request = parse_command(command).expect("failed to parse");
+        validate_mailbox_names(&request.command, utf8)
+    };
+
+    assert_eq!(valid(b"a1 SELECT Archive.2019", false), Ok(()));
+    assert_eq!(valid(b"a2 LIST \"\" *", false), Ok(()));
+    assert_eq!(valid(b"a3 CREATE Sent.", false), Ok(()));
+    assert_eq!(valid(b"a4 LIST \"\" \"\"", false), Ok(()));
+
+    // Nothing may reach outside of the own mailbox root
+    for command in &[
+        &b"a4 DELETE \"/../victim@host\""[..],
+        b"a5 SELECT \"..\"",
+        b"a6 STATUS . (MESSAGES)",
+        b"a7 RENAME \"../victim@host/.Sent\" Stolen",
+        b"a8 COPY 1 \"Sent/../../victim@host\"",
+        b"a9 SELECT \"\"",
+        b"a10 APPEND \"\" {0}\r\n",
+    ] {
+        assert_eq!(
+            valid(command, true),
+            Err("NO [CANNOT] Invalid mailbox name"),
+            "{}",
+            String::from_utf8_lossy(command)
+        );
+    }
+
+    let entwuerfe = b"a11 SELECT \"Entw\xc3\xbcrfe\"";
+    assert!(valid(entwuerfe, false).is_err());
+    assert_eq!(valid(entwuerfe, true), Ok(()));
+}
+
+#[test]
+fn parse_list_extended() {
+    let request = parse_command(b"a1 LIST (SUBSCRIBED) \"\" (\"INBOX\" Sent.%) RETURN (CHILDREN)")
+        .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::List {
+            selection: vec!["SUBSCRIBED".to_string()],
+            reference: "".to_string(),
+            patterns: vec!["INBOX".to_string(), "Sent.%".to_string()],
+            return_options: vec!["CHILDREN".to_string()],
+        }
+    );
+}
+
+#[test]
+fn parse_fetch() {
+    let request =
+        parse_command(b"a1 UID FETCH 1:*,5 (FLAGS BODY.PEEK[1.2.HEADER.FIELDS (From To)]<0.100>)")
+            .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Fetch {
+            sequence_set: SequenceSet(vec![
+                (SeqNumber::Value(1), SeqNumber::Largest),
+                (SeqNumber::Value(5), SeqNumber::Value(5)),
+            ]),
+            attributes: vec![
+                FetchAttribute::Flags,
+                FetchAttribute::BodySection {
+                    peek: true,
+                    section: Section {
+                        part: vec![1, 2],
+                        text: Some(SectionText::HeaderFields(vec![
+                            "From".to_string(),
+                            "To".to_string()
+                        ])),
+                    },
+                    partial: Some((0, 100)),
+                },
+            ],
+            modifiers: FetchModifiers::default(),
+            uid: true,
+        }
+    );
+
+    let request = parse_command(b"a2 FETCH 2 FAST").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Fetch {
+            sequence_set: SequenceSet(vec![(SeqNumber::Value(2), SeqNumber::Value(2))]),
+            attributes: vec![
+                FetchAttribute::Flags,
+                FetchAttribute::InternalDate,
+                FetchAttribute::Rfc822Size
+            ],
+            modifiers: FetchModifiers::default(),
+            uid: false,
+        }
+    );
+}
+
+#[test]
+fn parse_append() {
+    let request = parse_command(
+        b"a1 APPEND Sent (\\Seen Junk) \" 7-Feb-1994 21:52:25 -0800\" {13}\r\nSubject: Hi\r\n",
+    )
+    .expect("failed to parse");
+    match request.command {
+        Command::Append {
+            mailbox,
+            flags,
+            date,
+            message,
+        } => {
+            assert_eq!(mailbox, "Sent");
+            assert_eq!(flags, vec!["\\Seen", "Junk"]);
+            assert_eq!(
+                date.expect("date is missing").to_rfc3339(),
+                "1994-02-07T21:52:25-08:00"
+            );
+            assert_eq!(message, b"Subject: Hi\r\n");
+        }
+        command => panic!("unexpected command {:?}", command),
+    }
+
+    let request = parse_command(b"a2 APPEND INBOX {0}\r\n").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Append {
+            mailbox: "INBOX".to_string(),
+            flags: Vec::new(),
+            date: None,
+            message: Vec::new(),
+        }
+    );
+
+    assert!(parse_command(b"a3 APPEND INBOX \"31-Foo-2020 00:00:00 +0000\" {0}\r\n").is_err());
+
+    let request = parse_command(b"a4 APPEND Sent (\\Seen) utf8 (~{14+}\r\nSubject: \xc3\xbcber)")
+        .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Append {
+            mailbox: "Sent".to_string(),
+            flags: vec!["\\Seen".to_string()],
+            date: None,
+            message: "Subject: über".as_bytes().to_vec(),
+        }
+    );
+    assert!(parse_command(b"a5 APPEND Sent UTF8 ({3}\r\nabc)").is_err());
+    assert!(parse_command(b"a6 APPEND Sent UTF8 (~{3}\r\nabc").is_err());
+}
+
+#[test]
+fn parse_store() {
+    let request = parse_command(b"a1 UID STORE 1:3 +FLAGS.SILENT (\\Deleted $Forwarded)")
+        .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Store {
+            sequence_set: SequenceSet(vec![(SeqNumber::Value(1), SeqNumber::Value(3))]),
+            action: StoreAction {
+                operation: StoreOperation::Add,
+                silent: true,
+                flags: vec!["\\Deleted".to_string(), "$Forwarded".to_string()],
+                unchanged_since: None,
+            },
+            uid: true,
+        }
+    );
+
+    let request = parse_command(b"a2 STORE * flags \\Seen Junk").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Store {
+            sequence_set: SequenceSet(vec![(SeqNumber::Largest, SeqNumber::Largest)]),
+            action: StoreAction {
+                operation: StoreOperation::Replace,
+                silent: false,
+                flags: vec!["\\Seen".to_string(), "Junk".to_string()],
+                unchanged_since: None,
+            },
+            uid: false,
+        }
+    );
+
+    assert!(parse_command(b"a3 STORE 1 FLAGS.LOUD (\\Seen)").is_err());
+}
+
+#[test]
+fn parse_condstore() {
+    let request =
+        parse_command(b"a1 SELECT INBOX (QRESYNC (67890007 20050715194045000 41,43:211,214:541))")
+            .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Select {
+            mailbox: "INBOX".to_string(),
+            parameters: SelectParameters {
+                condstore: false,
+                qresync: Some(Qresync {
+                    uid_validity: 67890007,
+                    modseq: 20050715194045000,
+                    known_uids: Some(SequenceSet(vec![
+                        (SeqNumber::Value(41), SeqNumber::Value(41)),
+                        (SeqNumber::Value(43), SeqNumber::Value(211)),
+                        (SeqNumber::Value(214), SeqNumber::Value(541)),
+                    ])),
+                    known_sequence: None,
+                }),
+            },
+        }
+    );
+
+    let request = parse_command(b"a2 UID FETCH 1:* FLAGS (CHANGEDSINCE 12345 VANISHED)")
+        .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Fetch {
+            sequence_set: SequenceSet(vec![(SeqNumber::Value(1), SeqNumber::Largest)]),
+            attributes: vec![FetchAttribute::Flags],
+            modifiers: FetchModifiers {
+                changed_since: Some(12345),
+                vanished: true,
+            },
+            uid: true,
+        }
+    );
+    // VANISHED needs UID FETCH and CHANGEDSINCE
+    assert!(parse_command(b"a3 FETCH 1:* FLAGS (CHANGEDSINCE 1 VANISHED)").is_err());
+    assert!(parse_command(b"a4 UID FETCH 1:* FLAGS (VANISHED)").is_err());
+
+    let request =
+        parse_command(b"a5 STORE 1 (UNCHANGEDSINCE 5) +FLAGS (\\Seen)").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Store {
+            sequence_set: SequenceSet(vec![(SeqNumber::Value(1), SeqNumber::Value(1))]),
+            action: StoreAction {
+                operation: StoreOperation::Add,
+                silent: false,
+                flags: vec!["\\Seen".to_string()],
+                unchanged_since: Some(5),
+            },
+            uid: false,
+        }
+    );
+
+    let request = parse_command(b"a6 SEARCH MODSEQ 5").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Search {
+            charset: None,
+            criteria: SearchKey::ModSeq(5),
+            uid: false,
+        }
+    );
+}
+
+#[test]
+fn parse_search() {
+    let request = parse_command(
+        b"a1 UID SEARCH CHARSET utf-8 OR (FROM \"alice\" UNSEEN) NOT SINCE 1-Feb-1994 2:* LARGER 100",
+    )
+    .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Search {
+            charset: Some("UTF-8".to_string()),
+            criteria: SearchKey::And(vec![
+                SearchKey::Or(
+                    Box::new(SearchKey::And(vec![
+                        SearchKey::From("alice".to_string()),
+                        SearchKey::Unseen,
+                    ])),
+                    Box::new(SearchKey::Not(Box::new(SearchKey::Since(
+                        NaiveDate::from_ymd(1994, 2, 1)
+                    )))),
+                ),
+                SearchKey::SequenceSet(SequenceSet(vec![(
+                    SeqNumber::Value(2),
+                    SeqNumber::Largest
+                )])),
+                SearchKey::Larger(100),
+            ]),
+            uid: true,
+        }
+    );
+
+    let request = parse_command(b"a2 SEARCH HEADER X-Mailer {4+}\r\nmutt SENTON \"17-Jul-1996\"")
+        .expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Search {
+            charset: None,
+            criteria: SearchKey::And(vec![
+                SearchKey::Header("X-Mailer".to_string(), "mutt".to_string()),
+                SearchKey::SentOn(NaiveDate::from_ymd(1996, 7, 17)),
+            ]),
+            uid: false,
+        }
+    );
+
+    assert!(parse_command(b"a3 SEARCH").is_err());
+    assert!(parse_command(b"a4 SEARCH BEFORE 31-Foo-2020").is_err());
+    assert!(parse_command(b"a5 SEARCH ()").is_err());
+}
+
+#[test]
+fn expunge_renumbering() {
+    let mut uids = vec![3, 5, 7, 9, 11];
+    let responses = expunge_responses(&mut uids, &[5, 7, 11, 12], false);
+    assert_eq!(responses, vec!["* 2 EXPUNGE", "* 2 EXPUNGE", "* 3 EXPUNGE"]);
+    assert_eq!(uids, vec![3, 9]);
+
+    // QRESYNC clients get the UIDs instead
+    let mut uids = vec![3, 5, 7, 9, 11];
+    let responses = expunge_responses(&mut uids, &[11, 5, 7, 12], true);
+    assert_eq!(responses, vec!["* VANISHED 5,7,11"]);
+    assert_eq!(uids, vec![3, 9]);
+}
+
+#[test]
+fn parse_copy_and_move() {
+    let request = parse_command(b"a1 UID MOVE 4:6 Archive").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Move {
+            sequence_set: SequenceSet(vec![(SeqNumber::Value(4), SeqNumber::Value(6))]),
+            mailbox: "Archive".to_string(),
+            uid: true,
+        }
+    );
+
+    let request = parse_command(b"a2 copy 2 inbox").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Copy {
+            sequence_set: SequenceSet(vec![(SeqNumber::Value(2), SeqNumber::Value(2))]),
+            mailbox: "INBOX".to_string(),
+            uid: false,
+        }
+    );
+
+    let request = parse_command(b"a3 UID EXPUNGE 3,5").expect("failed to parse");
+    assert_eq!(
+        request.command,
+        Command::Expunge {
+            uids: Some(SequenceSet(vec![
+                (SeqNumber::Value(3), SeqNumber::Value(3)),
+                (SeqNumber::Value(5), SeqNumber::Value(5)),
+            ])),
+        }
+    );
+    assert!(parse_command(b"a4 UID EXPUNGE").is_err());
+}
+
+#[test]
+fn uid_set_ranges() {
+    assert_eq!(uid_set(&[1, 2, 3, 5, 7, 8]), "1:3,5,7:8");
+    assert_eq!(uid_set(&[9]), "9");
+    assert_eq!(uid_set(&[]), "");
+}
+
+#[test]
+fn folder_keywords() {
+    let message = |flags: &[&str]| MessageInfo {
+        uid: 1,
+        flags: flags.iter().map(|flag| flag.to_string()).collect(),
+        size: 0,
+        internal_date: UNIX_EPOCH,
+        recent: false,
+        modseq: 1,
+    };
+    let messages = vec![
+        message(&["\\Seen", "Junk"]),
+        message(&[]),
+        message(&["$Forwarded", "Junk"]),
+    ];
+    assert_eq!(
+        folder_flags(&messages).join(" "),
+        "\\Answered \\Flagged \\Deleted \\Seen \\Draft $Forwarded Junk"
+    );
+}
+
+#[test]
+fn idle_changes() {
+    let message = |uid: u32, flags: &[&str], recent: bool| MessageInfo {
+        uid,
+        flags: flags.iter().map(|flag| flag.to_string()).collect(),
+        size: 0,
+        internal_date: UNIX_EPOCH,
+        recent,
+        modseq: u64::from(uid),
+    };
+    let before = vec![
+        message(1, &[], false),
+        message(2, &[], false),
+        message(3, &[], false),
+    ];
+    let mut selected = Selected {
+        folder: "INBOX".to_string(),
+        read_only: false,
+        uids: vec![1, 2, 3],
+        recent: Vec::new(),
+        flags: before
+            .into_iter()
+            .map(|message| (message.uid, message.flags))
+            .collect(),
+    };
+
+    // 2 was expunged, 3 got flagged and 4 was delivered
+    let after = vec![
+        message(1, &[], false),
+        message(3, &["\\Flagged"], false),
+        message(4, &[], true),
+    ];
+    let mut qresync_selected = selected.clone();
+    assert_eq!(
+        changes(&mut selected, &after, Extensions::default()),
+        vec![
+            "* 2 EXPUNGE",
+            "* 2 FETCH (FLAGS (\\Flagged))",
+            "* 3 EXISTS",
+            "* 1 RECENT"
+        ]
+    );
+    assert_eq!(selected.uids, vec![1, 3, 4]);
+    assert_eq!(selected.recent, vec![4]);
+
+    // Nothing is reported twice
+    assert!(changes(&mut selected, &after, Extensions::default()).is_empty());
+
+    let enabled = Extensions {
+        condstore: true,
+        qresync: true,
+        ..Extensions::default()
+    };
+    assert_eq!(
+        changes(&mut qresync_selected, &after, enabled),
+        vec![
+            "* VANISHED 2",
+            "* 2 FETCH (FLAGS (\\Flagged) MODSEQ (3))",
+            "* 3 EXISTS",
+            "* 1 RECENT"
+        ]
+    );
+}
+
+#[test]
+fn capability_registry() {
+    let mut config = Config {
+        shared_secret: String::new(),
+        mailbox_root: "./mailbox_root".to_string(),
+        max_literal_size: 1024,
+        literal_minus: false,
+        tls_certificate: None,
+        tls_key: None,
+        tls_required: false,
+        plaintext_login: false,
+    };
+    let greeting = Advertising {
+        tls: false,
+        authenticated: false,
+        starttls: false,
+    };
+    // Plain text passwords need TLS, for LOGIN and AUTHENTICATE alike
+    assert_eq!(
+        capabilities(&config, greeting),
+        "IMAP4rev1 LOGINDISABLED UTF8=ACCEPT NAMESPACE LIST-EXTENDED ID ENABLE UNSELECT UIDPLUS \
+         MOVE IDLE STATUS=SIZE CONDSTORE QRESYNC LITERAL+"
+    );
+
+    config.tls_certificate = Some("chain.pem".to_string());
+    config.tls_key = Some("key.pem".to_string());
+    config.tls_required = true;
+    config.literal_minus = true;
+    // STARTTLS depends on the acceptor built at startup, not on the config
+    assert!(!capabilities(&config, greeting).contains("STARTTLS"));
+    let greeting = Advertising {
+        starttls: true,
+        ..greeting
+    };
+    let plain = capabilities(&config, greeting);
+    assert!(plain.starts_with("IMAP4rev1 STARTTLS LOGINDISABLED UTF8=ACCEPT"));
+    assert!(plain.ends_with(" LITERAL-"));
+
+    let encrypted = capabilities(
+        &config,
+        Advertising {
+            tls: true,
+            authenticated: false,
+            starttls: true,
+        },
+    );
+    assert!(encrypted.starts_with("IMAP4rev1 AUTH=PLAIN AUTH=LOGIN SASL-IR UTF8=ACCEPT"));
+
+    // Nothing about logging in is left once the client did
+    let authenticated = capabilities(
+        &config,
+        Advertising {
+            tls: true,
+            authenticated: true,
+            starttls: true,
+        },
+    );
+    assert!(authenticated.starts_with("IMAP4rev1 UTF8=ACCEPT"));
+    // Only a part of IMAP4rev2 is there, so it can't be advertised yet
+    assert!(!authenticated.contains("IMAP4rev2"));
+
+    // Plain text passwords are still refused while TLS is required
+    config.plaintext_login = true;
+    let plain = capabilities(&config, greeting);
+    assert!(plain.contains(" LOGINDISABLED ") && !plain.contains("AUTH="));
+    config.tls_required = false;
+    let plain = capabilities(&config, greeting);
+    assert!(plain.starts_with("IMAP4rev1 STARTTLS AUTH=PLAIN AUTH=LOGIN SASL-IR UTF8=ACCEPT"));
+
+    // Every mechanism is advertised, and nothing else
+    let advertised: Vec<&str> = plain
+        .split(' ')
+        .filter_map(|name| name.strip_prefix("AUTH="))
+        .collect();
+    let mechanisms: Vec<&str> = sasl::MECHANISMS
+        .iter()
+        .map(|mechanism| mechanism.name)
+        .collect();
+    assert_eq!(advertised, mechanisms);
+}
+
+#[test]
+fn sasl_plain() {
+    let mut plain = sasl::registered("plain")
+        .expect("PLAIN is registered")
+        .start();
+    assert_eq!(plain.initial_challenge(), b"");
+    assert_eq!(
+        plain.step(b"\0test@localhost\0secret"),
+        Ok(Step::Done(Credentials {
+            authzid: None,
+            user: "test@localhost".to_string(),
+            password: "secret".to_string(),
+        }))
+    );
+    assert_eq!(
+        plain.step(b"admin@localhost\0test@localhost\0secret"),
+        Ok(Step::Done(Credentials {
+            authzid: Some("admin@localhost".to_string()),
+            user: "test@localhost".to_string(),
+            password: "secret".to_string(),
+        }))
+    );
+
+    assert!(plain.step(b"").is_err());
+    assert!(plain.step(b"test@localhost\0secret").is_err());
+    assert!(plain.step(b"\0test@localhost\0").is_err());
+    assert!(plain.step(b"\0a\0b\0c").is_err());
+    assert!(plain.step(b"\0test@localhost\0\xff").is_err());
+
+    assert!(sasl::registered("CRAM-MD5").is_none());
+}
+
+#[test]
+fn sasl_login() {
+    let mut login = sasl::registered("LOGIN")
+        .expect("LOGIN is registered")
+        .start();
+    assert_eq!(login.initial_challenge(), b"Username:");
+    assert_eq!(
+        login.step(b"test@localhost"),
+        Ok(Step::Challenge(b"Password:".to_vec()))
+    );
+    assert_eq!(
+        login.step(b"secret"),
+        Ok(Step::Done(Credentials {
+            authzid: None,
+            user: "test@localhost".to_string(),
+            password: "secret".to_string(),
+        }))
+    );
+}
+
+#[test]
+fn parse_errors_keep_tag() {
+    let error = parse_command(b"a1 FOO").expect_err("parsed unknown command");
+    assert_eq!(error.tag, Some("a1".to_string()));
+
+    let error = parse_command(b"a2 SELECT {10}\r\nshort").expect_err("parsed short literal");
+    assert_eq!(error.tag, Some("a2".to_string()));
+}
+
+fn decode_all(codec: &mut ImapCodec, input: &[u8]) -> Vec<Input> {
+    let mut buffer = BytesMut::from(input);
+    let mut decoded = Vec::new();
+    while let Some(item) = codec.decode(&mut buffer).expect("failed to decode") {
+        decoded.push(item);
+    }
+    decoded
+}
+
+#[test]
+fn codec_synchronizing_literal() {
+    let mut codec = ImapCodec::new(1024, false);
+
+    let decoded = decode_all(&mut codec, b"a1 APPEND INBOX {5}\r\n");
+    assert_eq!(decoded, vec![Input::ContinuationRequest]);
+
+    let decoded = decode_all(&mut codec, b"Hello\r\n");
+    assert_eq!(
+        decoded,
+        vec![Input::Command(b"a1 APPEND INBOX {5}\r\nHello".to_vec())]
+    );
+}
+
+#[test]
+fn codec_empty_synchronizing_literal() {
+    let mut codec = ImapCodec::new(1024, false);
+
+    let decoded = decode_all(&mut codec, b"a1 LOGIN {0}\r\n");
+    assert_eq!(decoded, vec![Input::ContinuationRequest]);
+
+    let decoded = decode_all(&mut codec, b" secret\r\n");
+    assert_eq!(
+        decoded,
+        vec![Input::Command(b"a1 LOGIN {0}\r\n secret".to_vec())]
+    );
+}
+
+#[test]
+fn codec_line_too_long() {
+    let mut codec = ImapCodec::new(1024, false);
+
+    // The line is skipped once, the command after it still arrives
+    let mut buffer = BytesMut::from(&[b'x'; 70 * 1024][..]);
+    assert!(codec.decode(&mut buffer).is_err());
+    assert!(buffer.is_empty());
+    buffer.extend_from_slice(b"xxxx\r\na1 NOOP\r\n");
+    assert_eq!(
+        codec.decode(&mut buffer).expect("failed to decode"),
+        Some(Input::Command(b"a1 NOOP".to_vec()))
+    );
+
+    let mut input = vec![b'x'; 70 * 1024];
+    input.extend_from_slice(b"\r\na2 NOOP\r\n");
+    let mut buffer = BytesMut::from(&input[..]);
+    assert!(codec.decode(&mut buffer).is_err());
+    assert_eq!(
+        codec.decode(&mut buffer).expect("failed to decode"),
+        Some(Input::Command(b"a2 NOOP".to_vec()))
+    );
+}
+
+#[test]
+fn codec_command_too_large() {
+    let mut codec = ImapCodec::new(1024, false);
+
+    // Every literal is small, but there is no end to them
+    let literal = format!("{} {{1000+}}\r\n{}", "x".repeat(1000), "y".repeat(1000));
+    let mut input = b"a1 APPEND INBOX".to_vec();
+    for _ in 0..100 {
+        input.extend_from_slice(literal.as_bytes());
+    }
+    input.extend_from_slice(b"\r\na2 NOOP\r\n");
+
+    let decoded = decode_all(&mut codec, &input);
+    assert_eq!(
+        decoded,
+        vec![
+            Input::LiteralTooLarge {
+                tag: Some("a1".to_string()),
+                synchronizing: false,
+            },
+            Input::Command(b"a2 NOOP".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn binary_literals() {
+    // Latin-1 and NUL bytes must arrive as they are stored
+    let body = b"Subject: caf\xe9\r\n\r\n\x00\xff\xfe";
+    let item = literal_item("BODY[]", body);
+    assert_eq!(&item[..13], b"BODY[] {20}\r\n");
+    assert_eq!(&item[13..], &body[..]);
+    assert_eq!(body.len(), 20);
+
+    let mut codec = ImapCodec::new(1024, false);
+    let mut buffer = BytesMut::new();
+    codec
+        .encode(item.clone(), &mut buffer)
+        .expect("failed to encode");
+    assert_eq!(&buffer[..item.len()], &item[..]);
+    assert_eq!(buffer[item.len()], b'\n');
+}
+
+#[test]
+fn codec_non_synchronizing_literal() {
+    let mut codec = ImapCodec::new(1024, false);
+
+    let decoded = decode_all(
+        &mut codec,
+        b"a1 LOGIN {4+}\r\nuser {4+}\r\npass\r\na2 NOOP\r\n",
+    );
+    assert_eq!(
+        decoded,
+        vec![
+            Input::Command(b"a1 LOGIN {4+}\r\nuser {4+}\r\npass".to_vec()),
+            Input::Command(b"a2 NOOP".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn codec_literal_too_large() {
+    let mut codec = ImapCodec::new(4, false);
+
+    let decoded = decode_all(&mut codec, b"a1 APPEND INBOX {5}\r\na2 NOOP\r\n");
+    assert_eq!(
+        decoded,
+        vec![
+            Input::LiteralTooLarge {
+                tag: Some("a1".to_string()),
+                synchronizing: true,
+            },
+            Input::Command(b"a2 NOOP".to_vec()),
+        ]
+    );
+
+    let decoded = decode_all(&mut codec, b"a3 APPEND INBOX {5+}\r\nHello\r\na4 NOOP\r\n");
+    assert_eq!(
+        decoded,
+        vec![
+            Input::LiteralTooLarge {
+                tag: Some("a3".to_string()),
+                synchronizing: false,
+            },
+            Input::Command(b"a4 NOOP".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn codec_literal_minus() {
+    let mut codec = ImapCodec::new(1024 * 1024, true);
+
+    let mut input = b"a1 APPEND INBOX {5000+}\r\n".to_vec();
+    input.extend_from_slice(&[b'x'; 5000]);
+    input.extend_from_slice(b"\r\n");
+
+    let decoded = decode_all(&mut codec, &input);
+    assert_eq!(
+        decoded,
+        vec![Input::LiteralTooLarge {
+            tag: Some("a1".to_string()),
+            synchronizing: false,
+        }]
+    );
+
+    // Only non-synchronizing literals are limited by LITERAL-
+    let decoded = decode_all(&mut codec, b"a2 APPEND INBOX {5000}\r\n");
+    assert_eq!(decoded, vec![Input::ContinuationRequest]);
+}
+
+const MULTIPART: &[u8] = b"From: Fred Foobar <foobar@Blurdybloop.example>\r\n\
+Subject: afternoon meeting\r\n\
+To: mooch@owatagu.example, Team: a@example.org;\r\n\
+Message-Id: <B27397-0100000@Blurdybloop.example>\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed;\r\n boundary=\"frontier\"\r\n\
+\r\n\
+Preamble\r\n\
+--frontier\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+\r\n\
+Hello Joe\r\n\
+--frontier\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+Subject: inner\r\n\
+\r\n\
+Inner body\r\n\
+--frontier--\r\n";
+
+#[test]
+fn message_envelope() {
+    let part = Part::parse(MULTIPART);
+    assert_eq!(
+        part.envelope(false),
+        "(NIL \"afternoon meeting\" ((\"Fred Foobar\" NIL \"foobar\" \"Blurdybloop.example\")) \
+         ((\"Fred Foobar\" NIL \"foobar\" \"Blurdybloop.example\")) \
+         ((\"Fred Foobar\" NIL \"foobar\" \"Blurdybloop.example\")) \
+         ((NIL NIL \"mooch\" \"owatagu.example\")(NIL NIL \"Team\" NIL)(NIL NIL \"a\" \"example.org\")(NIL NIL NIL NIL)) \
+         NIL NIL NIL \"<B27397-0100000@Blurdybloop.example>\")"
+    );
+}
+
+#[test]
+fn message_envelope_encoded_words() {
+    let part = Part::parse(
+        b"From: =?utf-8?q?J=C3=BCrgen?= <j@example.org>\r\n\
+          To: =?utf-8?b?w7xiZXI=?=: u@example.org;\r\n\r\n",
+    );
+    let envelope = part.envelope(false);
+    assert!(envelope.contains("((\"=?utf-8?q?J=C3=BCrgen?=\" NIL \"j\" \"example.org\"))"));
+    assert!(envelope.contains("((NIL NIL \"=?utf-8?b?w7xiZXI=?=\" NIL)"));
+    assert!(envelope.is_ascii());
+
+    let envelope = part.envelope(true);
+    assert!(envelope.contains("((\"Jürgen\" NIL \"j\" \"example.org\"))"));
+    assert!(envelope.contains("((NIL NIL \"über\" NIL)"));
+}
+
+#[test]
+fn message_body_structure() {
+    let part = Part::parse(MULTIPART);
+    assert_eq!(
+        part.body_structure(false, false),
+        "((\"text\" \"plain\" (\"charset\" \"utf-8\") NIL NIL \"7bit\" 9 1)\
+         (\"message\" \"rfc822\" NIL NIL NIL \"7bit\" 28 \
+         (NIL \"inner\" NIL NIL NIL NIL NIL NIL NIL NIL) \
+         (\"text\" \"plain\" (\"charset\" \"us-ascii\") NIL NIL \"7bit\" 10 1) 3) \"mixed\")"
+    );
+    assert!(part
+        .body_structure(true, false)
+        .ends_with("\"mixed\" (\"boundary\" \"frontier\") NIL NIL NIL)"));
+}
+
+#[test]
+fn message_sections() {
+    let part = Part::parse(MULTIPART);
+    let section = |part: Vec<u32>, text: Option<SectionText>| Section { part, text };
+
+    assert_eq!(part.section(&section(vec![], None)), MULTIPART);
+    assert_eq!(part.section(&section(vec![1], None)), b"Hello Joe");
+    assert_eq!(
+        part.section(&section(vec![1], Some(SectionText::Mime))),
+        b"Content-Type: text/plain; charset=utf-8\r\n\r\n"
+    );
+    assert_eq!(
+        part.section(&section(vec![2], Some(SectionText::Header))),
+        b"Subject: inner\r\n\r\n"
+    );
+    assert_eq!(
+        part.section(&section(vec![2], Some(SectionText::Text))),
+        b"Inner body"
+    );
+    assert_eq!(part.section(&section(vec![2, 1], None)), b"Inner body");
+    assert_eq!(part.section(&section(vec![3], None)), b"");
+
+    let fields = section(
+        vec![],
+        Some(SectionText::HeaderFields(vec![
+            "subject".to_string(),
+            "TO".to_string(),
+        ])),
+    );
+    assert_eq!(
+        part.section(&fields),
+        b"Subject: afternoon meeting\r\nTo: mooch@owatagu.example, Team: a@example.org;\r\n\r\n"
+    );
+    assert_eq!(section_name(&fields), "HEADER.FIELDS (subject TO)");
+
+    let not = section(
+        vec![],
+        Some(SectionText::HeaderFieldsNot(vec![
+            "Content-Type".to_string()
+        ])),
+    );
+    assert!(!String::from_utf8_lossy(&part.section(&not)).contains("boundary"));
+}
+
+#[test]
+fn message_deep_nesting() {
+    // Enough levels to overflow the stack if every one was parsed
+    let mut nested = b"Content-Type: message/rfc822\r\n\r\n".repeat(10_000);
+    nested.extend_from_slice(b"Subject: innermost\r\n\r\nBody\r\n");
+
+    let part = Part::parse(&nested);
+    let structure = part.body_structure(true, false);
+    assert_eq!(
+        structure.matches("\"message\" \"rfc822\"").count(),
+        crate::message::MAX_DEPTH
+    );
+    assert!(structure.contains("\"application\" \"octet-stream\""));
+    assert!(!structure.contains("innermost"));
+
+    let mut multipart = Vec::new();
+    for level in 0..10_000 {
+        multipart.extend_from_slice(
+            format!(
+                "Content-Type: multipart/mixed; boundary=\"b{}\"\r\n\r\n--b{}\r\n",
+                level, level
+            )
+            .as_bytes(),
+        );
+    }
+    let part = Part::parse(&multipart);
+    assert!(part
+        .body_structure(false, false)
+        .contains("\"application\" \"octet-stream\""));
+}
+
+#[test]
+fn sequence_set_contains() {
+    let request = parse_command(b"a1 FETCH 2,4:*,1:1 FLAGS").expect("failed to parse");
+    let sequence_set = match request.command {
+        Command::Fetch { sequence_set, .. } => sequence_set,
+        command => panic!("unexpected command {:?}", command),
+    };
+    let matching: Vec<u32> = (1..=6)
+        .filter(|number| sequence_set.contains(*number, 6))
+        .collect();
+    assert_eq!(matching, vec![1, 2, 4, 5, 6]);
+    assert_eq!(sequence_set.highest_value(), Some(4));
+
+    // n:* includes the last message even if n is bigger
+    assert!(sequence_set.contains(3, 3));
+}
+
+/// A logged in connection of a new user with its mailbox in an empty directory.
+async fn authenticated(name: &str) -> (Arc<Mutex<Shared>>, SocketAddr, Rx) {
+    setup();
+    let root = std::env::temp_dir().join(format!("imapserver-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let config = Config {
+        shared_secret: "secret".to_string(),
+        mailbox_root: root.to_string_lossy().into_owned(),
+        max_literal_size: 1024,
+        literal_minus: false,
+        tls_certificate: None,
+        tls_key: None,
+        tls_required: false,
+        plaintext_login: false,
+    };
+    let user = format!("{}-{}@localhost", name, std::process::id());
+    let mailbox = Mailbox::new(user, "secret".to_string(), &config)
+        .await
+        .expect("failed to add user");
+    mailbox.check_mailbox_root().await.unwrap();
+
+    let addr: SocketAddr = "127.0.0.1:1143".parse().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut state = Shared::new(Arc::new(config), false);
+    state.peers.insert(
+        addr,
+        Connection {
+            state: State::Authenticated,
+            sasl: None,
+            tx,
+            mailbox: Some(mailbox),
+            idle: None,
+            enabled: Extensions::default(),
+            tls: false,
+            start_tls: false,
+        },
+    );
+    (Arc::new(Mutex::new(state)), addr, rx)
+}
+
+/// Everything sent to the client since the last call.
+///
+/// The codec ends every message with a line feed, the one after the last
+/// message is left out.
+fn responses(rx: &mut Rx) -> String {
+    let mut sent = Vec::new();
+    while let Ok(message) = rx.try_recv() {
+        sent.push(String::from_utf8(message).unwrap());
+    }
+    sent.join("\n")
+}
+
+#[tokio::test]
+async fn delete_and_rename() {
+    let (state, addr, mut rx) = authenticated("folders").await;
+    let mailbox = state.lock().await.peers[&addr].mailbox.clone().unwrap();
+    mailbox.create_folder("Work.2020").await.unwrap();
+    let uid = mailbox
+        .append_message("Work", b"Subject: Hello\r\n\r\n", &[], None)
+        .await
+        .unwrap();
+    let metadata = mailbox.folder_metadata("Work").await.unwrap();
+
+    let rename = |tag: &'static str, from: &str, to: &str| {
+        Commands::rename(
+            tag,
+            from.to_string(),
+            to.to_string(),
+            addr,
+            Arc::clone(&state),
+        )
+    };
+    rename("a1", "Work", "Projects").await.unwrap();
+    assert_eq!(responses(&mut rx), "a1 OK RENAME completed\r");
+    assert_eq!(
+        mailbox.store().list_folders().await.unwrap(),
+        vec!["INBOX", "Projects", "Projects.2020"]
+    );
+    assert_eq!(mailbox.folder_metadata("Projects").await.unwrap(), metadata);
+    assert!(mailbox.read_message("Projects", uid).await.is_ok());
+
+    rename("a2", "Projects", "inbox").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "a2 NO [ALREADYEXISTS] Mailbox already exists\r"
+    );
+    rename("a3", "Work", "Other").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "a3 NO [NONEXISTENT] Mailbox does not exist\r"
+    );
+    rename("a4", "Projects", "Other/Work").await.unwrap();
+    assert_eq!(responses(&mut rx), "a4 NO Invalid mailbox name\r");
+
+    let delete = |tag: &'static str, folder: &str| {
+        Commands::delete(tag, folder.to_string(), addr, Arc::clone(&state))
+    };
+    delete("a5", "Projects").await.unwrap();
+    assert_eq!(responses(&mut rx), "a5 OK DELETE completed\r");
+    assert_eq!(
+        mailbox.store().list_folders().await.unwrap(),
+        vec!["INBOX", "Projects.2020"]
+    );
+
+    // The folders below keep the name around, but it can't be deleted again
+    delete("a6", "Projects").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "a6 NO [CANNOT] Mailbox has inferior hierarchical names\r"
+    );
+    delete("a7", "INBOX").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "a7 NO [CANNOT] INBOX can't be deleted\r"
+    );
+    delete("a8", "Drafts").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "a8 NO [NONEXISTENT] Mailbox does not exist\r"
+    );
+    assert_eq!(
+        mailbox.store().list_folders().await.unwrap(),
+        vec!["INBOX", "Projects.2020"]
+    );
+
+    std::fs::remove_dir_all(&state.lock().await.config.mailbox_root).unwrap();
+}
+
+#[tokio::test]
+async fn status_items() {
+    let (state, addr, mut rx) = authenticated("status").await;
+    let mailbox = state.lock().await.peers[&addr].mailbox.clone().unwrap();
+    let message = b"Subject: Hello\r\n\r\nWorld\r\n";
+    mailbox
+        .append_message("INBOX", message, &[], None)
+        .await
+        .unwrap();
+    // Messages with flags are no longer new in a Maildir
+    mailbox
+        .append_message("INBOX", message, &["\\Seen".to_string()], None)
+        .await
+        .unwrap();
+    let metadata = mailbox.folder_metadata("INBOX").await.unwrap();
+
+    let status = |tag: &'static str, folder: &str, items: &[&str]| {
+        let items = items.iter().map(|item| item.to_string()).collect();
+        Commands::status(tag, folder.to_string(), items, addr, Arc::clone(&state))
+    };
+    status(
+        "a1",
+        "INBOX",
+        &[
+            "MESSAGES",
+            "RECENT",
+            "UIDNEXT",
+            "UIDVALIDITY",
+            "UNSEEN",
+            "SIZE",
+        ],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        format!(
+            "* STATUS \"INBOX\" (MESSAGES 2 RECENT 1 UIDNEXT {} UIDVALIDITY {} UNSEEN 1 SIZE {})\r\n\
+             a1 OK STATUS Completed\r",
+            metadata.uid_next,
+            metadata.uid_validity,
+            2 * message.len()
+        )
+    );
+    assert!(!state.lock().await.extensions(addr).condstore);
+
+    // Asking for HIGHESTMODSEQ turns on CONDSTORE
+    status("a2", "INBOX", &["HIGHESTMODSEQ"]).await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        format!(
+            "* STATUS \"INBOX\" (HIGHESTMODSEQ {})\r\na2 OK STATUS Completed\r",
+            metadata.highest_modseq
+        )
+    );
+    assert!(state.lock().await.extensions(addr).condstore);
+
+    status("a3", "INBOX", &["MESSAGES", "DELETED"])
+        .await
+        .unwrap();
+    assert_eq!(responses(&mut rx), "a3 BAD Unknown status item DELETED\r");
+    status("a4", "Drafts", &["MESSAGES"]).await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "a4 NO [NONEXISTENT] Mailbox does not exist\r"
+    );
+
+    std::fs::remove_dir_all(&state.lock().await.config.mailbox_root).unwrap();
+}
+
+#[tokio::test]
+async fn subscribe_and_lsub() {
+    let (state, addr, mut rx) = authenticated("lsub").await;
+    let mailbox = state.lock().await.peers[&addr].mailbox.clone().unwrap();
+    mailbox.create_folder("Sent").await.unwrap();
+
+    for (tag, folder) in &[
+        ("a1", "Sent"),
+        ("a2", "inbox"),
+        ("a3", "Missing"),
+        ("a4", "Work.2020"),
+    ] {
+        Commands::subscribe(tag, folder.to_string(), addr, Arc::clone(&state))
+            .await
+            .unwrap();
+        assert_eq!(
+            responses(&mut rx),
+            format!("{} OK SUBSCRIBE completed\r", tag)
+        );
+    }
+
+    let lsub = |tag: &'static str, reference: &str, pattern: &str| {
+        let (reference, pattern) = (reference.to_string(), pattern.to_string());
+        Commands::lsub(tag, reference, pattern, addr, Arc::clone(&state))
+    };
+    lsub("a5", "", "*").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "* LSUB (\\HasNoChildren) \".\" \"INBOX\"\r\n\
+         * LSUB () \".\" \"Missing\"\r\n\
+         * LSUB (\\HasNoChildren) \".\" \"Sent\"\r\n\
+         * LSUB (\\Noselect) \".\" \"Work\"\r\n\
+         * LSUB () \".\" \"Work.2020\"\r\n\
+         a5 OK LSUB completed\r"
+    );
+    // The unsubscribed parent stands in for the folders below it
+    lsub("a6", "", "W%").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "* LSUB (\\Noselect) \".\" \"Work\"\r\na6 OK LSUB completed\r"
+    );
+    lsub("a7", "Work.", "%").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "* LSUB () \".\" \"Work.2020\"\r\na7 OK LSUB completed\r"
+    );
+
+    Commands::unsubscribe("a8", "Missing".to_string(), addr, Arc::clone(&state))
+        .await
+        .unwrap();
+    assert_eq!(responses(&mut rx), "a8 OK UNSUBSCRIBE completed\r");
+    Commands::unsubscribe("a9", "Missing".to_string(), addr, Arc::clone(&state))
+        .await
+        .unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        "a9 NO [NONEXISTENT] Not subscribed to that mailbox\r"
+    );
+    lsub("a10", "", "M*").await.unwrap();
+    assert_eq!(responses(&mut rx), "a10 OK LSUB completed\r");
+
+    std::fs::remove_dir_all(&state.lock().await.config.mailbox_root).unwrap();
+}
+
+#[test]
+fn tls_acceptor() {
+    let testdata = |name: &str| Some(format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name));
+    let mut config = Config {
+        shared_secret: String::new(),
+        mailbox_root: "./mailbox_root".to_string(),
+        max_literal_size: 1024,
+        literal_minus: false,
+        tls_certificate: testdata("certificate.pem"),
+        tls_key: None,
+        tls_required: false,
+        plaintext_login: false,
+    };
+    assert!(tls::acceptor(&config).unwrap().is_none());
+
+    config.tls_key = testdata("key.pem");
+    assert!(tls::acceptor(&config).unwrap().is_some());
+    // The same key without the PKCS#8 wrapping
+    config.tls_key = testdata("rsa-key.pem");
+    assert!(tls::acceptor(&config).unwrap().is_some());
+
+    let error = |config: &Config| tls::acceptor(config).err().unwrap().to_string();
+    config.tls_key = testdata("certificate.pem");
+    assert!(error(&config).ends_with("certificate.pem contains no private key"));
+    config.tls_key = testdata("key.pem");
+    config.tls_certificate = testdata("key.pem");
+    assert!(error(&config).ends_with("key.pem contains no certificate"));
+    config.tls_certificate = testdata("missing.pem");
+    let e = tls::acceptor(&config).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn append_to_selected_folder() {
+    let (state, addr, mut rx) = authenticated("append").await;
+    let mailbox = state.lock().await.peers[&addr].mailbox.clone().unwrap();
+    mailbox.create_folder("Sent").await.unwrap();
+    let message = b"Subject: Hello\r\n\r\nWorld\r\n".to_vec();
+    let first = mailbox
+        .append_message("INBOX", &message, &["\\Seen".to_string()], None)
+        .await
+        .unwrap();
+    state.lock().await.peers.get_mut(&addr).unwrap().state = State::Selected(Selected {
+        folder: "INBOX".to_string(),
+        read_only: false,
+        uids: vec![first],
+        recent: Vec::new(),
+        flags: vec![(first, vec!["\\Seen".to_string()])]
+            .into_iter()
+            .collect(),
+    });
+    let uid_validity = mailbox.folder_metadata("INBOX").await.unwrap().uid_validity;
+
+    let append = |tag: &'static str, folder: &str| {
+        let (folder, message) = (folder.to_string(), message.clone());
+        Commands::append(
+            tag,
+            folder,
+            Vec::new(),
+            None,
+            message,
+            addr,
+            Arc::clone(&state),
+        )
+    };
+    append("a1", "INBOX").await.unwrap();
+    assert_eq!(
+        responses(&mut rx),
+        format!(
+            "* 2 EXISTS\r\n* 1 RECENT\r\na1 OK [APPENDUID {} {}] APPEND completed\r",
+            uid_validity,
+            first + 1
+        )
+    );
+    match &state.lock().await.peers[&addr].state {
+        State::Selected(selected) => {
+            assert_eq!(selected.uids, vec![first, first + 1]);
+            assert_eq!(selected.recent, vec![first + 1]);
+        }
+        state => panic!("unexpected state {:?}", state),
+    }
+
+    // Other folders are only reported by STATUS
+    append("a2", "Sent").await.unwrap();
+    let response = responses(&mut rx);
+    assert!(response.starts_with("a2 OK [APPENDUID "), "{}", response);
+
+    std::fs::remove_dir_all(&state.lock().await.config.mailbox_root).unwrap();
+}
@@ -0,0 +1,125 @@
+//! TLS for IMAPS (RFC 8314) using rustls.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use IMAPServer_shared::config::Config;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads all certificates of a PEM file, the leaf certificate first.
+fn load_certificates(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let chain = certs(&mut reader).map_err(|()| invalid(format!("{} is not PEM", path)))?;
+    if chain.is_empty() {
+        return Err(invalid(format!("{} contains no certificate", path)));
+    }
+    Ok(chain)
+}
+
+/// Reads the first PKCS#8 or RSA private key of a PEM file.
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys =
+        pkcs8_private_keys(&mut reader).map_err(|()| invalid(format!("{} is not PEM", path)))?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys =
+            rsa_private_keys(&mut reader).map_err(|()| invalid(format!("{} is not PEM", path)))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid(format!("{} contains no private key", path)))
+}
+
+/// Builds the acceptor from the certificate and key in the config.
+///
+/// Returns `None` if TLS is not configured.
+pub fn acceptor(config: &Config) -> io::Result<Option<TlsAcceptor>> {
+    let (certificate, key) = match (&config.tls_certificate, &config.tls_key) {
+        (Some(certificate), Some(key)) => (certificate, key),
+        _ => return Ok(None),
+    };
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(load_certificates(certificate)?, load_key(key)?)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// A client connection, either in plain text or encrypted.
+#[derive(Debug)]
+pub enum ImapStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl ImapStream {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ImapStream::Tls(_))
+    }
+
+    /// Performs the TLS handshake on a plain text connection, as done by STARTTLS.
+    pub async fn upgrade(self, acceptor: &TlsAcceptor) -> io::Result<ImapStream> {
+        match self {
+            ImapStream::Plain(stream) => {
+                let stream = acceptor.accept(stream).await?;
+                Ok(ImapStream::Tls(Box::new(stream)))
+            }
+            ImapStream::Tls(_) => Err(io::Error::other("the connection is already encrypted")),
+        }
+    }
+}
+
+impl AsyncRead for ImapStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ImapStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ImapStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
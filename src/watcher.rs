@@ -0,0 +1,86 @@
+//! Watches the Maildirs for changes made outside of a command, e.g. by a local
+//! delivery agent, and pushes them to idling connections.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use tokio::sync::{mpsc, Mutex};
+
+use IMAPServer_shared::mailbox::{folder_of, Mailbox};
+
+use crate::Shared;
+
+/// How long file events are collected before they are reported.
+const DELAY: Duration = Duration::from_millis(200);
+
+/// The paths an event touched.
+fn changed_paths(event: DebouncedEvent) -> Vec<PathBuf> {
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Remove(path) => {
+            vec![path]
+        }
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        _ => Vec::new(),
+    }
+}
+
+/// Starts watching the roots of `mailboxes`.
+///
+/// Users created after the start are not watched, their clients still see
+/// changes on NOOP and when IDLE starts.
+pub fn spawn(mailboxes: &[Mailbox], state: Arc<Mutex<Shared>>) -> notify::Result<()> {
+    let (events_tx, events_rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(events_tx, DELAY)?;
+
+    let mut roots: Vec<(PathBuf, String)> = Vec::new();
+    for mailbox in mailboxes {
+        // Events use the path as it was watched
+        let root = std::fs::canonicalize(&mailbox.mailbox_root)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        roots.push((root, mailbox.user.clone()));
+    }
+
+    let (changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        // Watching stops when the watcher is dropped
+        let _watcher = watcher;
+        for event in events_rx {
+            for path in changed_paths(event) {
+                let change = roots.iter().find_map(|(root, user)| {
+                    folder_of(root, &path).map(|folder| (user.clone(), folder))
+                });
+                if let Some(change) = change {
+                    if changes_tx.send(change).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(change) = changes_rx.recv().await {
+            // A single delivery touches several files
+            let mut changes = vec![change];
+            while let Ok(change) = changes_rx.try_recv() {
+                changes.push(change);
+            }
+            changes.sort();
+            changes.dedup();
+
+            let mut state = state.lock().await;
+            for (user, folder) in changes {
+                debug!("{} of {} changed", folder, user);
+                state.refresh_idle(&user, &folder).await;
+            }
+        }
+    });
+
+    Ok(())
+}